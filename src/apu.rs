@@ -0,0 +1,299 @@
+use crate::state::NesState;
+use crate::timing::Timing;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+pub mod constants;
+mod envelope;
+mod length_counter;
+
+mod frame_counter;
+use frame_counter::{FrameClock, FrameCounter};
+
+mod pulse;
+use pulse::{Pulse, PulseChannel};
+
+mod triangle;
+use triangle::Triangle;
+
+mod noise;
+use noise::Noise;
+
+mod dmc;
+use dmc::Dmc;
+
+///
+/// Audio processing unit.
+/// Clocked once per CPU cycle. Channel registers are written via NesState.
+/// See: https://www.nesdev.org/wiki/APU
+///
+pub struct Apu {
+    /// Pulse channel 1 ($4000-$4003).
+    pulse1: Pulse,
+
+    /// Pulse channel 2 ($4004-$4007).
+    pulse2: Pulse,
+
+    /// Triangle channel ($4008-$400B).
+    triangle: Triangle,
+
+    /// Noise channel ($400C-$400F).
+    noise: Noise,
+
+    /// Delta modulation channel ($4010-$4013).
+    dmc: Dmc,
+
+    /// Overall APU cycle counter, in CPU cycles.
+    total_cycle_count: u64,
+
+    /// Frame counter ($4017).
+    frame_counter: FrameCounter,
+
+    /// Lookup table for the nonlinear pulse channel mixer.
+    pulse_table: [f32; 31],
+
+    /// Lookup table for the nonlinear triangle/noise/DMC mixer.
+    tnd_table: [f32; 203],
+}
+
+impl Apu {
+    pub fn new(timing: Timing) -> Self {
+        let mut pulse_table = [0.0; 31];
+
+        // See: https://www.nesdev.org/wiki/APU_Mixer#Lookup_Table
+        for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+
+        let mut tnd_table = [0.0; 203];
+
+        for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        Self {
+            pulse1: Pulse::new(PulseChannel::One),
+            pulse2: Pulse::new(PulseChannel::Two),
+            triangle: Triangle::new(),
+            noise: Noise::new(timing.pal_apu),
+            dmc: Dmc::new(timing.pal_apu),
+            total_cycle_count: 0,
+            frame_counter: FrameCounter::new(timing.pal_apu),
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    /// Advance the APU by one CPU cycle. DMC sample fetches are read
+    /// through the given state, which stalls the CPU.
+    pub fn cycle(&mut self, state: &mut NesState) {
+        self.total_cycle_count += 1;
+
+        // The triangle, noise and DMC timers are clocked every CPU cycle.
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+
+        if let Some(addr) = self.dmc.pending_fetch() {
+            let sample = state.dmc_mem_read(addr);
+            self.dmc.load_sample(sample);
+        }
+
+        // Pulse timers are clocked every APU cycle, which is every other CPU
+        // cycle.
+        if self.total_cycle_count & 1 == 0 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        match self.frame_counter.clock() {
+            FrameClock::None => (),
+            FrameClock::Quarter => self.clock_quarter_frame(),
+            FrameClock::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            },
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.clock_quarter_frame();
+        self.pulse2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    /// Write to one of the channel registers ($4000-$4013).
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000 => self.pulse1.write_control(value),
+            0x4001 => self.pulse1.write_sweep(value),
+            0x4002 => self.pulse1.write_timer_lo(value),
+            0x4003 => self.pulse1.write_timer_hi(value),
+            0x4004 => self.pulse2.write_control(value),
+            0x4005 => self.pulse2.write_sweep(value),
+            0x4006 => self.pulse2.write_timer_lo(value),
+            0x4007 => self.pulse2.write_timer_hi(value),
+            0x4008 => self.triangle.write_linear_counter(value),
+            0x4009 => (), // Unused
+            0x400A => self.triangle.write_timer_lo(value),
+            0x400B => self.triangle.write_timer_hi(value),
+            0x400C => self.noise.write_control(value),
+            0x400D => (), // Unused
+            0x400E => self.noise.write_period(value),
+            0x400F => self.noise.write_length(value),
+            0x4010 => self.dmc.write_control(value),
+            0x4011 => self.dmc.write_direct_load(value),
+            0x4012 => self.dmc.write_sample_address(value),
+            0x4013 => self.dmc.write_sample_length(value),
+            _ => panic!("apu: invalid register address: {addr:04X}"),
+        }
+    }
+
+    /// Read the status register. Returns the length counter status of each
+    /// channel in bits 0-3, DMC bytes remaining in bit 4, and the frame and
+    /// DMC interrupt flags in bits 6 and 7. Reading clears the frame interrupt
+    /// flag.
+    pub fn read_4015_status(&mut self) -> u8 {
+        let mut status = 0;
+
+        if self.pulse1.length_counter_active() {
+            status |= 0x01;
+        }
+
+        if self.pulse2.length_counter_active() {
+            status |= 0x02;
+        }
+
+        if self.triangle.length_counter_active() {
+            status |= 0x04;
+        }
+
+        if self.noise.length_counter_active() {
+            status |= 0x08;
+        }
+
+        if self.dmc.bytes_remaining() {
+            status |= 0x10;
+        }
+
+        if self.frame_counter.irq_flag() {
+            status |= 0x40;
+        }
+
+        if self.dmc.irq_flag() {
+            status |= 0x80;
+        }
+
+        self.frame_counter.clear_irq_flag();
+
+        status
+    }
+
+    /// Write to the status register. Enables/disables each channel. Disabling
+    /// a channel clears its length counter. Also clears the DMC interrupt flag.
+    pub fn write_4015_status(&mut self, value: u8) {
+        self.pulse1.set_enabled((value & 0x01) != 0);
+        self.pulse2.set_enabled((value & 0x02) != 0);
+        self.triangle.set_enabled((value & 0x04) != 0);
+        self.noise.set_enabled((value & 0x08) != 0);
+        self.dmc.set_enabled((value & 0x10) != 0);
+    }
+
+    /// Write to the frame counter register.
+    pub fn write_4017_frame_counter(&mut self, value: u8, cpu_cycle: u64) {
+        self.frame_counter.write(value, cpu_cycle);
+    }
+
+    ///
+    /// Reset APU as if NES reset button was pressed. All channels are
+    /// silenced and the frame counter restarts in its current mode.
+    /// See: https://www.nesdev.org/wiki/CPU_power_up_state
+    ///
+    pub fn reset(&mut self, cpu_cycle: u64) {
+        self.write_4015_status(0);
+        self.triangle.reset();
+        self.dmc.reset();
+        self.frame_counter.reset(cpu_cycle);
+    }
+
+    /// True while the APU is asserting the CPU's IRQ line.
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.irq_flag() || self.dmc.irq_flag()
+    }
+
+    /// Mix the channel outputs into a single sample in the range 0.0-1.0.
+    pub fn output(&self) -> f32 {
+        let pulse_sum = self.pulse1.output() + self.pulse2.output();
+        let tnd_sum = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+
+        self.pulse_table[pulse_sum as usize] + self.tnd_table[tnd_sum]
+    }
+
+    /// Write channel and frame counter state to a save state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.total_cycle_count);
+        self.frame_counter.save_state(writer);
+        self.pulse1.save_state(writer);
+        self.pulse2.save_state(writer);
+        self.triangle.save_state(writer);
+        self.noise.save_state(writer);
+        self.dmc.save_state(writer);
+    }
+
+    /// Restore channel and frame counter state from a save state.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.total_cycle_count = reader.read_u64()?;
+        self.frame_counter.load_state(reader)?;
+        self.pulse1.load_state(reader)?;
+        self.pulse2.load_state(reader)?;
+        self.triangle.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.dmc.load_state(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::mappers::get_mapper;
+    use crate::mem::{Memory, PpuMemory};
+    use crate::ppu::Ppu;
+
+    #[test]
+    fn test_noise_clocked_every_cpu_cycle() {
+        let mut state = NesState::new(get_mapper(0, 0, Memory::new_cpu(), PpuMemory::new()).unwrap(),
+                                      Rc::new(RefCell::new(Ppu::new(Timing::default()))),
+                                      Rc::new(RefCell::new(Apu::new(Timing::default()))));
+
+        // Constant volume 15 with the length counter halted, period index 4
+        let mut apu = Apu::new(Timing::default());
+        apu.write_4015_status(0x08);
+        apu.write_register(0x400C, 0x3F);
+        apu.write_register(0x400E, 0x04);
+        apu.write_register(0x400F, 0x08);
+
+        let mut expected = Noise::new(false);
+        expected.set_enabled(true);
+        expected.write_control(0x3F);
+        expected.write_period(0x04);
+        expected.write_length(0x08);
+
+        for _ in 0..64 * 20 {
+            apu.cycle(&mut state);
+            expected.clock_timer();
+            assert!(apu.noise.output() == expected.output());
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use super::constants::{DMC_RATES, DMC_RATES_PAL};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+///
+/// APU delta modulation channel. Plays 1-bit delta encoded samples fetched
+/// from CPU memory. Sample fetches are performed by the APU on behalf of this
+/// channel since they go through NesState and stall the CPU.
+/// See: https://www.nesdev.org/wiki/APU_DMC
+///
+pub struct Dmc {
+    irq_enabled: bool,
+    loop_flag: bool,
+
+    /// Set when a non-looping sample finishes with IRQs enabled.
+    irq_flag: bool,
+
+    /// Rate table selected by $4010, which differs between NTSC and PAL.
+    rates: &'static [u16; 16],
+
+    /// Timer reload value, in CPU cycles.
+    timer_period: u16,
+
+    /// Timer counter, clocked every CPU cycle.
+    timer: u16,
+
+    /// 7-bit output level.
+    output_level: u8,
+
+    /// Sample start address, set by $4012.
+    sample_address: u16,
+
+    /// Sample length in bytes, set by $4013.
+    sample_length: u16,
+
+    /// Address of the next sample byte to fetch.
+    current_address: u16,
+
+    /// Sample bytes left to fetch.
+    bytes_remaining: u16,
+
+    /// Most recently fetched sample byte, waiting to be moved to the
+    /// output shift register.
+    sample_buffer: Option<u8>,
+
+    shift_register: u8,
+    bits_remaining: u8,
+
+    /// Set when the output unit started a cycle with an empty sample buffer.
+    silence: bool,
+}
+
+impl Dmc {
+    pub fn new(pal: bool) -> Self {
+        let rates = if pal { &DMC_RATES_PAL } else { &DMC_RATES };
+
+        Self {
+            irq_enabled: false,
+            loop_flag: false,
+            irq_flag: false,
+            rates,
+            timer_period: rates[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    /// Reset as the NES reset button does. Only bit 0 of the output level
+    /// survives.
+    pub fn reset(&mut self) {
+        self.output_level &= 1;
+    }
+
+    /// $4010: IL-- RRRR
+    /// IRQ enabled (I), loop (L), rate index (R). Clearing the IRQ enable
+    /// flag also clears the IRQ flag.
+    pub fn write_control(&mut self, value: u8) {
+        self.irq_enabled = (value & 0x80) != 0;
+        self.loop_flag = (value & 0x40) != 0;
+        self.timer_period = self.rates[(value & 0x0F) as usize];
+
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+    }
+
+    /// $4011: -DDD DDDD
+    /// Directly load the output level (D).
+    pub fn write_direct_load(&mut self, value: u8) {
+        self.output_level = value & 0x7F;
+    }
+
+    /// $4012: Sample address = $C000 + (value * 64).
+    pub fn write_sample_address(&mut self, value: u8) {
+        self.sample_address = 0xC000 | ((value as u16) << 6);
+    }
+
+    /// $4013: Sample length = (value * 16) + 1 bytes.
+    pub fn write_sample_length(&mut self, value: u8) {
+        self.sample_length = ((value as u16) << 4) | 0x0001;
+    }
+
+    /// Enable/disable from $4015 bit 4. Enabling restarts the sample only if
+    /// no bytes remain; disabling stops the sample after the buffered byte.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart_sample();
+        }
+    }
+
+    pub fn bytes_remaining(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
+    fn restart_sample(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Address of the next sample byte if the memory reader needs to fill the
+    /// sample buffer, or None if no fetch is needed.
+    pub fn pending_fetch(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    /// Store a sample byte fetched from the address returned by pending_fetch
+    /// and advance the memory reader.
+    pub fn load_sample(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+
+        // Address wraps around to $8000, not $0000
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart_sample();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    /// Clock the timer. Called once per CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if (self.shift_register & 0x01) != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// Current output level (0-127).
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.loop_flag);
+        writer.write_bool(self.irq_flag);
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.output_level);
+        writer.write_u16(self.sample_address);
+        writer.write_u16(self.sample_length);
+        writer.write_u16(self.current_address);
+        writer.write_u16(self.bytes_remaining);
+        writer.write_bool(self.sample_buffer.is_some());
+        writer.write_u8(self.sample_buffer.unwrap_or(0));
+        writer.write_u8(self.shift_register);
+        writer.write_u8(self.bits_remaining);
+        writer.write_bool(self.silence);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.irq_enabled = reader.read_bool()?;
+        self.loop_flag = reader.read_bool()?;
+        self.irq_flag = reader.read_bool()?;
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.output_level = reader.read_u8()? & 0x7F;
+        self.sample_address = reader.read_u16()?;
+        self.sample_length = reader.read_u16()?;
+        self.current_address = reader.read_u16()?;
+        self.bytes_remaining = reader.read_u16()?;
+
+        let has_sample = reader.read_bool()?;
+        let sample = reader.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+
+        self.shift_register = reader.read_u8()?;
+        self.bits_remaining = reader.read_u8()?;
+        self.silence = reader.read_bool()?;
+
+        if self.timer_period == 0 || self.bits_remaining == 0 {
+            return Err(SaveStateError::InvalidData("dmc channel"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_end_irq_and_loop() {
+        let mut dmc = Dmc::new(false);
+        dmc.write_control(0x80);        // IRQ enabled, no loop
+        dmc.write_sample_address(0xFF); // $FFC0
+        dmc.write_sample_length(0x00);  // 1 byte
+        dmc.set_enabled(true);
+
+        assert!(dmc.pending_fetch() == Some(0xFFC0));
+        dmc.load_sample(0xAA);
+        assert!(dmc.pending_fetch().is_none());
+        assert!(!dmc.bytes_remaining());
+        assert!(dmc.irq_flag());
+
+        // Looping restarts the sample instead of raising the IRQ
+        let mut dmc = Dmc::new(false);
+        dmc.write_control(0xC0);
+        dmc.write_sample_length(0x00);
+        dmc.set_enabled(true);
+        dmc.load_sample(0xAA);
+
+        assert!(dmc.bytes_remaining());
+        assert!(!dmc.irq_flag());
+    }
+}
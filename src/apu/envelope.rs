@@ -0,0 +1,86 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+///
+/// Envelope generator used by the pulse and noise channels. Produces either a
+/// constant volume or a decaying saw envelope.
+/// See: https://www.nesdev.org/wiki/APU_Envelope
+///
+#[derive(Default)]
+pub struct Envelope {
+    /// Set by a write to the channel's length counter load register. Restarts
+    /// the envelope on the next quarter frame clock.
+    start: bool,
+
+    /// Restart the decay at 15 when it reaches 0. Shares a bit with the
+    /// length counter halt flag.
+    loop_flag: bool,
+
+    /// If true, output volume directly instead of the decay level.
+    constant_volume: bool,
+
+    /// Constant volume, or the divider period when using the envelope.
+    volume: u8,
+
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    /// Update envelope settings from bits 0-5 of $4000/$4004/$400C.
+    pub fn write_control(&mut self, value: u8) {
+        self.loop_flag = (value & 0x20) != 0;
+        self.constant_volume = (value & 0x10) != 0;
+        self.volume = value & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocked by the frame counter on each quarter frame.
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.loop_flag {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay_level
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.start);
+        writer.write_bool(self.loop_flag);
+        writer.write_bool(self.constant_volume);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.divider);
+        writer.write_u8(self.decay_level);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.start = reader.read_bool()?;
+        self.loop_flag = reader.read_bool()?;
+        self.constant_volume = reader.read_bool()?;
+        self.volume = reader.read_u8()?;
+        self.divider = reader.read_u8()?;
+        self.decay_level = reader.read_u8()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// CPU cycles of the first quarter frame, first half frame, second quarter
+/// frame, and the last half frame of the 4-step and 5-step sequences.
+/// See: https://www.nesdev.org/wiki/APU_Frame_Counter
+const SEQUENCE_NTSC: [u64; 5] = [7457, 14913, 22371, 29829, 37281];
+const SEQUENCE_PAL: [u64; 5] = [8313, 16627, 24939, 33253, 41565];
+
+///
+/// Result of clocking the frame counter for one CPU cycle.
+///
+#[derive(Debug, PartialEq)]
+pub enum FrameClock {
+    None,
+
+    /// Clock envelopes and the triangle's linear counter.
+    Quarter,
+
+    /// Clock everything clocked on a quarter frame, plus the length
+    /// counters and sweep units.
+    Half,
+}
+
+///
+/// APU frame counter ($4017). Generates the quarter and half frame clocks
+/// used by the channels, and the frame IRQ when in 4-step mode.
+/// See: https://www.nesdev.org/wiki/APU_Frame_Counter
+///
+pub struct FrameCounter {
+    /// Step timings, which differ between NTSC and PAL.
+    sequence: &'static [u64; 5],
+
+    /// 5-step sequence if set, 4-step otherwise.
+    five_step_mode: bool,
+
+    /// If set the frame IRQ flag is never set.
+    irq_inhibit: bool,
+
+    /// Frame interrupt flag, readable through $4015 bit 6.
+    irq_flag: bool,
+
+    /// CPU cycle position within the current sequence.
+    cycle: u64,
+
+    /// Mode written to $4017 that has not taken effect yet, along with the
+    /// number of CPU cycles left before it does.
+    pending_write: Option<(bool, u8)>,
+}
+
+impl FrameCounter {
+    pub fn new(pal: bool) -> Self {
+        Self {
+            sequence: if pal { &SEQUENCE_PAL } else { &SEQUENCE_NTSC },
+            five_step_mode: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            pending_write: None,
+        }
+    }
+
+    /// $4017: MI-- ----
+    /// Sequencer mode (M), IRQ inhibit (I). The inhibit flag takes effect
+    /// immediately. The sequencer is reset 3 CPU cycles after the write if
+    /// the write occurs on an even CPU cycle, 4 cycles after otherwise.
+    pub fn write(&mut self, value: u8, cpu_cycle: u64) {
+        self.irq_inhibit = (value & 0x40) != 0;
+
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+
+        let delay = if cpu_cycle & 1 == 0 { 3 } else { 4 };
+        self.pending_write = Some(((value & 0x80) != 0, delay));
+    }
+
+    /// Reset as the NES reset button does: the last value written to $4017
+    /// is written again and the frame interrupt flag is cleared.
+    pub fn reset(&mut self, cpu_cycle: u64) {
+        let mut value = 0;
+
+        if self.five_step_mode {
+            value |= 0x80;
+        }
+
+        if self.irq_inhibit {
+            value |= 0x40;
+        }
+
+        self.irq_flag = false;
+        self.write(value, cpu_cycle);
+    }
+
+    pub fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
+    pub fn clear_irq_flag(&mut self) {
+        self.irq_flag = false;
+    }
+
+    /// Advance the frame counter by one CPU cycle.
+    pub fn clock(&mut self) -> FrameClock {
+        if let Some((five_step_mode, delay)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((five_step_mode, delay - 1));
+            } else {
+                self.pending_write = None;
+                self.five_step_mode = five_step_mode;
+                self.cycle = 0;
+
+                // Entering 5-step mode immediately clocks all units
+                if five_step_mode {
+                    return FrameClock::Half;
+                }
+            }
+        }
+
+        self.cycle += 1;
+
+        let [quarter1, half1, quarter2, four_step_end, five_step_end] = *self.sequence;
+
+        match (self.five_step_mode, self.cycle) {
+            (_, c) if c == quarter1 => FrameClock::Quarter,
+            (_, c) if c == half1 => FrameClock::Half,
+            (_, c) if c == quarter2 => FrameClock::Quarter,
+            (false, c) if c == four_step_end - 1 => {
+                self.set_irq_flag();
+                FrameClock::None
+            },
+            (false, c) if c == four_step_end => {
+                self.set_irq_flag();
+                FrameClock::Half
+            },
+            (false, c) if c == four_step_end + 1 => {
+                self.set_irq_flag();
+                self.cycle = 0;
+                FrameClock::None
+            },
+            (true, c) if c == five_step_end => FrameClock::Half,
+            (true, c) if c == five_step_end + 1 => {
+                self.cycle = 0;
+                FrameClock::None
+            },
+            _ => FrameClock::None,
+        }
+    }
+
+    fn set_irq_flag(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.five_step_mode);
+        writer.write_bool(self.irq_inhibit);
+        writer.write_bool(self.irq_flag);
+        writer.write_u64(self.cycle);
+
+        let (pending_mode, pending_delay) = self.pending_write.unwrap_or((false, 0));
+        writer.write_bool(pending_mode);
+        writer.write_u8(pending_delay);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.five_step_mode = reader.read_bool()?;
+        self.irq_inhibit = reader.read_bool()?;
+        self.irq_flag = reader.read_bool()?;
+        self.cycle = reader.read_u64()?;
+
+        let pending_mode = reader.read_bool()?;
+        let pending_delay = reader.read_u8()?;
+        self.pending_write = if pending_delay > 0 {
+            Some((pending_mode, pending_delay))
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_irq_four_step_only() {
+        let mut frame_counter = FrameCounter::new(false);
+
+        for _ in 0..29827 {
+            frame_counter.clock();
+        }
+        assert!(!frame_counter.irq_flag());
+
+        frame_counter.clock();
+        assert!(frame_counter.irq_flag());
+
+        // 5-step mode never sets the flag
+        let mut frame_counter = FrameCounter::new(false);
+        frame_counter.write(0x80, 0);
+
+        for _ in 0..(37282 * 2) {
+            frame_counter.clock();
+        }
+        assert!(!frame_counter.irq_flag());
+
+        // PAL sequences are longer
+        let mut frame_counter = FrameCounter::new(true);
+
+        for _ in 0..33251 {
+            frame_counter.clock();
+        }
+        assert!(!frame_counter.irq_flag());
+
+        frame_counter.clock();
+        assert!(frame_counter.irq_flag());
+    }
+
+    #[test]
+    fn test_write_delay_and_five_step_clock() {
+        let mut frame_counter = FrameCounter::new(false);
+
+        // Even cycle: reset takes effect on the 3rd clock
+        frame_counter.write(0x80, 10);
+        assert!(frame_counter.clock() == FrameClock::None);
+        assert!(frame_counter.clock() == FrameClock::None);
+        assert!(frame_counter.clock() == FrameClock::Half);
+
+        // Odd cycle: reset takes effect on the 4th clock
+        frame_counter.write(0x80, 11);
+        for _ in 0..3 {
+            assert!(frame_counter.clock() == FrameClock::None);
+        }
+        assert!(frame_counter.clock() == FrameClock::Half);
+    }
+}
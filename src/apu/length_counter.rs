@@ -0,0 +1,65 @@
+use super::constants::LENGTH_TABLE;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+///
+/// Length counter used by all channels except the DMC. Silences the channel
+/// once the counter reaches zero.
+/// See: https://www.nesdev.org/wiki/APU_Length_Counter
+///
+#[derive(Default)]
+pub struct LengthCounter {
+    /// Channel enable bit from $4015. A disabled channel's counter is held at 0.
+    enabled: bool,
+
+    /// When set the counter is not decremented.
+    halt: bool,
+
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    /// Load the counter using the 5-bit index from bits 3-7 of a length
+    /// counter load register. Ignored while the channel is disabled.
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    /// Clocked by the frame counter on each half frame.
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.halt);
+        writer.write_u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.halt = reader.read_bool()?;
+        self.counter = reader.read_u8()?;
+
+        Ok(())
+    }
+}
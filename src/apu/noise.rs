@@ -0,0 +1,184 @@
+use super::constants::{NOISE_PERIODS, NOISE_PERIODS_PAL};
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+///
+/// APU noise channel. Generates pseudo-random output using a 15-bit linear
+/// feedback shift register.
+/// See: https://www.nesdev.org/wiki/APU_Noise
+///
+pub struct Noise {
+    /// 15-bit linear feedback shift register. Must never be 0.
+    shift_register: u16,
+
+    /// If set, feedback comes from bit 6 instead of bit 1, producing the
+    /// short 93-step (metallic sounding) sequence.
+    mode: bool,
+
+    /// Period table selected by $400E, which differs between NTSC and PAL.
+    periods: &'static [u16; 16],
+
+    /// Timer reload value, in CPU cycles.
+    timer_period: u16,
+
+    /// Timer counter, clocked every CPU cycle.
+    timer: u16,
+
+    envelope: Envelope,
+    length_counter: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(pal: bool) -> Self {
+        let periods = if pal { &NOISE_PERIODS_PAL } else { &NOISE_PERIODS };
+
+        Self {
+            shift_register: 1,
+            mode: false,
+            periods,
+            timer_period: periods[0],
+            timer: 0,
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default(),
+        }
+    }
+
+    /// $400C: --LC VVVV
+    /// Length counter halt / envelope loop (L), constant volume (C),
+    /// volume / envelope divider period (V).
+    pub fn write_control(&mut self, value: u8) {
+        self.length_counter.set_halt((value & 0x20) != 0);
+        self.envelope.write_control(value);
+    }
+
+    /// $400E: M--- PPPP
+    /// Mode (M), timer period index (P).
+    pub fn write_period(&mut self, value: u8) {
+        self.mode = (value & 0x80) != 0;
+        self.timer_period = self.periods[(value & 0x0F) as usize];
+    }
+
+    /// $400F: LLLL L---
+    /// Length counter load (L). Also restarts the envelope.
+    pub fn write_length(&mut self, value: u8) {
+        self.length_counter.load(value >> 3);
+        self.envelope.restart();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    pub fn length_counter_active(&self) -> bool {
+        self.length_counter.is_active()
+    }
+
+    /// Clock the timer. Called once per CPU cycle.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_shift_register();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_shift_register(&mut self) {
+        let other_bit = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ (self.shift_register >> other_bit)) & 0x01;
+
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    /// Quarter frame clock from the frame counter.
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Half frame clock from the frame counter.
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    /// Current output level (0-15).
+    pub fn output(&self) -> u8 {
+        if (self.shift_register & 0x01) != 0 || !self.length_counter.is_active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.shift_register);
+        writer.write_bool(self.mode);
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        self.envelope.save_state(writer);
+        self.length_counter.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift_register = reader.read_u16()?;
+        self.mode = reader.read_bool()?;
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.envelope.load_state(reader)?;
+        self.length_counter.load_state(reader)?;
+
+        if self.shift_register == 0 || self.timer_period == 0 {
+            return Err(SaveStateError::InvalidData("noise channel"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Count shift register clocks until the register returns to its initial
+    /// value.
+    fn sequence_length(noise: &mut Noise) -> u32 {
+        let start = noise.shift_register;
+        let mut count = 0;
+
+        loop {
+            noise.clock_shift_register();
+            count += 1;
+
+            if noise.shift_register == start {
+                return count;
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise_sequence_lengths() {
+        let mut noise = Noise::new(false);
+        assert!(sequence_length(&mut noise) == 32767);
+
+        noise.write_period(0x80);
+        assert!(sequence_length(&mut noise) == 93);
+    }
+
+    #[test]
+    fn test_noise_clock_rate() {
+        // Period index 4 is 64 CPU cycles per shift register clock
+        let mut noise = Noise::new(false);
+        noise.write_period(0x04);
+
+        let mut expected = Noise::new(false);
+
+        for _ in 0..10 {
+            for _ in 0..64 {
+                noise.clock_timer();
+            }
+
+            expected.clock_shift_register();
+            assert!(noise.shift_register == expected.shift_register);
+        }
+    }
+}
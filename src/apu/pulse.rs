@@ -0,0 +1,249 @@
+use super::constants::DUTY_SEQUENCES;
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+/// Identifies which of the two pulse channels this is. The sweep units of
+/// the two channels differ in how they negate the period change.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PulseChannel {
+    /// Pulse 1 ($4000-$4003). Negates using ones' complement.
+    One,
+    /// Pulse 2 ($4004-$4007). Negates using twos' complement.
+    Two,
+}
+
+///
+/// Sweep unit. Periodically adjusts the pulse channel's timer period.
+/// See: https://www.nesdev.org/wiki/APU_Sweep
+///
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    divider_period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+///
+/// APU pulse (square wave) channel.
+/// See: https://www.nesdev.org/wiki/APU_Pulse
+///
+pub struct Pulse {
+    channel: PulseChannel,
+
+    /// Duty cycle selector (0-3) into DUTY_SEQUENCES.
+    duty: u8,
+
+    /// Current position in the 8-step duty sequence.
+    sequence_step: u8,
+
+    /// 11-bit timer reload value.
+    timer_period: u16,
+
+    /// Timer counter, clocked every APU cycle (every other CPU cycle).
+    timer: u16,
+
+    envelope: Envelope,
+    length_counter: LengthCounter,
+    sweep: Sweep,
+}
+
+impl Pulse {
+    pub fn new(channel: PulseChannel) -> Self {
+        Self {
+            channel,
+            duty: 0,
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length_counter: LengthCounter::default(),
+            sweep: Sweep::default(),
+        }
+    }
+
+    /// $4000/$4004: DDLC VVVV
+    /// Duty (D), length counter halt / envelope loop (L), constant volume (C),
+    /// volume / envelope divider period (V).
+    pub fn write_control(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length_counter.set_halt((value & 0x20) != 0);
+        self.envelope.write_control(value);
+    }
+
+    /// $4001/$4005: EPPP NSSS
+    /// Enabled (E), divider period (P), negate (N), shift count (S).
+    pub fn write_sweep(&mut self, value: u8) {
+        self.sweep.enabled = (value & 0x80) != 0;
+        self.sweep.divider_period = (value >> 4) & 0x07;
+        self.sweep.negate = (value & 0x08) != 0;
+        self.sweep.shift = value & 0x07;
+        self.sweep.reload = true;
+    }
+
+    /// $4002/$4006: Timer low 8 bits.
+    pub fn write_timer_lo(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    /// $4003/$4007: LLLL LTTT
+    /// Length counter load (L), timer high 3 bits (T). Also restarts the
+    /// envelope and resets the duty sequence.
+    pub fn write_timer_hi(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00FF) | (((value & 0x07) as u16) << 8);
+        self.length_counter.load(value >> 3);
+        self.envelope.restart();
+        self.sequence_step = 0;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    pub fn length_counter_active(&self) -> bool {
+        self.length_counter.is_active()
+    }
+
+    /// Clock the timer. Called once per APU cycle (every other CPU cycle).
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Quarter frame clock from the frame counter.
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Half frame clock from the frame counter.
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+        self.clock_sweep();
+    }
+
+    /// Current output level (0-15).
+    pub fn output(&self) -> u8 {
+        let waveform = DUTY_SEQUENCES[self.duty as usize][self.sequence_step as usize];
+
+        if waveform == 0 || !self.length_counter.is_active() || self.sweep_muting() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0
+            && !self.sweep_muting()
+        {
+            self.timer_period = self.sweep_target_period();
+        }
+
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.divider_period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    /// The period the sweep unit would set on its next update. This is
+    /// calculated continuously, even when the sweep unit is disabled.
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+
+        if self.sweep.negate {
+            match self.channel {
+                // Ones' complement: subtracts an extra 1
+                PulseChannel::One => self.timer_period.saturating_sub(change + 1),
+                PulseChannel::Two => self.timer_period.saturating_sub(change),
+            }
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    /// The channel is muted when the period is too low or when the sweep
+    /// target period would overflow 11 bits.
+    fn sweep_muting(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target_period() > 0x7FF
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.duty);
+        writer.write_u8(self.sequence_step);
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        self.envelope.save_state(writer);
+        self.length_counter.save_state(writer);
+
+        writer.write_bool(self.sweep.enabled);
+        writer.write_u8(self.sweep.divider_period);
+        writer.write_bool(self.sweep.negate);
+        writer.write_u8(self.sweep.shift);
+        writer.write_u8(self.sweep.divider);
+        writer.write_bool(self.sweep.reload);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.duty = reader.read_u8()? & 0x03;
+        self.sequence_step = reader.read_u8()? & 0x07;
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.envelope.load_state(reader)?;
+        self.length_counter.load_state(reader)?;
+
+        self.sweep.enabled = reader.read_bool()?;
+        self.sweep.divider_period = reader.read_u8()?;
+        self.sweep.negate = reader.read_bool()?;
+        self.sweep.shift = reader.read_u8()? & 0x07;
+        self.sweep.divider = reader.read_u8()?;
+        self.sweep.reload = reader.read_bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep_negate_ones_complement() {
+        let mut pulse1 = Pulse::new(PulseChannel::One);
+        let mut pulse2 = Pulse::new(PulseChannel::Two);
+
+        for pulse in [&mut pulse1, &mut pulse2] {
+            pulse.write_timer_lo(0x00);
+            pulse.write_timer_hi(0x01); // period 0x100
+            pulse.write_sweep(0x89);    // enabled, period 0, negate, shift 1
+        }
+
+        assert!(pulse1.sweep_target_period() == 0x100 - 0x80 - 1);
+        assert!(pulse2.sweep_target_period() == 0x100 - 0x80);
+
+        pulse1.clock_half_frame();
+        pulse2.clock_half_frame();
+
+        assert!(pulse1.timer_period == 0x7F);
+        assert!(pulse2.timer_period == 0x80);
+    }
+
+    #[test]
+    fn test_sweep_mutes_on_overflow() {
+        let mut pulse = Pulse::new(PulseChannel::Two);
+        pulse.write_timer_lo(0xFF);
+        pulse.write_timer_hi(0x07); // period 0x7FF
+        pulse.write_sweep(0x01);    // disabled, shift 1
+
+        // Target period overflows even though the sweep unit is disabled
+        assert!(pulse.sweep_muting());
+    }
+}
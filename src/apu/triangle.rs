@@ -0,0 +1,140 @@
+use super::constants::TRIANGLE_SEQUENCE;
+use super::length_counter::LengthCounter;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+///
+/// APU triangle channel.
+/// See: https://www.nesdev.org/wiki/APU_Triangle
+///
+#[derive(Default)]
+pub struct Triangle {
+    /// Current position in the 32-step output sequence.
+    sequence_step: u8,
+
+    /// 11-bit timer reload value.
+    timer_period: u16,
+
+    /// Timer counter, clocked every CPU cycle.
+    timer: u16,
+
+    /// Linear counter reload value from $4008.
+    linear_counter_period: u8,
+
+    linear_counter: u8,
+
+    /// Set by a write to $400B. Reloads the linear counter on the next
+    /// quarter frame clock.
+    linear_counter_reload: bool,
+
+    /// Linear counter control flag. Shares a bit with the length counter halt.
+    control: bool,
+
+    length_counter: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reset the sequencer to the start of the output sequence.
+    pub fn reset(&mut self) {
+        self.sequence_step = 0;
+    }
+
+    /// $4008: CRRR RRRR
+    /// Length counter halt / linear counter control (C), linear counter
+    /// reload value (R).
+    pub fn write_linear_counter(&mut self, value: u8) {
+        self.control = (value & 0x80) != 0;
+        self.length_counter.set_halt(self.control);
+        self.linear_counter_period = value & 0x7F;
+    }
+
+    /// $400A: Timer low 8 bits.
+    pub fn write_timer_lo(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x0700) | value as u16;
+    }
+
+    /// $400B: LLLL LTTT
+    /// Length counter load (L), timer high 3 bits (T). Also sets the linear
+    /// counter reload flag.
+    pub fn write_timer_hi(&mut self, value: u8) {
+        self.timer_period = (self.timer_period & 0x00FF) | (((value & 0x07) as u16) << 8);
+        self.length_counter.load(value >> 3);
+        self.linear_counter_reload = true;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length_counter.set_enabled(enabled);
+    }
+
+    pub fn length_counter_active(&self) -> bool {
+        self.length_counter.is_active()
+    }
+
+    /// Clock the timer. Called once per CPU cycle. The sequencer only advances
+    /// while both the length and linear counters are non-zero.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+
+            if self.length_counter.is_active() && self.linear_counter > 0 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Quarter frame clock from the frame counter.
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    /// Half frame clock from the frame counter.
+    pub fn clock_half_frame(&mut self) {
+        self.length_counter.clock();
+    }
+
+    /// Current output level (0-15).
+    pub fn output(&self) -> u8 {
+        // Very low periods produce ultrasonic frequencies. Real hardware still
+        // outputs them but they cause popping here, so hold the current value.
+        if self.timer_period < 2 {
+            return 7;
+        }
+
+        TRIANGLE_SEQUENCE[self.sequence_step as usize]
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.sequence_step);
+        writer.write_u16(self.timer_period);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.linear_counter_period);
+        writer.write_u8(self.linear_counter);
+        writer.write_bool(self.linear_counter_reload);
+        writer.write_bool(self.control);
+        self.length_counter.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.sequence_step = reader.read_u8()? & 0x1F;
+        self.timer_period = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.linear_counter_period = reader.read_u8()?;
+        self.linear_counter = reader.read_u8()?;
+        self.linear_counter_reload = reader.read_bool()?;
+        self.control = reader.read_bool()?;
+        self.length_counter.load_state(reader)
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::ines::{RomError, MAX_ROM_SIZE};
+
+/// Magic bytes at the start of a zip file (local file header).
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+
+/// Magic bytes at the start of a gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+///
+/// A rom file, possibly taken out of an archive.
+///
+pub struct RomFile {
+    /// File name of the rom, without any directory. For archives this is the
+    /// name of the file inside the archive.
+    pub name: String,
+
+    pub data: Vec<u8>,
+}
+
+///
+/// Unpack file data if it is a zip or gzip archive, detected by magic bytes.
+/// For zip archives the given entry is used, or the first .nes entry if none
+/// is given. Data that isn't an archive is returned as is. file_name is the
+/// name of the file the data was read from.
+///
+pub fn unpack(file_name: &str, data: Vec<u8>, entry: Option<&str>) -> Result<RomFile, RomError> {
+    if data.starts_with(&ZIP_MAGIC) {
+        unpack_zip(data, entry)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        unpack_gzip(file_name, &data)
+    } else {
+        Ok(RomFile { name: file_name.to_string(), data })
+    }
+}
+
+fn unpack_zip(data: Vec<u8>, entry: Option<&str>) -> Result<RomFile, RomError> {
+    let mut zip = ZipArchive::new(Cursor::new(data)).map_err(archive_error)?;
+
+    let entry_name = match entry {
+        Some(entry) => entry.to_string(),
+        None => first_nes_entry(&mut zip)?,
+    };
+
+    let mut file = match zip.by_name(&entry_name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => {
+            return Err(RomError::ArchiveEntryNotFound(entry_name));
+        },
+        Err(e) => return Err(archive_error(e)),
+    };
+
+    // The sizes in the central directory can't be trusted, so the data is
+    // read without preallocating
+    let data = read_limited(&mut file, MAX_ROM_SIZE)?;
+
+    Ok(RomFile { name: base_name(&entry_name), data })
+}
+
+/// Name of the first .nes file in the zip's central directory.
+fn first_nes_entry(zip: &mut ZipArchive<Cursor<Vec<u8>>>) -> Result<String, RomError> {
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i).map_err(archive_error)?;
+
+        if file.name().to_lowercase().ends_with(".nes") {
+            return Ok(file.name().to_string());
+        }
+    }
+
+    Err(RomError::NoRomInArchive)
+}
+
+///
+/// Decompress a gzip file. The rom is named after the original file name
+/// stored in the gzip header, or the archive's name without its .gz
+/// extension if there isn't one.
+///
+fn unpack_gzip(file_name: &str, data: &[u8]) -> Result<RomFile, RomError> {
+    let mut decoder = GzDecoder::new(data);
+
+    let unpacked = read_limited(&mut decoder, MAX_ROM_SIZE)?;
+
+    let stored_name = decoder.header()
+        .and_then(|header| header.filename())
+        .map(|name| base_name(&String::from_utf8_lossy(name)));
+
+    let name = match stored_name {
+        Some(name) if !name.is_empty() => name,
+        _ => strip_gz_extension(file_name),
+    };
+
+    Ok(RomFile { name, data: unpacked })
+}
+
+/// Read all data from an archive, failing if it unpacks to more than limit
+/// bytes.
+fn read_limited(reader: &mut impl Read, limit: usize) -> Result<Vec<u8>, RomError> {
+    let mut data = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut data)?;
+
+    if data.len() > limit {
+        return Err(RomError::ArchiveTooLarge);
+    }
+
+    Ok(data)
+}
+
+/// File name with any directory removed.
+fn base_name(path: &str) -> String {
+    match Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => path.to_string(),
+    }
+}
+
+fn strip_gz_extension(file_name: &str) -> String {
+    if file_name.to_lowercase().ends_with(".gz") {
+        file_name[..file_name.len() - 3].to_string()
+    } else {
+        file_name.to_string()
+    }
+}
+
+fn archive_error(e: zip::result::ZipError) -> RomError {
+    RomError::Archive(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn make_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+        for (name, data) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_plain_file() {
+        let rom = unpack("game.nes", b"NES\x1A".to_vec(), None).unwrap();
+
+        assert!(rom.name == "game.nes");
+        assert!(rom.data == b"NES\x1A");
+    }
+
+    #[test]
+    fn test_zip_first_nes_entry() {
+        let data = make_zip(&[
+            ("readme.txt", b"hello"),
+            ("roms/b.nes", b"first"),
+            ("a.nes", b"second"),
+        ]);
+
+        let rom = unpack("games.zip", data, None).unwrap();
+
+        assert!(rom.name == "b.nes");
+        assert!(rom.data == b"first");
+    }
+
+    #[test]
+    fn test_zip_chosen_entry() {
+        let data = make_zip(&[("b.nes", b"first"), ("a.nes", b"second")]);
+
+        let rom = unpack("games.zip", data.clone(), Some("a.nes")).unwrap();
+        assert!(rom.name == "a.nes");
+        assert!(rom.data == b"second");
+
+        let result = unpack("games.zip", data, Some("c.nes"));
+        assert!(matches!(result, Err(RomError::ArchiveEntryNotFound(name)) if name == "c.nes"));
+    }
+
+    #[test]
+    fn test_zip_without_rom() {
+        let data = make_zip(&[("readme.txt", b"hello")]);
+
+        assert!(matches!(unpack("games.zip", data, None), Err(RomError::NoRomInArchive)));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut encoder = GzBuilder::new()
+            .filename("Inner Name.nes")
+            .write(Vec::new(), Compression::default());
+        encoder.write_all(b"rom data").unwrap();
+
+        let rom = unpack("outer.nes.gz", encoder.finish().unwrap(), None).unwrap();
+        assert!(rom.name == "Inner Name.nes");
+        assert!(rom.data == b"rom data");
+
+        // Without a stored name the .gz extension is dropped
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"rom data").unwrap();
+
+        let rom = unpack("outer.nes.gz", encoder.finish().unwrap(), None).unwrap();
+        assert!(rom.name == "outer.nes");
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let data = make_zip(&[("a.nes", &[0; 1024])]);
+        let mut zip = ZipArchive::new(Cursor::new(data)).unwrap();
+        let mut file = zip.by_name("a.nes").unwrap();
+
+        assert!(matches!(read_limited(&mut file, 1023), Err(RomError::ArchiveTooLarge)));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 1024]).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let mut decoder = GzDecoder::new(&data[..]);
+        assert!(matches!(read_limited(&mut decoder, 1023), Err(RomError::ArchiveTooLarge)));
+
+        let mut decoder = GzDecoder::new(&data[..]);
+        assert!(read_limited(&mut decoder, 1024).unwrap().len() == 1024);
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Number of input samples averaged together by the first (decimation) stage.
+const DECIMATION_FACTOR: u32 = 8;
+
+/// Half width of the windowed sinc kernel, in decimated samples.
+const KERNEL_HALF_WIDTH: usize = 48;
+
+/// Number of kernel table entries per decimated sample.
+const KERNEL_OVERSAMPLE: usize = 64;
+
+/// Passband edge as a fraction of the output Nyquist frequency.
+const CUTOFF: f64 = 0.90;
+
+/// Maximum adjustment to the resampling ratio made by dynamic rate control.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Coefficient of the DC blocking high pass filter (about 35Hz at 48kHz).
+const DC_BLOCK_COEFFICIENT: f32 = 0.995;
+
+///
+/// Converts APU output, produced once per CPU cycle (~1.79 MHz), to the
+/// audio device's sample rate.
+///
+/// Resampling is done in two stages. First a boxcar filter averages and
+/// decimates the input by DECIMATION_FACTOR. The boxcar's nulls land on
+/// multiples of the decimated rate, so content that would alias into the
+/// audible band is suppressed. Then a Blackman windowed sinc filter
+/// interpolates the decimated signal at the (fractional) output positions,
+/// band limiting it to just below the output Nyquist frequency.
+///
+/// The ratio between the two stages can be nudged slightly (see
+/// adjust_rate) to keep the audio device's buffer from under or overrunning
+/// when the emulation loop drifts against the audio clock.
+///
+pub struct Resampler {
+    /// Running sum for the decimation stage.
+    accumulator: f32,
+
+    /// Number of input samples in accumulator.
+    accumulated: u32,
+
+    /// Decimated samples still needed by the interpolation stage.
+    history: VecDeque<f32>,
+
+    /// Absolute index of the first sample in history.
+    history_start: u64,
+
+    /// Absolute (fractional) decimated sample index of the next output sample.
+    next_output_time: f64,
+
+    /// Decimated samples per output sample, before rate adjustment.
+    base_step: f64,
+
+    /// Decimated samples per output sample.
+    step: f64,
+
+    /// Positive half of the windowed sinc kernel, sampled KERNEL_OVERSAMPLE
+    /// times per decimated sample.
+    kernel: Vec<f32>,
+
+    /// DC blocking filter state.
+    dc_last_input: f32,
+    dc_last_output: f32,
+
+    /// Resampled output waiting to be sent to the audio device.
+    output: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        let decimated_rate = input_rate / DECIMATION_FACTOR as f64;
+        let base_step = decimated_rate / output_rate;
+
+        Self {
+            accumulator: 0.0,
+            accumulated: 0,
+            history: VecDeque::with_capacity(KERNEL_HALF_WIDTH * 4),
+            history_start: 0,
+            next_output_time: KERNEL_HALF_WIDTH as f64,
+            base_step,
+            step: base_step,
+            kernel: Self::build_kernel(output_rate * CUTOFF / 2.0 / decimated_rate),
+            dc_last_input: 0.0,
+            dc_last_output: 0.0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Build the positive half of a Blackman windowed sinc low pass kernel.
+    /// Cutoff is given in cycles per decimated sample.
+    fn build_kernel(cutoff: f64) -> Vec<f32> {
+        let len = KERNEL_HALF_WIDTH * KERNEL_OVERSAMPLE + 2;
+        let mut kernel = Vec::with_capacity(len);
+
+        for i in 0..len {
+            let t = i as f64 / KERNEL_OVERSAMPLE as f64;
+
+            let sinc = if i == 0 {
+                1.0
+            } else {
+                let x = 2.0 * PI * cutoff * t;
+                x.sin() / x
+            };
+
+            // Window spans -KERNEL_HALF_WIDTH..KERNEL_HALF_WIDTH
+            let n = (t / KERNEL_HALF_WIDTH as f64 + 1.0) / 2.0;
+            let window = if n >= 1.0 {
+                0.0
+            } else {
+                0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos()
+            };
+
+            kernel.push((2.0 * cutoff * sinc * window) as f32);
+        }
+
+        kernel
+    }
+
+    /// Kernel value at the given distance (in decimated samples) from center.
+    fn kernel_at(&self, distance: f64) -> f32 {
+        let position = distance.abs() * KERNEL_OVERSAMPLE as f64;
+        let index = position as usize;
+        let frac = (position - index as f64) as f32;
+
+        self.kernel[index] + (self.kernel[index + 1] - self.kernel[index]) * frac
+    }
+
+    /// Add one input sample.
+    pub fn push(&mut self, sample: f32) {
+        self.accumulator += sample;
+        self.accumulated += 1;
+
+        if self.accumulated == DECIMATION_FACTOR {
+            let decimated = self.accumulator / DECIMATION_FACTOR as f32;
+            self.accumulator = 0.0;
+            self.accumulated = 0;
+
+            self.push_decimated(decimated);
+        }
+    }
+
+    fn push_decimated(&mut self, sample: f32) {
+        self.history.push_back(sample);
+
+        let newest = self.history_start + self.history.len() as u64 - 1;
+
+        // Produce every output sample whose kernel is now fully covered
+        while self.next_output_time + KERNEL_HALF_WIDTH as f64 <= newest as f64 {
+            let sample = self.interpolate(self.next_output_time);
+            let sample = self.dc_block(sample);
+
+            self.output.push(sample);
+            self.next_output_time += self.step;
+        }
+
+        // Drop samples that are no longer within reach of the kernel
+        let oldest_needed = self.next_output_time as u64 - KERNEL_HALF_WIDTH as u64;
+
+        while self.history_start < oldest_needed {
+            self.history.pop_front();
+            self.history_start += 1;
+        }
+    }
+
+    fn interpolate(&self, time: f64) -> f32 {
+        let center = time.floor();
+        let frac = time - center;
+        let center = center as u64 - self.history_start;
+
+        let mut sum = 0.0;
+
+        for k in 0..(KERNEL_HALF_WIDTH * 2) {
+            let offset = k as f64 - (KERNEL_HALF_WIDTH as f64 - 1.0);
+            let index = (center as f64 + offset) as usize;
+
+            sum += self.history[index] * self.kernel_at(offset - frac);
+        }
+
+        sum
+    }
+
+    /// Remove the DC offset of the APU output.
+    fn dc_block(&mut self, sample: f32) -> f32 {
+        let output = sample - self.dc_last_input + DC_BLOCK_COEFFICIENT * self.dc_last_output;
+
+        self.dc_last_input = sample;
+        self.dc_last_output = output;
+
+        output
+    }
+
+    ///
+    /// Dynamic rate control. Nudge the resampling ratio based on how full the
+    /// audio device's buffer is (0.0 = empty, 1.0 = full), producing slightly
+    /// fewer samples when it is more than half full and slightly more when it
+    /// is less than half full.
+    /// See: https://github.com/libretro/docs/blob/master/archive/ratecontrol.pdf
+    ///
+    pub fn adjust_rate(&mut self, buffer_fill: f64) {
+        let buffer_fill = buffer_fill.clamp(0.0, 1.0);
+
+        self.step = self.base_step * (1.0 + MAX_RATE_DELTA * (2.0 * buffer_fill - 1.0));
+    }
+
+    /// Take all resampled output produced so far.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT_RATE: f64 = 1_786_830.0;
+    const OUTPUT_RATE: f64 = 48_000.0;
+
+    fn resample_sine(freq: f64, seconds: f64) -> Vec<f32> {
+        let mut resampler = Resampler::new(INPUT_RATE, OUTPUT_RATE);
+        let input_count = (INPUT_RATE * seconds) as usize;
+
+        for i in 0..input_count {
+            let t = i as f64 / INPUT_RATE;
+            resampler.push((2.0 * PI * freq * t).sin() as f32);
+        }
+
+        resampler.take_samples()
+    }
+
+    /// Peak amplitude over the second half of the output, once filters have
+    /// settled.
+    fn peak(samples: &[f32]) -> f32 {
+        samples[samples.len() / 2..].iter().fold(0.0, |peak, s| s.abs().max(peak))
+    }
+
+    #[test]
+    fn test_output_rate() {
+        let output = resample_sine(440.0, 0.5);
+        let expected = (OUTPUT_RATE * 0.5) as usize;
+
+        // Allow for the kernel's startup latency
+        assert!(output.len() <= expected);
+        assert!(output.len() > expected - 200);
+    }
+
+    #[test]
+    fn test_passband_and_stopband() {
+        // Audible tone passes through unchanged
+        let passband = peak(&resample_sine(1000.0, 0.2));
+        assert!((passband - 1.0).abs() < 0.02);
+
+        // Tone above the output Nyquist frequency is filtered out
+        let stopband = peak(&resample_sine(30000.0, 0.2));
+        assert!(stopband < 0.01);
+    }
+}
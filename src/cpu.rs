@@ -1,5 +1,6 @@
 use crate::utils;
 use crate::state::NesState;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
 mod constants;
 use constants::*;
@@ -17,7 +18,8 @@ struct Instruction {
     legal: bool,
 }
 
-/// 6502 Addressing modes.
+/// 6502 Addressing modes. Named with the usual 6502 assembler abbreviations.
+#[allow(clippy::upper_case_acronyms)]
 enum AddrMode {
     IMM,
     ABS,
@@ -95,6 +97,13 @@ pub struct Cpu {
     /// Flag signaling NMI interupt was triggered
     nmi_flag: bool,
 
+    /// Value of the I flag used when polling for IRQs before the next
+    /// instruction. Usually the current I flag, but CLI, SEI and PLP change
+    /// the I flag after the poll, so their effect is delayed by one
+    /// instruction.
+    /// See: https://www.nesdev.org/wiki/CPU_interrupts#Delayed_IRQ_response_after_CLI,_SEI,_and_PLP
+    irq_poll_disabled: bool,
+
     /// Trace cpu execution via logging messages.
     trace_cpu: bool,
 }
@@ -113,6 +122,7 @@ impl Cpu {
             cycle_count: 7,
             bytes_consumed: Vec::new(),
             nmi_flag: false,
+            irq_poll_disabled: true,
             trace_cpu,
         };
 
@@ -132,10 +142,59 @@ impl Cpu {
     /// Using reset state documented here:
     ///     https://www.nesdev.org/wiki/CPU_power_up_state
     /// 
-    pub fn _reset(&mut self, state: &mut NesState) {
+    pub fn reset(&mut self, state: &mut NesState) {
         self.reg.PC = state.cpu_mem_read_word(0xFFFC);
-        self.reg.SP -= 3; 
-        self.reg.P = 0x34; // Not sure if this is correct
+        self.reg.SP = self.reg.SP.wrapping_sub(3);
+        utils::set_bit(PS_I_BIT, &mut self.reg.P);
+
+        self.nmi_flag = false;
+        self.irq_poll_disabled = true;
+
+        // The reset sequence takes as long as an interrupt
+        self.cycle_count += 7;
+    }
+
+    /// Write registers and interrupt state to a save state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.reg.A);
+        writer.write_u8(self.reg.X);
+        writer.write_u8(self.reg.Y);
+        writer.write_u16(self.reg.PC);
+        writer.write_u8(self.reg.SP);
+        writer.write_u8(self.reg.P);
+        writer.write_u64(self.cycle_count);
+        writer.write_bool(self.nmi_flag);
+        writer.write_bool(self.irq_poll_disabled);
+    }
+
+    /// Restore registers and interrupt state from a save state.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.reg.A = reader.read_u8()?;
+        self.reg.X = reader.read_u8()?;
+        self.reg.Y = reader.read_u8()?;
+        self.reg.PC = reader.read_u16()?;
+        self.reg.SP = reader.read_u8()?;
+        self.reg.P = reader.read_u8()?;
+        self.cycle_count = reader.read_u64()?;
+        self.nmi_flag = reader.read_bool()?;
+        self.irq_poll_disabled = reader.read_bool()?;
+
+        Ok(())
+    }
+
+    /// Current CPU cycle count.
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    /// True if trace logging of CPU execution is enabled.
+    pub fn trace_cpu(&self) -> bool {
+        self.trace_cpu
+    }
+
+    /// Enable or disable trace logging of CPU execution.
+    pub fn set_trace_cpu(&mut self, trace_cpu: bool) {
+        self.trace_cpu = trace_cpu;
     }
 
     /// Sets the program counter to the given value (for debug/testing).
@@ -155,20 +214,34 @@ impl Cpu {
     /// cycles used by the last instruction.
     /// 
     pub fn cycle_to(&mut self, state: &mut NesState, cycle: u64) -> u64 {
-        let mut instruction_cycles = 0; 
-        let interupt_cycles = self.handle_interupts(state);
-
-        self.cycle_count += interupt_cycles;
+        let mut cycles_used = 0;
 
         while self.cycle_count < cycle {
-            let cpu_cycles_used = self.execute(state);
+            // Interrupts are polled before each instruction. Include any
+            // cycles stolen by OAM/DMC DMA.
+            let cpu_cycles_used = self.handle_interupts(state)
+                + self.execute(state)
+                + state.take_dma_stall_cycles();
+
             self.cycle_count += cpu_cycles_used;
-            instruction_cycles += cpu_cycles_used;
+            cycles_used += cpu_cycles_used;
         }
 
-        interupt_cycles + instruction_cycles
+        cycles_used
     }
 
+    ///
+    /// Service a pending NMI, or an IRQ if the IRQ line is asserted and IRQs
+    /// are not disabled. NMI has priority: if both are pending the NMI is
+    /// taken and the IRQ follows after the NMI handler returns, if the line is
+    /// still asserted.
+    ///
+    /// Interrupts are only polled between instructions, and the PPU raises
+    /// NMI after the CPU has run its batch of cycles, so an NMI can't become
+    /// pending partway through the BRK/IRQ push sequence. NMI hijacking of
+    /// BRK and IRQ is therefore not emulated.
+    /// See: https://www.nesdev.org/wiki/CPU_interrupts
+    ///
     fn handle_interupts(&mut self, state: &mut NesState) -> u64 {
         let mut interupt_cycles = 0;
 
@@ -190,10 +263,25 @@ impl Cpu {
 
             interupt_cycles = 7;
         }
-        // Make sure to else if here when adding more interrupts to avoid
-        // overriding the reaction to NMI. Also need to read more about
-        // interrupt hijacking as there are specific behaviors when multple
-        // interrupts are triggered together or over a short time period.
+        else if !self.irq_poll_disabled && state.irq_line() {
+            self.stack_push_word(state, self.reg.PC);
+
+            // B flag clear, bit 5 set for hardware interrupts
+            let mut p_val = self.reg.P;
+            utils::clear_bit(PS_B_BIT, &mut p_val);
+            utils::set_bit(5, &mut p_val);
+            self.stack_push(state, p_val);
+
+            utils::set_bit(PS_I_BIT, &mut self.reg.P);
+
+            self.reg.PC = state.cpu_mem_read_word(0xFFFE);
+
+            interupt_cycles = 7;
+        }
+
+        if interupt_cycles > 0 {
+            self.irq_poll_disabled = true;
+        }
 
         interupt_cycles
     }
@@ -218,9 +306,16 @@ impl Cpu {
             self.print_log_line(instruction, instruction_address);
         }
 
+        let i_flag_before = utils::bit_is_set(PS_I_BIT, self.reg.P);
+
         // Execute 
         (instruction.func)(self, state);
 
+        self.irq_poll_disabled = match self.opcode {
+            OPCODE_CLI | OPCODE_SEI | OPCODE_PLP => i_flag_before,
+            _ => utils::bit_is_set(PS_I_BIT, self.reg.P),
+        };
+
         let total_cycles = instruction.cycles + self.extra_cycles;
 
         total_cycles
@@ -286,15 +381,18 @@ impl Cpu {
         state.cpu_mem_read_word(addr)
     }
 
+    ///
+    /// This function should be used for all instruction writes to CPU memory.
+    /// Stores and read-modify-write instructions write on their last cycle,
+    /// so the write cycle is approximated as the last cycle of the currently
+    /// executing instruction. Registers such as $4014 and $4017 depend on
+    /// whether that cycle is odd or even.
+    ///
     fn do_mem_write(&mut self, state: &mut NesState, addr: u16, value: u8) {
-        //let instruction = &Cpu::OP_CODES[self.opcode as usize];
-        //let cycle = self.cycle_count + instruction.cycles + self.extra_cycles;
-
-        state.cpu_mem_write(addr, value, self.cycle_count);
+        let instruction = &Cpu::OP_CODES[self.opcode as usize];
+        let cycle = (self.cycle_count + instruction.cycles + self.extra_cycles).saturating_sub(1);
 
-        if addr == 0x4014 { // PPU OAM DMA port, takes 513 or 514 cycles
-            self.extra_cycles += 513;
-        }
+        state.cpu_mem_write(addr, value, cycle);
     }
 
     fn read_byte(&mut self, state: &mut NesState) -> u8 {
@@ -686,6 +784,7 @@ impl Cpu {
 
         self.reg.PC = self.do_mem_read_word(state, 0xFFFE);
         utils::set_bit(PS_B_BIT, &mut self.reg.P);
+        utils::set_bit(PS_I_BIT, &mut self.reg.P);
     }
 
     fn bvc(&mut self, state: &mut NesState) {
@@ -1388,7 +1487,9 @@ mod tests {
     use super::*;
     use crate::mem::{Memory, PpuMemory};
     use crate::ppu::Ppu;
+    use crate::apu::Apu;
     use crate::mappers::get_mapper;
+    use crate::timing::Timing;
 
     impl Cpu {
         pub fn default() -> Self {
@@ -1402,6 +1503,7 @@ mod tests {
                 cycle_count: 0,
                 bytes_consumed: Vec::new(),
                 nmi_flag: false,
+                irq_poll_disabled: true,
                 trace_cpu: false,
             }
         }
@@ -1413,8 +1515,9 @@ mod tests {
             Some(mem) => mem
         };
 
-        NesState::new(get_mapper(0, cpu_mem, PpuMemory::new()),
-                      Rc::new(RefCell::new(Ppu::new())))
+        NesState::new(get_mapper(0, 0, cpu_mem, PpuMemory::new()).unwrap(),
+                      Rc::new(RefCell::new(Ppu::new(Timing::default()))),
+                      Rc::new(RefCell::new(Apu::new(Timing::default()))))
     }
 
     fn get_state_with_cpu_mem_ramp() -> NesState {
@@ -1430,6 +1533,26 @@ mod tests {
         get_mem_controller(Some(cpu_mem))
     }
 
+    #[test]
+    fn test_cpu_reset() {
+        let mut state = get_state_with_cpu_mem_ramp();
+        let mut cpu = Cpu::new(&mut state, false);
+
+        cpu.reg.A = 0x12;
+        cpu.reg.SP = 0x01;
+        cpu.reg.P = 0x20;
+        cpu.reg.PC = 0x1234;
+
+        cpu.reset(&mut state);
+
+        // Ramp memory puts 0xFDFC at the reset vector
+        assert!(cpu.reg.PC == 0xFDFC);
+        assert!(cpu.reg.SP == 0xFE);
+        assert!(cpu.reg.P == 0x24);
+        assert!(cpu.reg.A == 0x12);
+        assert!(cpu.cycle_count == 14);
+    }
+
     #[test]
     fn test_cpu_read_byte() {
         let mut state = get_state_with_cpu_mem_ramp();
@@ -1672,4 +1795,91 @@ mod tests {
 
         assert!(cpu.reg.A == 0x47);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_irq_delayed_after_cli() {
+        let start_addr = 0x0200;
+        let irq_handler = 0x0300;
+        let mut cpu_mem = Memory::new_cpu();
+
+        cpu_mem.load(start_addr, &[OPCODE_CLI, OPCODE_NOP, OPCODE_NOP]);
+        cpu_mem.load(irq_handler, &[OPCODE_NOP]);
+        cpu_mem.load(0xFFFE, &[0x00, 0x03]);
+
+        let apu = Rc::new(RefCell::new(Apu::new(Timing::default())));
+        let mut state = NesState::new(get_mapper(0, 0, cpu_mem, PpuMemory::new()).unwrap(),
+                                      Rc::new(RefCell::new(Ppu::new(Timing::default()))),
+                                      Rc::clone(&apu));
+
+        // Run the APU until the frame counter raises its IRQ
+        for _ in 0..29830 {
+            apu.borrow_mut().cycle(&mut state);
+        }
+        assert!(state.irq_line());
+
+        let mut cpu = Cpu::default();
+        cpu.reg.P = 0x24; // I flag set
+        cpu.reg.SP = 0xFD;
+        cpu.reg.PC = start_addr;
+
+        // CLI, then the IRQ is held off for one more instruction
+        cpu.cycle_to(&mut state, 1);
+        assert!(cpu.reg.PC == start_addr + 1);
+
+        cpu.cycle_to(&mut state, cpu.cycle_count + 1);
+        assert!(cpu.reg.PC == start_addr + 2);
+
+        // IRQ is taken before the next instruction
+        cpu.cycle_to(&mut state, cpu.cycle_count + 1);
+        assert!(cpu.reg.PC == irq_handler + 1);
+        assert!(utils::bit_is_set(PS_I_BIT, cpu.reg.P));
+    }
+
+    #[test]
+    fn test_frame_counter_write_delay_from_sta() {
+        // STA $4017 writes on its 4th cycle. The frame counter resets 3 CPU
+        // cycles after a write on an even cycle, 4 after an odd one.
+        for (start_cycle, delay) in [(8, 4), (9, 3)] {
+            let mut cpu_mem = Memory::new_cpu();
+            cpu_mem.load(0x0300, &[0x8D, 0x17, 0x40]);
+
+            let apu = Rc::new(RefCell::new(Apu::new(Timing::default())));
+            let mut state = NesState::new(get_mapper(0, 0, cpu_mem, PpuMemory::new()).unwrap(),
+                                          Rc::new(RefCell::new(Ppu::new(Timing::default()))),
+                                          Rc::clone(&apu));
+
+            let mut cpu = Cpu::default();
+            cpu.reg.A = 0x00;
+            cpu.reg.PC = 0x0300;
+            cpu.cycle_count = start_cycle;
+            cpu.cycle_to(&mut state, start_cycle + 1);
+
+            // The frame IRQ is raised 29828 cycles after the reset
+            for _ in 0..(delay - 1 + 29827) {
+                apu.borrow_mut().cycle(&mut state);
+            }
+            assert!(!state.irq_line());
+
+            apu.borrow_mut().cycle(&mut state);
+            assert!(state.irq_line());
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_stall_from_sta() {
+        // STA $4014 writes on its 4th cycle. OAM DMA takes 513 cycles, plus
+        // one more to align if that write was on an odd cycle.
+        for (start_cycle, stall_cycles) in [(8, 514), (9, 513)] {
+            let mut cpu_mem = Memory::new_cpu();
+            cpu_mem.load(0x0300, &[0x8D, 0x14, 0x40]);
+            let mut state = get_mem_controller(Some(cpu_mem));
+
+            let mut cpu = Cpu::default();
+            cpu.reg.A = 0x02;
+            cpu.reg.PC = 0x0300;
+            cpu.cycle_count = start_cycle;
+
+            assert!(cpu.cycle_to(&mut state, start_cycle + 1) == 4 + stall_cycles);
+        }
+    }
+}
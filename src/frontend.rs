@@ -0,0 +1,93 @@
+use retrobrite::Nes;
+
+/// Sample rate reported by frontends with no audio output.
+const NULL_AUDIO_SAMPLE_RATE: u32 = 48_000;
+
+///
+/// Actions requested by the user that the main loop must carry out.
+///
+#[cfg_attr(not(feature = "sdl2"), allow(dead_code))]
+pub enum GuiAction {
+    /// Exit the emulator.
+    Quit,
+
+    /// Save the machine state to the given slot.
+    SaveState(u8),
+
+    /// Restore the machine state from the given slot.
+    LoadState(u8),
+
+    /// Step back to the previous rewind state. Sent once per frame while the
+    /// rewind key is held.
+    Rewind,
+
+    /// Run faster than real time. Sent once per frame while the fast-forward
+    /// key is held.
+    FastForward,
+
+    /// Switch to the next slow motion speed.
+    CycleSlowMotion,
+
+    /// Toggle skipping the rendering of frames while fast-forwarding.
+    ToggleFrameSkip,
+
+    /// Press the NES reset button.
+    Reset,
+
+    /// Turn the NES off and back on.
+    PowerCycle,
+
+    /// Pause emulation, or resume it if already paused.
+    TogglePause,
+
+    /// Run a single frame, pausing first if not already paused.
+    FrameAdvance,
+
+    /// Run a single scanline, pausing first if not already paused.
+    ScanlineAdvance,
+}
+
+///
+/// Video, audio and input backend driven by the main loop once per frame.
+///
+pub trait Frontend {
+    /// Sample rate the frontend wants audio samples in.
+    fn audio_sample_rate(&self) -> u32;
+
+    /// Display a frame output by Nes::frame_buffer.
+    fn render_frame(&mut self, frame: &[u8]);
+
+    ///
+    /// Queue samples for playback. Returns how full the frontend's audio
+    /// queue is, from 0.0 (empty) to 1.0 (full), for use in dynamic rate
+    /// control.
+    ///
+    fn queue_audio(&mut self, samples: &[f32]) -> f64;
+
+    /// Process pending UI events, updating controller state and returning
+    /// any actions the main loop should carry out.
+    fn process_events(&mut self, nes: &mut Nes) -> Vec<GuiAction>;
+}
+
+///
+/// Frontend that discards video and audio and never supplies input. Used to
+/// run headless (on CI machines, for automated tests, etc).
+///
+pub struct NullFrontend;
+
+impl Frontend for NullFrontend {
+    fn audio_sample_rate(&self) -> u32 {
+        NULL_AUDIO_SAMPLE_RATE
+    }
+
+    fn render_frame(&mut self, _frame: &[u8]) {}
+
+    fn queue_audio(&mut self, _samples: &[f32]) -> f64 {
+        // Always report a half full queue so the rate is never adjusted
+        0.5
+    }
+
+    fn process_events(&mut self, _nes: &mut Nes) -> Vec<GuiAction> {
+        Vec::new()
+    }
+}
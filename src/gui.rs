@@ -1,3 +1,7 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use sdl2::controller::{GameController, Button};
 use sdl2::GameControllerSubsystem;
 use sdl2::{video::Window, EventPump, Sdl, VideoSubsystem};
@@ -7,7 +11,15 @@ use sdl2::render::Canvas;
 use sdl2::surface::Surface;
 use sdl2::pixels::PixelFormatEnum;
 
-use crate::state::NesState;
+use retrobrite::Nes;
+use retrobrite::nes::{
+    A_BUTTON_MASK, B_BUTTON_MASK, SELECT_BUTTON_MASK, START_BUTTON_MASK,
+    UP_BUTTON_MASK, DOWN_BUTTON_MASK, LEFT_BUTTON_MASK, RIGHT_BUTTON_MASK,
+    FRAME_WIDTH, Port,
+};
+use retrobrite::savestate::NUM_SLOTS;
+
+use crate::frontend::{Frontend, GuiAction};
 
 /// NES resolution width
 const WIDTH: u32 = 256;
@@ -24,86 +36,41 @@ const TOP_OVERSCAN: u16 = 8;
 
 const FRAME_BUFFER_SIZE_IN_BYTES: usize = (WIDTH * HEIGHT * 3) as usize;
 
-const PALETTE: [[u8; 3]; 64] = [
-        [0x62, 0x62, 0x62],
-        [0x00, 0x1f, 0xb2],
-        [0x24, 0x04, 0xc8],
-        [0x52, 0x00, 0xb2],
-        [0x73, 0x00, 0x76],
-        [0x80, 0x00, 0x24],
-        [0x73, 0x0b, 0x00],
-        [0x52, 0x28, 0x00],
-        [0x24, 0x44, 0x00],
-        [0x00, 0x57, 0x00],
-        [0x00, 0x5c, 0x00],
-        [0x00, 0x53, 0x24],
-        [0x00, 0x3c, 0x76],
-        [0x00, 0x00, 0x00],
-        [0x00, 0x00, 0x00],
-        [0x00, 0x00, 0x00],
-
-        [0xab, 0xab, 0xab],
-        [0x0d, 0x57, 0xff],
-        [0x4b, 0x30, 0xff],
-        [0x8a, 0x13, 0xff],
-        [0xbc, 0x08, 0xd6],
-        [0xd2, 0x12, 0x69],
-        [0xc7, 0x2e, 0x00],
-        [0x9d, 0x54, 0x00],
-        [0x60, 0x7b, 0x00],
-        [0x20, 0x98, 0x00],
-        [0x00, 0xa3, 0x00],
-        [0x00, 0x99, 0x42],
-        [0x00, 0x7d, 0xb4],
-        [0x00, 0x00, 0x00],
-        [0x00, 0x00, 0x00],
-        [0x00, 0x00, 0x00],
-
-        [0xff, 0xff, 0xff],
-        [0x53, 0xae, 0xff],
-        [0x90, 0x85, 0xff],
-        [0xd3, 0x65, 0xff],
-        [0xff, 0x57, 0xff],
-        [0xff, 0x5d, 0xcf],
-        [0xff, 0x77, 0x57],
-        [0xfa, 0x9e, 0x00],
-        [0xbd, 0xc7, 0x00],
-        [0x7a, 0xe7, 0x00],
-        [0x43, 0xf6, 0x11],
-        [0x26, 0xef, 0x7e],
-        [0x2c, 0xd5, 0xf6],
-        [0x4e, 0x4e, 0x4e],
-        [0x00, 0x00, 0x00],
-        [0x00, 0x00, 0x00],
-
-        [0xff, 0xff, 0xff],
-        [0xb6, 0xe1, 0xff],
-        [0xce, 0xd1, 0xff],
-        [0xe9, 0xc3, 0xff],
-        [0xff, 0xbc, 0xff],
-        [0xff, 0xbd, 0xf4],
-        [0xff, 0xc6, 0xc3],
-        [0xff, 0xd5, 0x9a],
-        [0xe9, 0xe6, 0x81],
-        [0xce, 0xf4, 0x81],
-        [0xb6, 0xfb, 0x9a],
-        [0xa9, 0xfa, 0xc3],
-        [0xa9, 0xf0, 0xf4],
-        [0xb8, 0xb8, 0xb8],
-        [0x00, 0x00, 0x00],
-        [0x00, 0x00, 0x00],
-];
-
-/// Button masks
-const A_BUTTON_MASK: u8 = 1;
-const B_BUTTON_MASK: u8 = 2;
-const SELECT_BUTTON_MASK: u8 = 4;
-const START_BUTTON_MASK: u8 = 8;
-const UP_BUTTON_MASK: u8 = 16;
-const DOWN_BUTTON_MASK: u8 = 32;
-const LEFT_BUTTON_MASK: u8 = 64;
-const RIGHT_BUTTON_MASK: u8 = 128;
+/// Requested audio device sample rate.
+const AUDIO_SAMPLE_RATE: i32 = 48_000;
+
+/// Requested audio device buffer size, in samples.
+const AUDIO_DEVICE_BUFFER_SIZE: u16 = 1024;
+
+/// Maximum number of samples held in the audio queue. Dynamic rate control
+/// aims to keep the queue half full.
+const AUDIO_QUEUE_CAPACITY: usize = 4096;
+
+
+///
+/// SDL audio callback. Plays samples from a queue shared with the Gui. On
+/// underrun the last sample is repeated to avoid popping.
+///
+struct AudioOutput {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    last_sample: f32,
+}
 
+impl AudioCallback for AudioOutput {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let mut queue = self.queue.lock().unwrap();
+
+        for sample in out.iter_mut() {
+            if let Some(queued) = queue.pop_front() {
+                self.last_sample = queued;
+            }
+
+            *sample = self.last_sample;
+        }
+    }
+}
 
 pub struct Gui {
     // I think we need to keep sdl_context and video subsystem around
@@ -119,6 +86,21 @@ pub struct Gui {
     game_controllers: Vec<GameController>,
     controller1_state: u8,
     controller2_state: u8,
+
+    /// Audio device, if one could be opened.
+    audio_device: Option<AudioDevice<AudioOutput>>,
+
+    /// Samples waiting to be played, shared with the audio callback.
+    audio_queue: Arc<Mutex<VecDeque<f32>>>,
+
+    /// Currently selected save state slot.
+    save_slot: u8,
+
+    /// True while the rewind key is held.
+    rewind_held: bool,
+
+    /// True while the fast-forward key is held.
+    fast_forward_held: bool,
 }
 
 impl Gui {
@@ -136,6 +118,9 @@ impl Gui {
 
         let event_pump = sdl_context.event_pump()?;
 
+        let audio_queue = Arc::new(Mutex::new(VecDeque::with_capacity(AUDIO_QUEUE_CAPACITY)));
+        let audio_device = Self::init_audio(&sdl_context, &audio_queue);
+
         let mut gui = Self {
                 sdl_context,
                 video_subsystem,
@@ -146,6 +131,11 @@ impl Gui {
                 game_controllers: Vec::new(),
                 controller1_state: 0,
                 controller2_state: 0,
+                audio_device,
+                audio_queue,
+                save_slot: 0,
+                rewind_held: false,
+                fast_forward_held: false,
         };
 
         gui.init_controllers();
@@ -153,6 +143,37 @@ impl Gui {
         Ok(gui)
     }
 
+    /// Open the default audio device. Emulation continues without sound if
+    /// this fails.
+    fn init_audio(sdl_context: &Sdl, queue: &Arc<Mutex<VecDeque<f32>>>) -> Option<AudioDevice<AudioOutput>> {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(AUDIO_SAMPLE_RATE),
+            channels: Some(1),
+            samples: Some(AUDIO_DEVICE_BUFFER_SIZE),
+        };
+
+        let device = sdl_context.audio().and_then(|audio_subsystem| {
+            audio_subsystem.open_playback(None, &desired_spec, |_spec| {
+                AudioOutput {
+                    queue: Arc::clone(queue),
+                    last_sample: 0.0,
+                }
+            })
+        });
+
+        match device {
+            Ok(device) => {
+                info!("audio: {} Hz, buffer size {}", device.spec().freq, device.spec().samples);
+                device.resume();
+                Some(device)
+            },
+            Err(e) => {
+                warn!("could not open audio device, continuing without sound: {e}");
+                None
+            }
+        }
+    }
+
     fn init_controllers(&mut self) {
         let num_controllers = self.gc_subsystem.num_joysticks().unwrap();
         println!("Found {num_controllers} game controllers");
@@ -175,34 +196,43 @@ impl Gui {
 
         self.gc_subsystem.set_event_state(true);
     }
+}
 
-    pub fn set_pixel(&mut self, x: u16, y: u16, value: u8) {
-        // Skip scanlines in the overscan portion of the top of the screen
-        if y < TOP_OVERSCAN {
-            return;
+impl Frontend for Gui {
+    /// Sample rate of the audio device.
+    fn audio_sample_rate(&self) -> u32 {
+        match &self.audio_device {
+            Some(device) => device.spec().freq as u32,
+            None => AUDIO_SAMPLE_RATE as u32,
         }
+    }
 
-        //println!("pixel value {}", value);
-
-        // Skip overscan lines at the bottom of the screen that would be out
-        // of range of our frame buffer.
-        if y - TOP_OVERSCAN >= HEIGHT as u16 {
-            return;
+    ///
+    /// Queue samples for playback. Returns how full the queue is, from 0.0
+    /// (empty) to 1.0 (full), for use in dynamic rate control. If the queue
+    /// overflows the oldest samples are dropped.
+    ///
+    fn queue_audio(&mut self, samples: &[f32]) -> f64 {
+        if self.audio_device.is_none() {
+            return 0.5;
         }
 
-        let xu = x as usize;
-        let yu = (y - TOP_OVERSCAN) as usize;
-        let index = (yu * WIDTH as usize * 3) + xu*3;
+        let mut queue = self.audio_queue.lock().unwrap();
+        queue.extend(samples);
 
-        // Mod value by 64 because some games rely on the palette value wrapping
-        // after 64
-        let rgb = PALETTE[(value % 64) as usize];
-        self.frame_buffer[index]   = rgb[0];
-        self.frame_buffer[index+1] = rgb[1];
-        self.frame_buffer[index+2] = rgb[2];
+        if queue.len() > AUDIO_QUEUE_CAPACITY {
+            let excess = queue.len() - AUDIO_QUEUE_CAPACITY;
+            queue.drain(..excess);
+        }
+
+        queue.len() as f64 / AUDIO_QUEUE_CAPACITY as f64
     }
 
-    pub fn render_frame(&mut self) {
+    /// Display a frame output by Nes::frame_buffer, omitting overscan.
+    fn render_frame(&mut self, frame: &[u8]) {
+        let start = TOP_OVERSCAN as usize * FRAME_WIDTH * 3;
+        self.frame_buffer.copy_from_slice(&frame[start..start + FRAME_BUFFER_SIZE_IN_BYTES]);
+
         let texture_creator = self.canvas.texture_creator();
         let surface = Surface::from_data(&mut self.frame_buffer,
                                          WIDTH, HEIGHT, WIDTH*3, 
@@ -214,13 +244,64 @@ impl Gui {
         self.canvas.present();
     }
 
-    pub fn process_events(&mut self, state: &mut NesState) -> bool {
-        let mut result = true;
+    ///
+    /// Process pending UI events, updating controller state and returning any
+    /// actions the main loop should carry out.
+    ///
+    /// Save state hotkeys:
+    ///   - 0-9: Select save state slot
+    ///   - F5: Save state to selected slot
+    ///   - F8: Load state from selected slot
+    ///   - Backspace (hold): Rewind
+    ///
+    /// Debug hotkeys:
+    ///   - P: Pause/resume
+    ///   - N: Advance one frame
+    ///   - M: Advance one scanline
+    ///
+    /// Console hotkeys:
+    ///   - F1: Reset
+    ///   - F2: Power cycle
+    ///
+    /// Speed hotkeys:
+    ///   - Tab (hold): Fast-forward
+    ///   - F3: Cycle slow motion speed (100%, 50%, 25%)
+    ///   - F4: Toggle frame skip while fast-forwarding
+    ///
+    fn process_events(&mut self, nes: &mut Nes) -> Vec<GuiAction> {
+        let mut actions = Vec::new();
         for event in self.event_pump.poll_iter() {
             //println!("{:?}", event);
             match event {
                 Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                    result = false;
+                    actions.push(GuiAction::Quit);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => {
+                    actions.push(GuiAction::SaveState(self.save_slot));
+                },
+                Event::KeyDown { keycode: Some(Keycode::F8), repeat: false, .. } => {
+                    actions.push(GuiAction::LoadState(self.save_slot));
+                },
+                Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => {
+                    actions.push(GuiAction::Reset);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => {
+                    actions.push(GuiAction::PowerCycle);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F3), repeat: false, .. } => {
+                    actions.push(GuiAction::CycleSlowMotion);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F4), repeat: false, .. } => {
+                    actions.push(GuiAction::ToggleFrameSkip);
+                },
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => {
+                    actions.push(GuiAction::TogglePause);
+                },
+                Event::KeyDown { keycode: Some(Keycode::N), .. } => {
+                    actions.push(GuiAction::FrameAdvance);
+                },
+                Event::KeyDown { keycode: Some(Keycode::M), .. } => {
+                    actions.push(GuiAction::ScanlineAdvance);
                 },
                 Event::KeyDown { keycode: key, .. } => {
                     let code = key.unwrap();
@@ -250,6 +331,18 @@ impl Gui {
                         Keycode::Right => {
                             self.controller1_state |= RIGHT_BUTTON_MASK;
                         },
+                        Keycode::Backspace => {
+                            self.rewind_held = true;
+                        },
+                        Keycode::Tab => {
+                            self.fast_forward_held = true;
+                        },
+                        Keycode::Num0 | Keycode::Num1 | Keycode::Num2 | Keycode::Num3 |
+                        Keycode::Num4 | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 |
+                        Keycode::Num8 | Keycode::Num9 => {
+                            self.save_slot = (code as i32 - Keycode::Num0 as i32) as u8 % NUM_SLOTS;
+                            println!("Save state slot {} selected", self.save_slot);
+                        },
                         _ => ()
                     }
 
@@ -286,6 +379,12 @@ impl Gui {
                         Keycode::Right => {
                             self.controller1_state &= !RIGHT_BUTTON_MASK;
                         },
+                        Keycode::Backspace => {
+                            self.rewind_held = false;
+                        },
+                        Keycode::Tab => {
+                            self.fast_forward_held = false;
+                        },
                         _ => ()
                     }
                     //println!("released key: {}", code);
@@ -356,9 +455,17 @@ impl Gui {
             }
         }
 
-        state.set_controller1_state(self.controller1_state);
-        state.set_controller2_state(self.controller2_state);
+        nes.set_input(Port::One, self.controller1_state);
+        nes.set_input(Port::Two, self.controller2_state);
+
+        if self.rewind_held {
+            actions.push(GuiAction::Rewind);
+        }
+
+        if self.fast_forward_held {
+            actions.push(GuiAction::FastForward);
+        }
 
-        result
+        actions
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,7 @@
-use std::{fs, path::Path, str::FromStr};
+use std::{fmt, fs, path::{Path, PathBuf}};
+use crate::archive;
+use crate::patch::{self, PatchError, PATCH_EXTENSIONS};
+use crate::romdb::{self, RomDbEntry};
 use crate::utils::bit_is_set;
 
 const HEADER_SIZE: usize = 16;
@@ -17,13 +20,133 @@ pub const CHR_ROM_CHUNK_SIZE: usize = 8192;
 /** Offset to num chr rom chunks in header. */
 const CHR_ROM_CHUNKS_OFFSET: usize = 5;
 
+/** Size of the trainer that may follow the header. */
+pub const TRAINER_SIZE: usize = 512;
+
+/**
+ * Largest rom file unpacked from an archive. Far bigger than any real rom,
+ * but stops a small archive from claiming gigabytes of data.
+ */
+pub const MAX_ROM_SIZE: usize = 64 * 1024 * 1024;
+
+/** Default size of PRG RAM for ines (1.0) files that don't specify one. */
+const DEFAULT_PRG_RAM_SIZE: usize = 8192;
+
+///
+/// Errors that can occur while loading a rom.
+///
 #[derive(Debug)]
+pub enum RomError {
+    /// Data does not start with the iNES magic number.
+    InvalidHeader,
+
+    /// File ended before the given section was complete.
+    Truncated { section: &'static str, expected: usize, actual: usize },
+
+    /// Header specifies no PRG ROM.
+    MissingPrgRom,
+
+    /// Header specifies a PRG or CHR ROM size too large to represent.
+    InvalidSize { section: &'static str },
+
+    /// Rom uses a mapper that is not implemented.
+    UnsupportedMapper(u16),
+
+    /// Mapper does not support the rom's PRG or CHR ROM size.
+    UnsupportedSize { mapper: &'static str, section: &'static str, size: usize },
+
+    /// Zip archive could not be read.
+    Archive(String),
+
+    /// Archive does not contain a .nes file.
+    NoRomInArchive,
+
+    /// Requested file is not in the archive.
+    ArchiveEntryNotFound(String),
+
+    /// File in the archive unpacks to more than MAX_ROM_SIZE bytes.
+    ArchiveTooLarge,
+
+    /// Patch could not be applied to the rom.
+    Patch(PatchError),
+
+    /// Rom file could not be read.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::InvalidHeader => write!(f, "not an iNES rom file"),
+            RomError::Truncated { section, expected, actual } => {
+                write!(f, "rom file is truncated: {section} needs {expected} bytes, but only {actual} are present")
+            },
+            RomError::MissingPrgRom => write!(f, "rom has no PRG ROM"),
+            RomError::InvalidSize { section } => write!(f, "rom header has an invalid {section} size"),
+            RomError::UnsupportedMapper(number) => write!(f, "unsupported mapper: {number}"),
+            RomError::UnsupportedSize { mapper, section, size } => {
+                write!(f, "{mapper} does not support {size} bytes of {section}")
+            },
+            RomError::Archive(e) => write!(f, "invalid archive: {e}"),
+            RomError::NoRomInArchive => write!(f, "archive does not contain a .nes file"),
+            RomError::ArchiveEntryNotFound(name) => write!(f, "archive does not contain {name}"),
+            RomError::ArchiveTooLarge => write!(f, "archived rom is larger than {MAX_ROM_SIZE} bytes"),
+            RomError::Patch(e) => write!(f, "failed to apply patch: {e}"),
+            RomError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for RomError {
+    fn from(e: std::io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
+
+impl From<PatchError> for RomError {
+    fn from(e: PatchError) -> Self {
+        RomError::Patch(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum FileFormat {
     INES,
     INES2,
 }
 
-#[derive(Debug)]
+/**
+ * Type of console the rom was made for (Flags 7, bits 0-1).
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConsoleType {
+    Nes,
+
+    /** Nintendo Vs. System, with its PPU and hardware type (NES 2.0 only). */
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+
+    Playchoice10,
+
+    /** Extended console type from byte 13 (NES 2.0 only). */
+    Extended(u8),
+}
+
+/**
+ * CPU/PPU timing the rom expects.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+
+    /** Works on both NTSC and PAL consoles. */
+    Multi,
+
+    /** Dendy (PAL famiclone). */
+    Dendy,
+}
+
+#[derive(Debug, Clone)]
 pub enum MirroringType {
     Horizontal,
     Vertical,
@@ -32,7 +155,7 @@ pub enum MirroringType {
 /**
  * Fields derived from the "Flags 6" byte of the header.
  */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Flags6 {
     pub mirroring: MirroringType,
     pub _has_battery_backed_prg_ram: bool,
@@ -44,18 +167,18 @@ pub struct Flags6 {
 /**
  * Fields derived from the "Flags 7" byte of the header.
  */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Flags7 {
     pub _vs_unisystem: bool,
     pub _playchoice_10: bool,
-    pub _file_format: FileFormat,
+    pub file_format: FileFormat,
     pub mapper_upper_nybble: u8,
 }
 
 /**
  * Ines file header data.
  */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct InesHeader {
     //file_id: [u8; 4],
     pub num_prg_rom_chunks: usize,
@@ -66,6 +189,40 @@ pub struct InesHeader {
 
     /** Fields from "Flags 7". */
     pub flags7: Flags7,
+
+    /** Full (12-bit for NES 2.0) mapper number. */
+    pub mapper_number: u16,
+
+    /** Submapper number (NES 2.0 only, otherwise 0). */
+    pub submapper_number: u8,
+
+    /** Size of PRG ROM in bytes. */
+    pub prg_rom_size: usize,
+
+    /** Size of CHR ROM in bytes. */
+    pub chr_rom_size: usize,
+
+    /** Size of volatile PRG RAM in bytes. */
+    pub prg_ram_size: usize,
+
+    /** Size of battery backed PRG RAM in bytes. */
+    pub prg_nvram_size: usize,
+
+    /** Size of volatile CHR RAM in bytes. */
+    pub chr_ram_size: usize,
+
+    /** Size of battery backed CHR RAM in bytes. */
+    pub chr_nvram_size: usize,
+
+    pub console_type: ConsoleType,
+
+    pub region: Region,
+
+    /** Number of miscellaneous roms after CHR ROM (NES 2.0 only). */
+    pub num_misc_roms: u8,
+
+    /** Default expansion device (NES 2.0 only, 0 if unspecified). */
+    pub default_expansion_device: u8,
 }
 
 /**
@@ -74,7 +231,19 @@ pub struct InesHeader {
 #[derive(Debug)]
 pub struct InesRom {
     pub rom_name: String,
+
+    /** Header with any corrections from the rom database applied. */
     pub header: InesHeader,
+
+    /** Header as found in the file. */
+    pub raw_header: InesHeader,
+
+    /** Rom database entry matching this rom's PRG and CHR ROM, if any. */
+    pub db_entry: Option<&'static RomDbEntry>,
+
+    /** Trainer to be loaded into PRG RAM at $7000-$71FF, if the rom has one. */
+    pub trainer: Option<[u8; TRAINER_SIZE]>,
+
     pub prg_rom: Vec<[u8; PRG_ROM_CHUNK_SIZE]>,
     pub chr_rom: Vec<[u8; CHR_ROM_CHUNK_SIZE]>,
 }
@@ -112,44 +281,199 @@ impl Flags7 {
         Self {
             _vs_unisystem: bit_is_set(0, flags),
             _playchoice_10: bit_is_set(1, flags),
-            _file_format: file_format,
+            file_format,
             mapper_upper_nybble: flags & 0xf0,
         }
     }
 }
 
 impl InesHeader {
-    fn parse(buffer: &[u8]) -> Self {
-        if buffer[0..4] != NES_FILE_ID {
-            panic!("nesrom: invalid file identification");
+    fn parse(buffer: &[u8]) -> Result<Self, RomError> {
+        if buffer.len() < 4 || buffer[0..4] != NES_FILE_ID {
+            return Err(RomError::InvalidHeader);
+        }
+
+        check_size("header", HEADER_SIZE, buffer.len())?;
+
+        let flags6 = Flags6::parse(buffer[6]);
+        let flags7 = Flags7::parse(buffer[7]);
+
+        let mut header = if flags7.file_format == FileFormat::INES2 {
+            Self::parse_ines2(buffer, flags6, flags7)?
+        } else {
+            Self::parse_ines(buffer, flags6, flags7)
+        };
+
+        header.num_prg_rom_chunks = header.prg_rom_size.div_ceil(PRG_ROM_CHUNK_SIZE);
+        header.num_chr_rom_chunks = header.chr_rom_size.div_ceil(CHR_ROM_CHUNK_SIZE);
+
+        if header.prg_rom_size == 0 {
+            return Err(RomError::MissingPrgRom);
         }
 
-        let num_prg_rom_chunks = usize::from(buffer[PRG_ROM_CHUNKS_OFFSET]);
-        let num_chr_rom_chunks = usize::from(buffer[CHR_ROM_CHUNKS_OFFSET]);
+        Ok(header)
+    }
+
+    ///
+    /// Parse the remainder of an ines (1.0) header. Bytes 8-15 are mostly
+    /// unused, so sizes not given by the header get common defaults.
+    /// See: https://www.nesdev.org/wiki/INES
+    ///
+    fn parse_ines(buffer: &[u8], flags6: Flags6, flags7: Flags7) -> Self {
+        // Some old dumping tools wrote a signature (e.g. "DiskDude!") over
+        // bytes 7-15. Ignore the upper mapper nybble if the padding isn't
+        // zeroed.
+        let mapper_upper_nybble = if buffer[12..16].iter().all(|&b| b == 0) {
+            flags7.mapper_upper_nybble
+        } else {
+            0
+        };
+
+        let mapper_number = (mapper_upper_nybble | flags6.mapper_lower_nybble) as u16;
+
+        let prg_rom_size = usize::from(buffer[PRG_ROM_CHUNKS_OFFSET]) * PRG_ROM_CHUNK_SIZE;
+        let chr_rom_size = usize::from(buffer[CHR_ROM_CHUNKS_OFFSET]) * CHR_ROM_CHUNK_SIZE;
+
+        // Byte 8 is PRG RAM size in 8KB units, with 0 meaning 8KB
+        let prg_ram_size = usize::from(buffer[8]).max(1) * DEFAULT_PRG_RAM_SIZE;
+
+        let (prg_ram_size, prg_nvram_size) = if flags6._has_battery_backed_prg_ram {
+            (0, prg_ram_size)
+        } else {
+            (prg_ram_size, 0)
+        };
+
+        let chr_ram_size = if chr_rom_size == 0 { CHR_ROM_CHUNK_SIZE } else { 0 };
+
+        let console_type = if flags7._vs_unisystem {
+            ConsoleType::VsSystem { ppu_type: 0, hardware_type: 0 }
+        } else if flags7._playchoice_10 {
+            ConsoleType::Playchoice10
+        } else {
+            ConsoleType::Nes
+        };
+
+        let region = if bit_is_set(0, buffer[9]) { Region::Pal } else { Region::Ntsc };
 
         Self {
-            //file_id: buffer[0..4],
-            num_prg_rom_chunks,
-            num_chr_rom_chunks,
-            flags6: Flags6::parse(buffer[6]),
-            flags7: Flags7::parse(buffer[7]),
+            num_prg_rom_chunks: 0,
+            num_chr_rom_chunks: 0,
+            flags6,
+            flags7,
+            mapper_number,
+            submapper_number: 0,
+            prg_rom_size,
+            chr_rom_size,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size: 0,
+            console_type,
+            region,
+            num_misc_roms: 0,
+            default_expansion_device: 0,
         }
     }
+
+    ///
+    /// Parse the remainder of a NES 2.0 header.
+    /// See: https://www.nesdev.org/wiki/NES_2.0
+    ///
+    fn parse_ines2(buffer: &[u8], flags6: Flags6, flags7: Flags7) -> Result<Self, RomError> {
+        let mapper_number = (buffer[8] as u16 & 0x0F) << 8
+            | (flags7.mapper_upper_nybble | flags6.mapper_lower_nybble) as u16;
+
+        let prg_rom_size = parse_rom_size(buffer[PRG_ROM_CHUNKS_OFFSET], buffer[9] & 0x0F, PRG_ROM_CHUNK_SIZE)
+            .ok_or(RomError::InvalidSize { section: "PRG ROM" })?;
+        let chr_rom_size = parse_rom_size(buffer[CHR_ROM_CHUNKS_OFFSET], buffer[9] >> 4, CHR_ROM_CHUNK_SIZE)
+            .ok_or(RomError::InvalidSize { section: "CHR ROM" })?;
+
+        let console_type = match buffer[7] & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem {
+                ppu_type: buffer[13] & 0x0F,
+                hardware_type: buffer[13] >> 4,
+            },
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(buffer[13] & 0x0F),
+        };
+
+        let region = match buffer[12] & 0x03 {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Multi,
+            _ => Region::Dendy,
+        };
+
+        Ok(Self {
+            num_prg_rom_chunks: 0,
+            num_chr_rom_chunks: 0,
+            flags6,
+            flags7,
+            mapper_number,
+            submapper_number: buffer[8] >> 4,
+            prg_rom_size,
+            chr_rom_size,
+            prg_ram_size: parse_ram_size(buffer[10] & 0x0F),
+            prg_nvram_size: parse_ram_size(buffer[10] >> 4),
+            chr_ram_size: parse_ram_size(buffer[11] & 0x0F),
+            chr_nvram_size: parse_ram_size(buffer[11] >> 4),
+            console_type,
+            region,
+            num_misc_roms: buffer[14] & 0x03,
+            default_expansion_device: buffer[15] & 0x3F,
+        })
+    }
+}
+
+///
+/// NES 2.0 PRG/CHR ROM size in bytes. If the MSB nybble is $F the size is
+/// in exponent-multiplier notation (LSB = EEEE EEMM, size = 2^E * (MM*2+1)),
+/// otherwise it is the 12-bit number of units of the given size. None if
+/// the size overflows a usize.
+///
+fn parse_rom_size(lsb: u8, msb_nybble: u8, unit_size: usize) -> Option<usize> {
+    if msb_nybble == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+
+        2usize.checked_pow(exponent)?.checked_mul(multiplier)
+    } else {
+        Some(((msb_nybble as usize) << 8 | lsb as usize) * unit_size)
+    }
+}
+
+/// Error if fewer than the expected number of bytes are available for the
+/// given section of the file.
+fn check_size(section: &'static str, expected: usize, actual: usize) -> Result<(), RomError> {
+    if actual < expected {
+        Err(RomError::Truncated { section, expected, actual })
+    } else {
+        Ok(())
+    }
+}
+
+/// NES 2.0 RAM size in bytes from a shift count (64 << shift, 0 = none).
+fn parse_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
 }
 
 ///
 /// Parses PRG rom buffer into a vec of PRG rom banks. Buffer
 /// should be just the slice that contains the PRG rom data.
+/// A partial last bank (possible with NES 2.0 exponent sizes)
+/// is padded with zeros.
 /// 
-fn parse_prg_rom(buffer: &[u8], num_prg_rom_chunks: usize) -> Vec<[u8; PRG_ROM_CHUNK_SIZE]> {
+fn parse_prg_rom(buffer: &[u8]) -> Vec<[u8; PRG_ROM_CHUNK_SIZE]> {
     let mut prg_rom_banks = Vec::new();
 
-    for i in 0..num_prg_rom_chunks {
-        let bank_start = i * PRG_ROM_CHUNK_SIZE;
-        let bank_end = bank_start + PRG_ROM_CHUNK_SIZE;
-
+    for chunk in buffer.chunks(PRG_ROM_CHUNK_SIZE) {
         let mut bank = [0; PRG_ROM_CHUNK_SIZE];
-        bank.copy_from_slice(&buffer[bank_start..bank_end]);
+        bank[..chunk.len()].copy_from_slice(chunk);
 
         prg_rom_banks.push(bank);
     }
@@ -160,16 +484,14 @@ fn parse_prg_rom(buffer: &[u8], num_prg_rom_chunks: usize) -> Vec<[u8; PRG_ROM_C
 ///
 /// Parses CHR rom buffer into a vec of CHR rom banks. Buffer
 /// should be just the slice that contains the CHR rom data.
+/// A partial last bank is padded with zeros.
 /// 
-fn parse_chr_rom(buffer: &[u8], num_chr_rom_chunks: usize) -> Vec<[u8; CHR_ROM_CHUNK_SIZE]> {
+fn parse_chr_rom(buffer: &[u8]) -> Vec<[u8; CHR_ROM_CHUNK_SIZE]> {
     let mut chr_rom_banks = Vec::new();
 
-    for i in 0..num_chr_rom_chunks {
-        let bank_start = i * CHR_ROM_CHUNK_SIZE;
-        let bank_end = bank_start + CHR_ROM_CHUNK_SIZE;
-
+    for chunk in buffer.chunks(CHR_ROM_CHUNK_SIZE) {
         let mut bank = [0; CHR_ROM_CHUNK_SIZE];
-        bank.copy_from_slice(&buffer[bank_start..bank_end]);
+        bank[..chunk.len()].copy_from_slice(chunk);
 
         chr_rom_banks.push(bank);
     }
@@ -178,50 +500,121 @@ fn parse_chr_rom(buffer: &[u8], num_chr_rom_chunks: usize) -> Vec<[u8; CHR_ROM_C
 }
 
 impl InesRom {
-    pub fn from_buffer(rom_name: String, buffer: &[u8]) -> Self {
-        let header = InesHeader::parse(buffer);
+    pub fn from_buffer(rom_name: String, buffer: &[u8]) -> Result<Self, RomError> {
+        let raw_header = InesHeader::parse(buffer)?;
+        let mut header = raw_header.clone();
 
-        let trainer_bytes: usize = if header.flags6.has_trainer { 512 } else { 0 };
+        let trainer_bytes: usize = if header.flags6.has_trainer { TRAINER_SIZE } else { 0 };
 
         let prg_rom_offset = HEADER_SIZE + trainer_bytes;
-        let prg_rom_size = PRG_ROM_CHUNK_SIZE * header.num_prg_rom_chunks;
+        let prg_rom_size = header.prg_rom_size;
 
-        let chr_rom_offset = prg_rom_offset + prg_rom_size;
-        let chr_rom_size = CHR_ROM_CHUNK_SIZE * header.num_chr_rom_chunks;
+        check_size("trainer", trainer_bytes, buffer.len() - HEADER_SIZE)?;
+        check_size("PRG ROM", prg_rom_size, buffer.len() - prg_rom_offset)?;
 
-        let prg_rom = parse_prg_rom(
-            &buffer[prg_rom_offset..prg_rom_offset + prg_rom_size],
-            header.num_prg_rom_chunks);
+        let chr_rom_offset = prg_rom_offset.checked_add(prg_rom_size)
+            .ok_or(RomError::InvalidSize { section: "PRG ROM" })?;
+        let chr_rom_size = header.chr_rom_size;
 
-        let chr_rom = parse_chr_rom(
-            &buffer[chr_rom_offset..chr_rom_offset + chr_rom_size],
-            header.num_chr_rom_chunks);
+        check_size("CHR ROM", chr_rom_size, buffer.len() - chr_rom_offset)?;
 
-        Self {
+        let trainer = match header.flags6.has_trainer {
+            true => Some(buffer[HEADER_SIZE..prg_rom_offset].try_into().unwrap()),
+            false => None,
+        };
+
+        let prg_rom_data = &buffer[prg_rom_offset..prg_rom_offset + prg_rom_size];
+        let chr_rom_data = &buffer[chr_rom_offset..chr_rom_offset + chr_rom_size];
+
+        // Known dumps get their header corrected
+        let db_entry = romdb::lookup(prg_rom_data, chr_rom_data);
+
+        if let Some(entry) = db_entry {
+            info!("rom database match: {}", entry.name);
+            entry.apply(&mut header);
+        }
+
+        let prg_rom = parse_prg_rom(prg_rom_data);
+        let chr_rom = parse_chr_rom(chr_rom_data);
+
+        Ok(Self {
             rom_name,
             header,
+            raw_header,
+            db_entry,
+            trainer,
             prg_rom,
             chr_rom,
-        }
+        })
+    }
+
+    /// Load a rom file, which may be inside a zip or gzip archive. For zip
+    /// archives the first .nes file is loaded.
+    pub fn from_path(path: &Path) -> Result<Self, RomError> {
+        Self::from_path_with_entry(path, None)
+    }
+
+    ///
+    /// Load a rom file, which may be inside a zip or gzip archive. For zip
+    /// archives the named entry is loaded, or the first .nes file if entry is
+    /// None. Archived roms are named after the file inside the archive, so
+    /// save files stay the same however the rom is stored. A patch beside the
+    /// rom is applied if there is one (see from_path_with_patch).
+    ///
+    pub fn from_path_with_entry(path: &Path, entry: Option<&str>) -> Result<Self, RomError> {
+        Self::from_path_with_patch(path, entry, None)
     }
 
-    pub fn from_path(path: &Path) -> Self {
-        let file_data = fs::read(path).expect("could not read rom file");
+    ///
+    /// Load a rom file as from_path_with_entry, applying an IPS, UPS or BPS
+    /// patch to it. If no patch is given, a <rom>.ips, <rom>.ups or
+    /// <rom>.bps file beside the rom is used if there is one. Patched roms
+    /// are named after the patched content, so they keep separate save files
+    /// from the unpatched rom.
+    ///
+    pub fn from_path_with_patch(path: &Path, entry: Option<&str>, patch: Option<&Path>) -> Result<Self, RomError> {
+        let file_data = fs::read(path)?;
+
+        let file_name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => path.to_string_lossy().into_owned(),
+        };
+
+        let rom_file = archive::unpack(&file_name, file_data, entry)?;
+
+        let patch_path = match patch {
+            Some(patch) => Some(patch.to_path_buf()),
+            None => find_patch(path),
+        };
+
+        let Some(patch_path) = patch_path else {
+            return Self::from_buffer(rom_file.name, &rom_file.data);
+        };
+
+        info!("applying patch {}", patch_path.display());
 
-        let rom_name = path.file_name().unwrap();
-        let rom_name = rom_name.to_str().unwrap();
-        let rom_name = String::from_str(rom_name).unwrap();
-        Self::from_buffer(rom_name, &file_data)
+        let patched = patch::apply(&fs::read(&patch_path)?, &rom_file.data)?;
+        let rom_name = format!("{}.{:08x}", rom_file.name, crc32fast::hash(&patched));
+
+        Self::from_buffer(rom_name, &patched)
     }
 
     pub fn get_mapper_number(&self) -> u16 {
-        let mapper: u8 = 
-            self.header.flags7.mapper_upper_nybble | self.header.flags6.mapper_lower_nybble;
+        self.header.mapper_number
+    }
 
-        mapper as u16
+    pub fn get_submapper_number(&self) -> u8 {
+        self.header.submapper_number
     }
 }
 
+/// First patch file beside a rom, named after the rom with a patch extension.
+fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
 /*
 fn bit_is_set(bit: u8, input: u8) -> bool {
     (input & (1 << bit)) != 0
@@ -236,9 +629,205 @@ mod tests {
     #[test]
     fn it_works() {
         let path_buf = PathBuf::from(r"./nestest/nestest.nes");
-        let nesrom = InesRom::from_path(&path_buf.as_path());
+        let nesrom = InesRom::from_path(&path_buf.as_path()).unwrap();
         println!("{:#?}", nesrom.header);
         println!("prg_rom_size: {}", nesrom.prg_rom.len());
         println!("chr_rom_size: {}", nesrom.chr_rom.len());
     }
+
+    /// Build a 16 byte header with the given bytes 4-15.
+    fn header_bytes(rest: [u8; 12]) -> Vec<u8> {
+        let mut buffer = NES_FILE_ID.to_vec();
+        buffer.extend_from_slice(&rest);
+        buffer
+    }
+
+    #[test]
+    fn test_ines_header_defaults() {
+        // 2 x 16KB PRG, no CHR, battery, mapper 1
+        let buffer = header_bytes([2, 0, 0x12, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let header = InesHeader::parse(&buffer).unwrap();
+
+        assert!(header.flags7.file_format == FileFormat::INES);
+        assert!(header.mapper_number == 1);
+        assert!(header.prg_rom_size == 32768);
+        assert!(header.num_prg_rom_chunks == 2);
+        assert!(header.chr_ram_size == 8192);
+        assert!(header.prg_ram_size == 0);
+        assert!(header.prg_nvram_size == 8192);
+        assert!(header.region == Region::Ntsc);
+    }
+
+    #[test]
+    fn test_ines_header_ignores_garbage_in_padding() {
+        let mut buffer = header_bytes([1, 1, 0x10, 0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        buffer[10..16].copy_from_slice(b"kDude!");
+
+        let header = InesHeader::parse(&buffer).unwrap();
+        assert!(header.mapper_number == 1);
+    }
+
+    #[test]
+    fn test_ines2_header() {
+        // Mapper 0x123 submapper 4, 3 x 16KB PRG, 2 x 8KB CHR, 8KB PRG-NVRAM,
+        // 2KB PRG-RAM, PAL, Vs. System, expansion device 1
+        let buffer = header_bytes([3, 2, 0x31, 0x29, 0x41, 0x00, 0x75, 0x00, 0x01, 0x12, 0x00, 0x01]);
+        let header = InesHeader::parse(&buffer).unwrap();
+
+        assert!(header.flags7.file_format == FileFormat::INES2);
+        assert!(header.mapper_number == 0x123);
+        assert!(header.submapper_number == 4);
+        assert!(header.prg_rom_size == 3 * PRG_ROM_CHUNK_SIZE);
+        assert!(header.chr_rom_size == 2 * CHR_ROM_CHUNK_SIZE);
+        assert!(header.prg_ram_size == 2048);
+        assert!(header.prg_nvram_size == 8192);
+        assert!(header.chr_ram_size == 0);
+        assert!(header.region == Region::Pal);
+        assert!(header.console_type == ConsoleType::VsSystem { ppu_type: 2, hardware_type: 1 });
+        assert!(header.default_expansion_device == 1);
+    }
+
+    #[test]
+    fn test_ines2_exponent_rom_size() {
+        // PRG size 2^13 * 3 = 24KB, CHR size in 8KB units
+        let buffer = header_bytes([(13 << 2) | 1, 1, 0x00, 0x08, 0x00, 0x0F, 0, 0x07, 0, 0, 0, 0]);
+        let header = InesHeader::parse(&buffer).unwrap();
+
+        assert!(header.prg_rom_size == 24576);
+        assert!(header.num_prg_rom_chunks == 2);
+        assert!(header.chr_rom_size == 8192);
+        assert!(header.chr_ram_size == 8192);
+    }
+
+    #[test]
+    fn test_ines2_garbage_exponent_rom_size() {
+        // PRG size 2^63 * 7 overflows
+        let buffer = header_bytes([0xFF, 0, 0x00, 0x08, 0x00, 0x0F, 0, 0, 0, 0, 0, 0]);
+        let result = InesRom::from_buffer("bad.nes".to_string(), &buffer);
+
+        assert!(matches!(result, Err(RomError::InvalidSize { section: "PRG ROM" })));
+
+        // CHR size 2^63 * 7 overflows
+        let buffer = header_bytes([1, 0xFF, 0x00, 0x08, 0x00, 0xF0, 0, 0, 0, 0, 0, 0]);
+        let result = InesRom::from_buffer("bad.nes".to_string(), &buffer);
+
+        assert!(matches!(result, Err(RomError::InvalidSize { section: "CHR ROM" })));
+
+        // PRG size 2^63 fits in a usize, but not in the file
+        let buffer = header_bytes([0xFC, 0, 0x00, 0x08, 0x00, 0x0F, 0, 0, 0, 0, 0, 0]);
+        let result = InesRom::from_buffer("bad.nes".to_string(), &buffer);
+
+        assert!(matches!(result, Err(RomError::Truncated { section: "PRG ROM", .. })));
+    }
+
+    #[test]
+    fn test_invalid_magic() {
+        let buffer = b"NOT A ROM FILE AT ALL".to_vec();
+        let result = InesRom::from_buffer("bad.nes".to_string(), &buffer);
+
+        assert!(matches!(result, Err(RomError::InvalidHeader)));
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let result = InesRom::from_buffer("bad.nes".to_string(), &NES_FILE_ID);
+
+        assert!(matches!(result, Err(RomError::Truncated { section: "header", expected: 16, actual: 4 })));
+    }
+
+    #[test]
+    fn test_truncated_prg_rom() {
+        let mut buffer = header_bytes([2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        buffer.resize(HEADER_SIZE + 20000, 0);
+
+        let result = InesRom::from_buffer("bad.nes".to_string(), &buffer);
+
+        match result {
+            Err(e @ RomError::Truncated { section: "PRG ROM", expected: 32768, actual: 20000 }) => {
+                assert!(e.to_string() == "rom file is truncated: PRG ROM needs 32768 bytes, but only 20000 are present");
+            },
+            _ => panic!("expected truncated PRG ROM error"),
+        }
+    }
+
+    #[test]
+    fn test_truncated_chr_rom() {
+        let mut buffer = header_bytes([1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        buffer.resize(HEADER_SIZE + PRG_ROM_CHUNK_SIZE + 100, 0);
+
+        let result = InesRom::from_buffer("bad.nes".to_string(), &buffer);
+
+        assert!(matches!(result, Err(RomError::Truncated { section: "CHR ROM", expected: 8192, actual: 100 })));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let result = InesRom::from_path(Path::new("./nestest/does-not-exist.nes"));
+
+        assert!(matches!(result, Err(RomError::Io(_))));
+    }
+
+    #[test]
+    fn test_rom_database_corrects_header() {
+        let mut buffer = fs::read("./nestest/nestest.nes").unwrap();
+
+        // Dirty header: mapper 64, vertical mirroring, battery
+        buffer[6] |= 0x03;
+        buffer[7] = 0x40;
+
+        let rom = InesRom::from_buffer("nestest.nes".to_string(), &buffer).unwrap();
+
+        assert!(rom.db_entry.unwrap().name == "nestest");
+        assert!(rom.raw_header.mapper_number == 64);
+        assert!(rom.get_mapper_number() == 0);
+        assert!(matches!(rom.header.flags6.mirroring, MirroringType::Horizontal));
+        assert!(!rom.header.flags6._has_battery_backed_prg_ram);
+    }
+
+    #[test]
+    fn test_patch_beside_rom() {
+        let dir = std::env::temp_dir().join(format!("retrobrite-patch-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let rom_path = dir.join("nestest.nes");
+        fs::copy("./nestest/nestest.nes", &rom_path).unwrap();
+
+        // Set the first PRG ROM byte to 0xEA
+        let mut ips = b"PATCH".to_vec();
+        ips.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x01, 0xEA]);
+        ips.extend_from_slice(b"EOF");
+        fs::write(dir.join("nestest.ips"), &ips).unwrap();
+
+        let not_a_patch = InesRom::from_path_with_patch(&rom_path, None, Some(Path::new("./nestest/nestest.nes")));
+        assert!(matches!(not_a_patch, Err(RomError::Patch(PatchError::UnknownFormat))));
+
+        let rom = InesRom::from_path(&rom_path).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(rom.prg_rom[0][0] == 0xEA);
+        assert!(rom.rom_name.starts_with("nestest.nes."));
+        assert!(rom.db_entry.is_none());
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut buffer = header_bytes([1, 0, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        buffer.extend_from_slice(&[0xAA; TRAINER_SIZE]);
+        buffer.extend_from_slice(&[0x55; PRG_ROM_CHUNK_SIZE]);
+
+        let rom = InesRom::from_buffer("trainer.nes".to_string(), &buffer).unwrap();
+
+        assert!(rom.trainer.unwrap() == [0xAA; TRAINER_SIZE]);
+        assert!(rom.prg_rom[0] == [0x55; PRG_ROM_CHUNK_SIZE]);
+
+        let rom = InesRom::from_buffer("trainer.nes".to_string(), &buffer[..HEADER_SIZE + 100]);
+        assert!(matches!(rom, Err(RomError::Truncated { section: "trainer", expected: 512, actual: 100 })));
+
+        // Without the trainer flag, the same data has no trainer
+        buffer[6] = 0;
+        let rom = InesRom::from_buffer("no-trainer.nes".to_string(), &buffer).unwrap();
+        assert!(rom.trainer.is_none());
+        assert!(rom.prg_rom[0][..TRAINER_SIZE] == [0xAA; TRAINER_SIZE]);
+    }
 }
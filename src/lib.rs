@@ -0,0 +1,24 @@
+#[macro_use]
+extern crate log;
+
+pub mod utils;
+mod cpu;
+mod ppu;
+mod apu;
+mod audio;
+mod mem;
+mod wram;
+mod state;
+mod palette;
+mod mappers;
+mod archive;
+
+pub mod ines;
+pub mod romdb;
+pub mod patch;
+pub mod savestate;
+pub mod rewind;
+pub mod timing;
+
+pub mod nes;
+pub use nes::Nes;
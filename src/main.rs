@@ -1,51 +1,47 @@
 #[macro_use]
 extern crate log;
 extern crate clap;
+#[cfg(feature = "sdl2")]
 extern crate sdl2;
 
-use std::cell::RefCell;
 use std::fs;
-use std::rc::Rc;
+use std::io;
+use std::path::Path;
 use std::time::{Instant, Duration};
 use std::thread::sleep;
 use std::path::PathBuf;
 use clap::Parser;
 
-mod utils;
-mod cpu;
-use cpu::Cpu;
+use retrobrite::Nes;
+use retrobrite::nes::{FRAME_WIDTH, FRAME_HEIGHT};
+use retrobrite::ines::InesRom;
+use retrobrite::rewind::RewindBuffer;
+use retrobrite::utils;
 
-mod ppu;
-use ppu::Ppu;
-
-mod mem;
-mod wram;
-
-mod state;
-use state::NesState;
-
-mod ines;
-use ines::InesRom;
-
-use crate::gui::Gui;
-use crate::mem::{Memory, PpuMemory};
-mod mappers;
+use crate::frontend::{Frontend, GuiAction, NullFrontend};
+mod frontend;
 
+#[cfg(feature = "sdl2")]
 mod gui;
 
-const MASTER_CLOCK_HZ: u64 = 21_441_960;
-const CLOCK_DIVISOR: u64 = 12;
-const CPU_FREQ: u64 = MASTER_CLOCK_HZ / CLOCK_DIVISOR;
-const CPU_CYCLES_PER_FRAME: u64 = CPU_FREQ / 60;
-const NS_PER_CYCLE: u64 = (1.0 / CPU_FREQ as f64 * 1e9) as u64;
-const _NS_PER_FRAME: u64 = CPU_CYCLES_PER_FRAME * NS_PER_CYCLE;
+/// Emulation speeds cycled through by the slow motion hotkey.
+const SLOW_MOTION_SPEEDS: [f64; 3] = [1.0, 0.5, 0.25];
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Rom file to load (ines format only).
+    /// Rom file to load (ines format, optionally in a zip or gzip archive).
     rom: Option<PathBuf>,
 
+    /// File to load from a zip archive (defaults to the first .nes file).
+    #[arg(long)]
+    rom_entry: Option<String>,
+
+    /// IPS, UPS or BPS patch to apply to the rom (defaults to a .ips, .ups or
+    /// .bps file named after the rom, if there is one).
+    #[arg(long)]
+    patch: Option<PathBuf>,
+
     /// Start program counter at given value (for debug/testing).
     #[arg(long)]
     pc: Option<u16>,
@@ -66,6 +62,36 @@ struct Cli {
     /// Enable trace logging of CPU execution.
     #[arg(long)]
     trace_cpu: bool,
+
+    /// Memory to use for rewind states, in megabytes (0 disables rewind).
+    #[arg(long, default_value_t = 64)]
+    rewind_buffer_mb: usize,
+
+    /// Capture a rewind state every this many frames. Rewinding plays back
+    /// one captured state per frame.
+    #[arg(long, default_value_t = 2)]
+    rewind_interval: u32,
+
+    /// Speed multiplier while fast-forward is held (0 runs uncapped).
+    #[arg(long, default_value_t = 0)]
+    fast_forward_speed: u32,
+
+    /// Run without a window, audio or input, as fast as possible. Requires
+    /// --frames or --cycles. Implied when built without the sdl2 feature.
+    #[arg(long)]
+    headless: bool,
+
+    /// Number of frames to run before exiting.
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Write the final frame to the given file on exit (binary PPM format).
+    #[arg(long)]
+    dump_frame: Option<PathBuf>,
+
+    /// Write the contents of CPU RAM ($0000-$07FF) to the given file on exit.
+    #[arg(long)]
+    dump_ram: Option<PathBuf>,
 }
 
 fn ensure_retrobrite_data_dir_exists() {
@@ -80,36 +106,84 @@ fn ensure_retrobrite_data_dir_exists() {
     }
 }
 
+/// Create the SDL frontend, or the null frontend if running headless.
+fn init_frontend(headless: bool) -> Box<dyn Frontend> {
+    if headless {
+        return Box::new(NullFrontend);
+    }
+
+    #[cfg(feature = "sdl2")]
+    return Box::new(gui::Gui::init().unwrap());
+
+    #[cfg(not(feature = "sdl2"))]
+    unreachable!("gui requested without sdl2 support");
+}
+
+/// Write a frame output by Nes::frame_buffer to a binary PPM file.
+fn dump_frame(path: &Path, frame: &[u8]) -> io::Result<()> {
+    let mut data = format!("P6\n{FRAME_WIDTH} {FRAME_HEIGHT}\n255\n").into_bytes();
+    data.extend_from_slice(frame);
+
+    fs::write(path, data)
+}
+
 fn main() {
     env_logger::init();
 
     let cli = Cli::parse();
 
     let rom_path = cli.rom.expect("No rom specified (try --help)");
-    let ines_file = InesRom::from_path(rom_path.as_path());
+
+    let rom_entry = cli.rom_entry.as_deref();
+    let patch = cli.patch.as_deref();
+
+    let ines_file = match InesRom::from_path_with_patch(rom_path.as_path(), rom_entry, patch) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("failed to load {}: {e}", rom_path.display());
+            std::process::exit(1);
+        },
+    };
 
     if cli.rom_info {
         println!("Rom file: {}", rom_path.display());
+
+        match ines_file.db_entry {
+            Some(entry) => println!("Rom database match: {}", entry.name),
+            None => println!("Rom database match: none"),
+        }
+
         println!("Mapper: {}", ines_file.get_mapper_number());
-        println!("{:#?}", ines_file.header);
+        println!("Submapper: {}", ines_file.get_submapper_number());
+        println!("Raw header: {:#?}", ines_file.raw_header);
+
+        if ines_file.db_entry.is_some() {
+            println!("Corrected header: {:#?}", ines_file.header);
+        }
         println!("sizeof chr-rom: {}", ines_file.chr_rom.len());
         std::process::exit(0);
     }
 
-    ensure_retrobrite_data_dir_exists();
+    // Without SDL there is no other way to run
+    let headless = cli.headless || cfg!(not(feature = "sdl2"));
 
-    // Init mapper and load rom
-    let mut mapper = mappers::get_mapper(
-        ines_file.get_mapper_number(), Memory::new_cpu(), PpuMemory::new());
+    if headless && cli.frames.is_none() && cli.cycles.is_none() {
+        println!("Running headless requires --frames or --cycles");
+        std::process::exit(1);
+    }
 
-    mapper.print_info();
-    mapper.load_rom(&ines_file);
+    ensure_retrobrite_data_dir_exists();
 
-    // Init state object
-    let ppu = Rc::new(RefCell::new(Ppu::new()));
-    let mut state = NesState::new(mapper, Rc::clone(&ppu));
+    let mut nes = match Nes::from_rom(ines_file) {
+        Ok(nes) => nes,
+        Err(e) => {
+            eprintln!("failed to load {}: {e}", rom_path.display());
+            std::process::exit(1);
+        },
+    };
+    nes.set_trace_cpu(cli.trace_cpu);
 
-    let mut cpu = Cpu::new(&mut state, cli.trace_cpu);
+    let max_frames = cli.frames.unwrap_or(0);
 
     let max_cycles = if let Some(cycles_to_run) = cli.cycles.as_ref() {
         *cycles_to_run
@@ -118,7 +192,7 @@ fn main() {
     };
 
     if let Some(pc) = cli.pc.as_ref() {
-        cpu.set_program_counter(*pc);
+        nes.set_program_counter(*pc);
     }
 
     let cycle_batch = if let Some(cycle_batch_option) = cli.cycle_batch.as_ref() {
@@ -126,81 +200,173 @@ fn main() {
     } else {
         1
     };
-    let mut cycle = 7;
+    nes.set_cycle_batch(cycle_batch);
+
     let mut cycles_this_second = 0;
     let mut last_report = Instant::now();
 
     let mut frame_start = Instant::now();
-    let frame_duration = Duration::from_nanos(CPU_CYCLES_PER_FRAME * NS_PER_CYCLE);
-    let mut frame_count: u64 = 0;
+    let timing = nes.timing();
+    let frame_duration = timing.frame_duration();
     let mut fps: u32 = 0;
 
-    info!("CPU FREQ: {}", CPU_FREQ);
-    info!("ns per cycle: {}", NS_PER_CYCLE);
+    info!("region: {:?}", timing.region);
+    info!("CPU FREQ: {}", timing.cpu_freq);
+    info!("frame duration: {:?}", frame_duration);
     info!("cycle_batch: {}", cycle_batch);
-    info!("reset vector: {:04X}", state.cpu_mem_read_word(0xFFFC));
 
-    let mut gui = Gui::init().unwrap();
+    let mut frontend = init_frontend(headless);
+    nes.set_audio_sample_rate(frontend.audio_sample_rate());
+
+    // Rewind has no use without input
+    let rewind_enabled = cli.rewind_buffer_mb > 0 && !headless;
+
+    let mut rewind_buffer = RewindBuffer::new(cli.rewind_buffer_mb * 1024 * 1024, cli.rewind_interval);
+    let mut rewinding = false;
+
+    // While paused the main loop still runs once per frame to display the
+    // last frame and handle events, but the NES is only run when stepped.
+    let mut paused = false;
+
+    let mut fast_forwarding = false;
+
+    // While fast-forwarding, only render about one frame per real frame
+    let mut frame_skip = true;
+    let mut last_render = Instant::now();
+
+    let mut slow_motion_index = 0;
 
     'mainloop: loop {
 
-        cycle += cycle_batch;
+        // Stop once max_cycles or max_frames is reached if necessary
+        if max_cycles > 0 && nes.cpu_cycle_count() >= max_cycles {
+            break 'mainloop;
+        }
 
-        // Clip cycle to max_cycles if necessary
-        if max_cycles > 0 && cycle > max_cycles {
+        if max_frames > 0 && nes.frame_count() >= max_frames {
             break 'mainloop;
         }
 
-        let cpu_cyles_used = cpu.cycle_to(&mut state, cycle);
+        if !paused {
+            let cycle_before_step = nes.cpu_cycle_count();
+            let frame_completed = nes.step();
+            cycles_this_second += nes.cpu_cycle_count() - cycle_before_step;
+
+            if !frame_completed {
+                continue;
+            }
+        }
+
+        // Use the end of each frame as our "sleep point" to keep timing at 60fps
+        if !(fast_forwarding && frame_skip && last_render.elapsed() < frame_duration) {
+            frontend.render_frame(nes.frame_buffer());
+            last_render = Instant::now();
+        }
+
+        // Audio is muted while rewinding, paused or fast-forwarding
+        let samples = nes.take_audio_samples();
+
+        if !rewinding && !paused && !fast_forwarding {
+            let buffer_fill = frontend.queue_audio(&samples);
+            nes.adjust_audio_rate(buffer_fill);
+        }
+
+        fps += 1;
+
+        let gui_actions = frontend.process_events(&mut nes);
+
+        fast_forwarding = gui_actions.iter().any(|action| matches!(action, GuiAction::FastForward));
+
+        let target_frame_duration = if fast_forwarding {
+            if cli.fast_forward_speed == 0 {
+                Duration::ZERO
+            } else {
+                frame_duration / cli.fast_forward_speed
+            }
+        } else {
+            frame_duration.div_f64(SLOW_MOTION_SPEEDS[slow_motion_index])
+        };
+
+        let frame_time_used = Instant::now() - frame_start;
+
+        if !headless && frame_time_used < target_frame_duration {
+          let sleep_time = target_frame_duration - frame_time_used;
+          sleep(sleep_time);
+        }
+
+        frame_start = Instant::now();
 
-        let mut ppu_ref = ppu.borrow_mut();
+        rewinding = gui_actions.iter().any(|action| matches!(action, GuiAction::Rewind));
 
-        for _ in 0..cpu_cyles_used*3 {
-            let ppu_result = ppu_ref.cycle(&mut state);
+        if !rewinding && !paused && rewind_enabled && rewind_buffer.frame_completed() {
+            rewind_buffer.push(nes.save_state());
+        }
 
-            match ppu_result {
-                ppu::PpuCycleResult::Idle => (),
-                ppu::PpuCycleResult::Pixel { scanline, x, color } => {
-                    gui.set_pixel(x, scanline, color);
+        for action in gui_actions {
+            match action {
+                GuiAction::Quit => break 'mainloop,
+                GuiAction::SaveState(slot) => {
+                    match nes.save_state_to_slot(slot) {
+                        Ok(path) => println!("saved state to {}", path.display()),
+                        Err(e) => println!("failed to save state to slot {slot}: {e}"),
+                    }
                 },
-                ppu::PpuCycleResult::HBlank => (),
-                ppu::PpuCycleResult::PostRenderLine => (),
-                ppu::PpuCycleResult::VBlankLine { trigger_nmi, scanline} => {
-                    if trigger_nmi {
-                        cpu.set_nmi_flag();
-                        trace!("VBlank: nmi triggered at scanline {scanline}");
+                GuiAction::LoadState(slot) => {
+                    match nes.load_state_from_slot(slot) {
+                        Ok(path) => println!("loaded state from {}", path.display()),
+                        Err(e) => println!("failed to load state from slot {slot}: {e}"),
                     }
-                }
-                ppu::PpuCycleResult::PreRenderLine { scanline_cycle } => {
-                    // Use prerender line scanline cycle 2 as our "sleep point" to
-                    // keep timing at 60fps
-                    if scanline_cycle == 2 {
-                        gui.render_frame();
-                        frame_count += 1;
-                        fps += 1;
-
-                        if gui.process_events(&mut state) == false {
-                            break 'mainloop;
-                        }
-
-                        let frame_time_used = Instant::now() - frame_start;
-            
-                        if frame_time_used < frame_duration {
-                          let sleep_time = frame_duration - frame_time_used;
-                          sleep(sleep_time);
-                        }
-
-                        frame_start = Instant::now();
+                },
+                GuiAction::Rewind => {
+                    if let Some(data) = rewind_buffer.pop() {
+                        nes.load_state(&data).expect("failed to load rewind state");
                     }
                 },
+                GuiAction::Reset => {
+                    nes.reset();
+                    println!("reset");
+                },
+                GuiAction::PowerCycle => {
+                    match nes.power_cycle() {
+                        Ok(()) => println!("power cycled"),
+                        Err(e) => error!("power cycle failed: {}", e),
+                    }
+                },
+                GuiAction::FastForward => (),
+                GuiAction::CycleSlowMotion => {
+                    slow_motion_index = (slow_motion_index + 1) % SLOW_MOTION_SPEEDS.len();
+                    let speed = SLOW_MOTION_SPEEDS[slow_motion_index];
+
+                    // Stretch the audio to match, lowering its pitch
+                    let sample_rate = frontend.audio_sample_rate() as f64 / speed;
+                    nes.set_audio_sample_rate(sample_rate as u32);
+
+                    println!("speed: {}%", speed * 100.0);
+                },
+                GuiAction::ToggleFrameSkip => {
+                    frame_skip = !frame_skip;
+                    println!("fast-forward frame skip {}", if frame_skip { "on" } else { "off" });
+                },
+                GuiAction::TogglePause => {
+                    paused = !paused;
+                    println!("{}", if paused { "paused" } else { "resumed" });
+                },
+                GuiAction::FrameAdvance => {
+                    paused = true;
+                    nes.run_frame();
+                    println!("frame {}", nes.frame_count());
+                },
+                GuiAction::ScanlineAdvance => {
+                    paused = true;
+                    nes.run_scanline();
+                    println!("frame {}, scanline {}", nes.frame_count(), nes.scanline());
+                },
             }
         }
 
-        cycles_this_second += cpu_cyles_used;
-
         if last_report.elapsed().as_millis() >= 1000 {
             info!("elapsed time for 1s cycle: {}ms, cycles this second: {}, fps: {}, frame counter: {}",
-                  last_report.elapsed().as_millis(), cycles_this_second, fps, frame_count);
+                  last_report.elapsed().as_millis(), cycles_this_second, fps, nes.frame_count());
 
             last_report = Instant::now();
             cycles_this_second = 0;
@@ -208,6 +374,20 @@ fn main() {
         }
     }
 
-    // Call state shutdown to ensure any shutdown related tasks/bookkeeping are done.
-    state.shutdown();
+    if let Some(path) = cli.dump_frame.as_ref() {
+        match dump_frame(path, nes.frame_buffer()) {
+            Ok(_) => println!("wrote frame to {}", path.display()),
+            Err(e) => println!("failed to write frame to {}: {e}", path.display()),
+        }
+    }
+
+    if let Some(path) = cli.dump_ram.as_ref() {
+        match fs::write(path, nes.ram()) {
+            Ok(_) => println!("wrote RAM to {}", path.display()),
+            Err(e) => println!("failed to write RAM to {}: {e}", path.display()),
+        }
+    }
+
+    // Call shutdown to ensure any shutdown related tasks/bookkeeping are done.
+    nes.shutdown();
 }
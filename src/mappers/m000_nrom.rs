@@ -1,7 +1,8 @@
-use super::Mapper;
-use crate::ines::{InesRom, MirroringType};
+use super::{check_chr_ram, load_trainer, Mapper};
+use crate::ines::{InesRom, MirroringType, RomError};
 use crate::mem::{Memory, PpuMemory};
 use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
 
 pub struct NromMapper {
@@ -33,9 +34,11 @@ impl Mapper for NromMapper {
         self.number
     }
 
-    fn load_rom(&mut self, ines: &InesRom) {
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
         if ines.header.num_prg_rom_chunks < 1 || ines.header.num_prg_rom_chunks > 2 {
-            panic!("nrom: invalid number of prg rom chunks");
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "PRG ROM", size: ines.header.prg_rom_size,
+            });
         }
 
         self.cpu_mem.load(0x8000, &ines.prg_rom[0]);
@@ -46,6 +49,8 @@ impl Mapper for NromMapper {
             self.cpu_mem.load(0xC000, &ines.prg_rom[1]);
         }
 
+        load_trainer(ines, None, &mut self.cpu_mem);
+
         self.mirroring = match ines.header.flags6.mirroring {
             MirroringType::Horizontal => Mirroring::Horizontal,
             MirroringType::Vertical => Mirroring::Vertical,
@@ -54,13 +59,19 @@ impl Mapper for NromMapper {
         self.ppu_mem.set_mirroring(self.mirroring);
 
         match ines.header.num_chr_rom_chunks {
-            0 => self.chr_ram = true,
+            0 => {
+                check_chr_ram(self.name, ines)?;
+                self.chr_ram = true;
+            },
             1 => self.ppu_mem.load(0x0000, &ines.chr_rom[0]),
             _ => {
-                panic!("nrom: invalid number of chr rom chunks: {}", ines.header.num_chr_rom_chunks);
+                return Err(RomError::UnsupportedSize {
+                    mapper: self.name, section: "CHR ROM", size: ines.header.chr_rom_size,
+                });
             }
         }
 
+        Ok(())
     }
     
     fn cpu_read(&mut self, addr: u16) -> u8 {
@@ -94,4 +105,13 @@ impl Mapper for NromMapper {
         }
     }
 
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)
+    }
 }
@@ -1,8 +1,9 @@
 
-use super::Mapper;
-use crate::ines::{InesRom, PRG_ROM_CHUNK_SIZE};
+use super::{check_chr_ram, load_trainer, new_prg_ram, Mapper};
+use crate::ines::{InesRom, RomError, PRG_ROM_CHUNK_SIZE};
 use crate::mem::{Memory, PpuMemory};
 use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use crate::utils::bit_is_set;
 use crate::wram::WRam;
 
@@ -90,20 +91,24 @@ impl Mapper for Mmc1Mapper {
         self.number
     }
 
-    fn load_rom(&mut self, ines: &InesRom) {
-        if ines.header.flags6._has_battery_backed_prg_ram {
-            let cart_wram = WRam::new(&ines.rom_name);
-
-            self.wram = Some(cart_wram);
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        if ines.chr_rom.is_empty() {
+            check_chr_ram(self.name, ines)?;
         }
 
-        self.init_prg_banks(&ines);
-        self.init_chr_banks(&ines);
+        self.wram = new_prg_ram(ines);
+
+        load_trainer(ines, self.wram.as_mut(), &mut self.cpu_mem);
+
+        self.init_prg_banks(ines);
+        self.init_chr_banks(ines);
 
         self.cpu_mem.load(0x8000, &self.prg_rom_banks[0]);
         self.cpu_mem.load(0xC000, &self.prg_rom_banks[ines.header.num_prg_rom_chunks - 1]);
 
         // Assuming reset vector will load CHR, so not loading it here.
+
+        Ok(())
     }
     
     fn cpu_read(&mut self, addr: u16) -> u8 {
@@ -128,7 +133,7 @@ impl Mapper for Mmc1Mapper {
             },
             0x8000..=0xFFFF => {
                 match self.push_shift_reg(value) {
-                    RegisterStatus::Cleared => self.reset(),
+                    RegisterStatus::Cleared => self.reset_control_register(),
                     RegisterStatus::Ready => {
                         match addr {
                             0x8000..=0x9FFF => {
@@ -157,7 +162,7 @@ impl Mapper for Mmc1Mapper {
         /*
         if addr >= 0x8000 {
             match self.push_shift_reg(value) {
-                RegisterStatus::Cleared => self.reset(),
+                RegisterStatus::Cleared => self.reset_control_register(),
                 RegisterStatus::Ready => {
                     match addr {
                         0x8000..=0x9FFF => {
@@ -198,13 +203,69 @@ impl Mapper for Mmc1Mapper {
         self.ppu_mem.write(addr, value);
     }
 
+    fn reset(&mut self) {
+        self.reg_write_count = 0;
+        self.reset_control_register();
+    }
+
     fn shutdown(&mut self) {
-        match &self.wram {
-            Some(wram) => wram.write_to_file(),
-            None => (),
+        if let Some(wram) = &self.wram {
+            wram.write_to_file();
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+        self.mirroring.save_state(writer);
+
+        writer.write_u8(self.shift_register);
+        writer.write_u8(self.reg_write_count);
+
+        writer.write_u8(match self.prg_rom_bank_mode {
+            PrgRomBankMode::Bank8000_32KB => 0,
+            PrgRomBankMode::Bank8000Fixed => 1,
+            PrgRomBankMode::BankC000Fixed => 2,
+        });
+
+        writer.write_u8(match self.chr_rom_bank_mode {
+            ChrRomBankMode::Switch8KB => 0,
+            ChrRomBankMode::Switch4KB => 1,
+        });
+
+        if let Some(wram) = &self.wram {
+            wram.save_state(writer);
         }
     }
 
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        self.shift_register = reader.read_u8()?;
+        self.reg_write_count = reader.read_u8()?;
+
+        self.prg_rom_bank_mode = match reader.read_u8()? {
+            0 => PrgRomBankMode::Bank8000_32KB,
+            1 => PrgRomBankMode::Bank8000Fixed,
+            2 => PrgRomBankMode::BankC000Fixed,
+            _ => return Err(SaveStateError::InvalidData("mmc1 prg bank mode")),
+        };
+
+        self.chr_rom_bank_mode = match reader.read_u8()? {
+            0 => ChrRomBankMode::Switch8KB,
+            1 => ChrRomBankMode::Switch4KB,
+            _ => return Err(SaveStateError::InvalidData("mmc1 chr bank mode")),
+        };
+
+        if let Some(wram) = &mut self.wram {
+            wram.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+
 }
 
 impl Mmc1Mapper {
@@ -351,8 +412,10 @@ impl Mmc1Mapper {
         }
     }
 
+    /// Clear the shift register and switch to fixing the last PRG bank at
+    /// $C000, as done by writes with bit 7 set.
     // TODO: It's late, review this.
-    fn reset(&mut self) {
+    fn reset_control_register(&mut self) {
         self.shift_register = 0x0C;
         self.handle_control_register();
         self.shift_register = 0;
@@ -1,8 +1,9 @@
 
-use super::Mapper;
-use crate::ines::{InesRom, MirroringType, PRG_ROM_CHUNK_SIZE};
+use super::{check_chr_ram, load_trainer, Mapper};
+use crate::ines::{InesRom, RomError, MirroringType, PRG_ROM_CHUNK_SIZE};
 use crate::mem::{Memory, PpuMemory};
 use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
 
 pub struct UnromMapper {
@@ -34,13 +35,16 @@ impl Mapper for UnromMapper {
         self.number
     }
 
-    fn load_rom(&mut self, ines: &InesRom) {
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
         self.init_prg_banks(&ines);
 
         self.cpu_mem.load(0x8000, &self.prg_rom_banks[0]);
         self.cpu_mem.load(0xC000, &self.prg_rom_banks[ines.header.num_prg_rom_chunks - 1]);
 
+        load_trainer(ines, None, &mut self.cpu_mem);
+
         // UNROM have chr ram, so no need to load anything into ppu mem here
+        check_chr_ram(self.name, ines)?;
 
         self.mirroring = match ines.header.flags6.mirroring {
             MirroringType::Horizontal => Mirroring::Horizontal,
@@ -48,6 +52,8 @@ impl Mapper for UnromMapper {
         };
 
         self.ppu_mem.set_mirroring(self.mirroring);
+
+        Ok(())
     }
     
     fn cpu_read(&mut self, addr: u16) -> u8 {
@@ -78,6 +84,15 @@ impl Mapper for UnromMapper {
         self.ppu_mem.write(addr, value);
     }
 
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)
+    }
 }
 
 impl UnromMapper {
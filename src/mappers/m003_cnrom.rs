@@ -0,0 +1,185 @@
+use super::{load_trainer, Mapper};
+use crate::ines::{InesRom, RomError, MirroringType, CHR_ROM_CHUNK_SIZE};
+use crate::mem::{Memory, PpuMemory};
+use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+
+
+pub struct CnromMapper {
+    name: &'static str,
+    number: u16,
+    cpu_mem: Memory,
+    ppu_mem: PpuMemory,
+    mirroring: Mirroring,
+    chr_rom_banks: Vec<[u8; CHR_ROM_CHUNK_SIZE]>,
+
+    /// Register writes are ANDed with the ROM byte at the written address.
+    /// NES 2.0 submapper 1 marks boards without bus conflicts.
+    bus_conflicts: bool,
+}
+
+pub fn new(submapper: u8, cpu_mem: Memory, ppu_mem: PpuMemory) -> CnromMapper {
+    CnromMapper {
+        name: "CNROM",
+        number: 3,
+        cpu_mem,
+        ppu_mem,
+        mirroring: Mirroring::Horizontal,
+        chr_rom_banks: Vec::new(),
+        bus_conflicts: submapper != 1,
+    }
+}
+
+impl Mapper for CnromMapper {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn number(&self) -> u16 {
+        self.number
+    }
+
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        if ines.header.num_prg_rom_chunks < 1 || ines.header.num_prg_rom_chunks > 2 {
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "PRG ROM", size: ines.header.prg_rom_size,
+            });
+        }
+
+        if ines.chr_rom.is_empty() {
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "CHR ROM", size: 0,
+            });
+        }
+
+        // PRG ROM is fixed, with 16KB roms mirrored at 0xC000
+        self.cpu_mem.load(0x8000, &ines.prg_rom[0]);
+        self.cpu_mem.load(0xC000, &ines.prg_rom[ines.header.num_prg_rom_chunks - 1]);
+
+        load_trainer(ines, None, &mut self.cpu_mem);
+
+        self.init_chr_banks(ines);
+        self.ppu_mem.load(0x0000, &self.chr_rom_banks[0]);
+
+        self.mirroring = match ines.header.flags6.mirroring {
+            MirroringType::Horizontal => Mirroring::Horizontal,
+            MirroringType::Vertical => Mirroring::Vertical,
+        };
+
+        self.ppu_mem.set_mirroring(self.mirroring);
+
+        Ok(())
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.cpu_mem.read(addr)
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr < 0x8000 {
+            self.cpu_mem.write(addr, value);
+        }
+        else {
+            // Bus conflict: the ROM drives the data bus at the same time,
+            // so the written value is ANDed with the byte at addr.
+            let value = if self.bus_conflicts {
+                value & self.cpu_mem.read(addr)
+            } else {
+                value
+            };
+
+            let bank = value as usize % self.chr_rom_banks.len();
+            self.ppu_mem.load(0x0000, &self.chr_rom_banks[bank]);
+        }
+    }
+
+    fn get_cpu_dma_slice(&self, addr: u16) -> &[u8] {
+        self.cpu_mem.get_slice(addr, 256)
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => (), // Cannot overwrite pattern table ROM
+            _ => self.ppu_mem.write(addr, value),
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)
+    }
+}
+
+impl CnromMapper {
+    fn init_chr_banks(&mut self, ines: &InesRom) {
+        for bank in ines.chr_rom.iter() {
+            self.chr_rom_banks.push(*bank);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_rom;
+
+    /// CNROM mapper with 4 x 8KB CHR ROM banks.
+    fn test_mapper(submapper: u8) -> CnromMapper {
+        let mut mapper = new(submapper, Memory::new_cpu(), PpuMemory::new());
+        mapper.load_rom(&test_rom(3, submapper, 4, 32)).unwrap();
+        mapper
+    }
+
+    /// 8KB CHR bank mapped at the address.
+    fn chr_bank(mapper: &mut CnromMapper, addr: u16) -> u8 {
+        mapper.ppu_read(addr) / 8
+    }
+
+    #[test]
+    fn test_chr_bank_switch() {
+        // No bus conflicts, as PRG ROM is filled with its bank numbers
+        let mut mapper = test_mapper(1);
+
+        assert!(chr_bank(&mut mapper, 0x0000) == 0);
+        assert!(chr_bank(&mut mapper, 0x1FFF) == 0);
+
+        mapper.cpu_write(0x8000, 2);
+        assert!(chr_bank(&mut mapper, 0x0000) == 2);
+        assert!(chr_bank(&mut mapper, 0x1FFF) == 2);
+
+        // Bank numbers wrap at the number of banks
+        mapper.cpu_write(0xFFFF, 7);
+        assert!(chr_bank(&mut mapper, 0x1000) == 3);
+
+        // CHR ROM is read only
+        mapper.ppu_write(0x0000, 0x55);
+        assert!(chr_bank(&mut mapper, 0x0000) == 3);
+    }
+
+    #[test]
+    fn test_bus_conflict() {
+        let mut mapper = test_mapper(0);
+
+        // ROM byte 0x01 at the written address masks out bit 1
+        mapper.cpu_mem.write(0x8123, 0x01);
+        mapper.cpu_write(0x8123, 3);
+
+        assert!(chr_bank(&mut mapper, 0x0000) == 1);
+
+        // Submapper 1 boards have no bus conflicts
+        let mut mapper = test_mapper(1);
+        mapper.cpu_mem.write(0x8123, 0x01);
+        mapper.cpu_write(0x8123, 3);
+
+        assert!(chr_bank(&mut mapper, 0x0000) == 3);
+    }
+}
@@ -0,0 +1,526 @@
+use super::{check_chr_ram, load_trainer, new_prg_ram, Mapper};
+use crate::ines::{InesRom, MirroringType, RomError};
+use crate::mem::{Memory, PpuMemory};
+use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::utils::bit_is_set;
+use crate::wram::WRam;
+
+const EIGHT_KB: usize = 8192;
+const ONE_KB: usize = 1024;
+
+/// PPU address line A12 must be low for longer than this many PPU cycles
+/// before a rising edge clocks the scanline counter. The MMC3 filters out
+/// the quick toggling that happens during tile fetches this way.
+const A12_LOW_CYCLES: u64 = 10;
+
+///
+/// MMC3 (TxROM boards). 8KB PRG ROM banks, 1KB and 2KB CHR ROM banks,
+/// mirroring control and a scanline counter clocked by PPU address line
+/// A12 that can raise an IRQ.
+/// See: https://www.nesdev.org/wiki/MMC3
+///
+pub struct Mmc3Mapper {
+    name: &'static str,
+    number: u16,
+    cpu_mem: Memory,
+    ppu_mem: PpuMemory,
+    mirroring: Mirroring,
+    four_screen: bool,
+    prg_rom_banks: Vec<[u8; EIGHT_KB]>,
+    chr_rom_banks: Vec<[u8; ONE_KB]>,
+    chr_ram: bool,
+
+    /// Bank select register ($8000). Bits 0-2 pick the bank register
+    /// written next, bit 6 the PRG ROM bank mode and bit 7 the CHR A12
+    /// inversion.
+    bank_select: u8,
+
+    /// Bank registers R0-R7. R0-R1 are 2KB CHR banks, R2-R5 are 1KB CHR
+    /// banks and R6-R7 are 8KB PRG ROM banks.
+    bank_registers: [u8; 8],
+
+    prg_ram_enabled: bool,
+    prg_ram_write_protected: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    /// PPU cycle of the most recent pattern table fetch with A12 high.
+    last_a12_high_cycle: u64,
+
+    wram: Option<WRam>,
+}
+
+pub fn new(cpu_mem: Memory, ppu_mem: PpuMemory) -> Mmc3Mapper {
+    Mmc3Mapper {
+        name: "MMC3",
+        number: 4,
+        cpu_mem,
+        ppu_mem,
+        mirroring: Mirroring::Vertical,
+        four_screen: false,
+        prg_rom_banks: Vec::new(),
+        chr_rom_banks: Vec::new(),
+        chr_ram: false,
+        bank_select: 0,
+        bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+        prg_ram_enabled: true,
+        prg_ram_write_protected: false,
+        irq_latch: 0,
+        irq_counter: 0,
+        irq_reload: false,
+        irq_enabled: false,
+        irq_pending: false,
+        last_a12_high_cycle: 0,
+        wram: None,
+    }
+}
+
+impl Mapper for Mmc3Mapper {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn number(&self) -> u16 {
+        self.number
+    }
+
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        self.wram = new_prg_ram(ines);
+
+        load_trainer(ines, self.wram.as_mut(), &mut self.cpu_mem);
+
+        for chunk in ines.prg_rom.iter().flat_map(|bank| bank.chunks(EIGHT_KB)) {
+            self.prg_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        // Boards with CHR RAM instead of ROM leave it unbanked
+        if ines.chr_rom.is_empty() {
+            check_chr_ram(self.name, ines)?;
+            self.chr_ram = true;
+        }
+
+        for chunk in ines.chr_rom.iter().flat_map(|bank| bank.chunks(ONE_KB)) {
+            self.chr_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        self.four_screen = ines.header.flags6._four_screen_vram;
+
+        if self.four_screen {
+            warn!("MMC3: four screen mirroring is not supported");
+        }
+
+        self.mirroring = match ines.header.flags6.mirroring {
+            MirroringType::Horizontal => Mirroring::Horizontal,
+            MirroringType::Vertical => Mirroring::Vertical,
+        };
+
+        self.ppu_mem.set_mirroring(self.mirroring);
+
+        self.update_prg_banks();
+        self.update_chr_banks();
+
+        Ok(())
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => {
+                if !self.prg_ram_enabled {
+                    return 0;
+                }
+
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.read(addr),
+                    None => self.cpu_mem.read(addr),
+                }
+            },
+            _ => self.cpu_mem.read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        let even = addr & 0x01 == 0;
+
+        match addr {
+            0x6000..=0x7FFF => {
+                if !self.prg_ram_enabled || self.prg_ram_write_protected {
+                    return;
+                }
+
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.write(addr, value),
+                    None => self.cpu_mem.write(addr, value),
+                }
+            },
+            0x8000..=0x9FFF => {
+                if even {
+                    self.bank_select = value;
+                    self.update_prg_banks();
+                    self.update_chr_banks();
+                } else {
+                    let register = (self.bank_select & 0x07) as usize;
+                    self.bank_registers[register] = value;
+
+                    match register {
+                        0..=5 => self.update_chr_banks(),
+                        _ => self.update_prg_banks(),
+                    }
+                }
+            },
+            0xA000..=0xBFFF => {
+                if even {
+                    if !self.four_screen {
+                        self.mirroring = match value & 0x01 {
+                            0 => Mirroring::Vertical,
+                            _ => Mirroring::Horizontal,
+                        };
+
+                        self.ppu_mem.set_mirroring(self.mirroring);
+                    }
+                } else {
+                    self.prg_ram_enabled = bit_is_set(7, value);
+                    self.prg_ram_write_protected = bit_is_set(6, value);
+                }
+            },
+            0xC000..=0xDFFF => {
+                if even {
+                    self.irq_latch = value;
+                } else {
+                    self.irq_counter = 0;
+                    self.irq_reload = true;
+                }
+            },
+            0xE000..=0xFFFF => {
+                if even {
+                    self.irq_enabled = false;
+                    self.irq_pending = false;
+                } else {
+                    self.irq_enabled = true;
+                }
+            },
+            _ => self.cpu_mem.write(addr, value),
+        }
+    }
+
+    fn get_cpu_dma_slice(&self, addr: u16) -> &[u8] {
+        self.cpu_mem.get_slice(addr, 256)
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => {
+                if self.chr_ram {
+                    self.ppu_mem.write(addr, value);
+                }
+                // Otherwise, cannot overwrite pattern table ROM
+            },
+            _ => self.ppu_mem.write(addr, value),
+        }
+    }
+
+    fn ppu_fetch(&mut self, addr: u16, ppu_cycle: u64) -> u8 {
+        self.watch_a12(addr, ppu_cycle);
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_bus_address(&mut self, addr: u16, ppu_cycle: u64) {
+        self.watch_a12(addr, ppu_cycle);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(wram) = &self.wram {
+            wram.write_to_file();
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+        self.mirroring.save_state(writer);
+
+        writer.write_u8(self.bank_select);
+        writer.write_bytes(&self.bank_registers);
+
+        writer.write_bool(self.prg_ram_enabled);
+        writer.write_bool(self.prg_ram_write_protected);
+
+        writer.write_u8(self.irq_latch);
+        writer.write_u8(self.irq_counter);
+        writer.write_bool(self.irq_reload);
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.irq_pending);
+        writer.write_u64(self.last_a12_high_cycle);
+
+        if let Some(wram) = &self.wram {
+            wram.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        self.bank_select = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.bank_registers)?;
+
+        self.prg_ram_enabled = reader.read_bool()?;
+        self.prg_ram_write_protected = reader.read_bool()?;
+
+        self.irq_latch = reader.read_u8()?;
+        self.irq_counter = reader.read_u8()?;
+        self.irq_reload = reader.read_bool()?;
+        self.irq_enabled = reader.read_bool()?;
+        self.irq_pending = reader.read_bool()?;
+        self.last_a12_high_cycle = reader.read_u64()?;
+
+        if let Some(wram) = &mut self.wram {
+            wram.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Mmc3Mapper {
+    /// Load the PRG ROM banks selected by R6, R7 and the PRG ROM bank mode.
+    /// The second to last bank is fixed at $C000 (mode 0) or $8000 (mode 1),
+    /// and the last bank is always fixed at $E000.
+    fn update_prg_banks(&mut self) {
+        let num_banks = self.prg_rom_banks.len();
+
+        let r6 = self.bank_registers[6] as usize % num_banks;
+        let r7 = self.bank_registers[7] as usize % num_banks;
+        let second_last = num_banks.saturating_sub(2);
+
+        let (bank_8000, bank_c000) = if bit_is_set(6, self.bank_select) {
+            (second_last, r6)
+        } else {
+            (r6, second_last)
+        };
+
+        self.cpu_mem.load(0x8000, &self.prg_rom_banks[bank_8000]);
+        self.cpu_mem.load(0xA000, &self.prg_rom_banks[r7]);
+        self.cpu_mem.load(0xC000, &self.prg_rom_banks[bank_c000]);
+        self.cpu_mem.load(0xE000, &self.prg_rom_banks[num_banks - 1]);
+    }
+
+    /// Load the 1KB CHR ROM banks selected by R0-R5. R0 and R1 select 2KB
+    /// banks (ignoring their low bit) at $0000 and $0800, and R2-R5 select
+    /// 1KB banks at $1000-$1C00. CHR A12 inversion swaps the two halves.
+    fn update_chr_banks(&mut self) {
+        if self.chr_ram {
+            return;
+        }
+
+        let r = &self.bank_registers;
+
+        let banks = [
+            r[0] & 0xFE, r[0] | 0x01,
+            r[1] & 0xFE, r[1] | 0x01,
+            r[2], r[3], r[4], r[5],
+        ];
+
+        let inversion = if bit_is_set(7, self.bank_select) { 0x1000 } else { 0 };
+
+        for (i, bank) in banks.iter().enumerate() {
+            let addr = (i * ONE_KB) as u16 ^ inversion;
+            let bank = *bank as usize % self.chr_rom_banks.len();
+
+            self.ppu_mem.load(addr, &self.chr_rom_banks[bank]);
+        }
+    }
+
+    /// Watch PPU address line A12 for the given address on the PPU bus. A
+    /// rising edge after A12 was low for long enough clocks the scanline
+    /// counter.
+    fn watch_a12(&mut self, addr: u16, ppu_cycle: u64) {
+        if addr & 0x1000 != 0 {
+            if ppu_cycle.saturating_sub(self.last_a12_high_cycle) > A12_LOW_CYCLES {
+                self.clock_irq_counter();
+            }
+
+            self.last_a12_high_cycle = ppu_cycle;
+        }
+    }
+
+    /// Clock the scanline counter on a filtered rising edge of A12. The
+    /// counter is reloaded from the latch when it is zero or a reload was
+    /// requested, and otherwise counts down. An IRQ is raised when it ends
+    /// up at zero.
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::apu::Apu;
+    use crate::mappers::test_rom;
+    use crate::ppu::Ppu;
+    use crate::state::NesState;
+    use crate::timing::Timing;
+
+    /// MMC3 mapper with 8 x 8KB PRG ROM banks and 16 x 1KB CHR ROM banks.
+    fn test_mapper() -> Mmc3Mapper {
+        let mut mapper = new(Memory::new_cpu(), PpuMemory::new());
+        mapper.load_rom(&test_rom(4, 0, 8, 16)).unwrap();
+        mapper
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = test_mapper();
+
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        mapper.cpu_write(0x8000, 7);
+        mapper.cpu_write(0x8001, 4);
+
+        assert!(mapper.cpu_read(0x8000) == 3);
+        assert!(mapper.cpu_read(0xA000) == 4);
+        assert!(mapper.cpu_read(0xC000) == 6);
+        assert!(mapper.cpu_read(0xE000) == 7);
+
+        // PRG ROM bank mode 1 swaps $8000 and $C000
+        mapper.cpu_write(0x8000, 0x40);
+
+        assert!(mapper.cpu_read(0x8000) == 6);
+        assert!(mapper.cpu_read(0xC000) == 3);
+        assert!(mapper.cpu_read(0xE000) == 7);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut mapper = test_mapper();
+
+        for (register, bank) in [(0, 5), (1, 8), (2, 10), (3, 11), (4, 12), (5, 13)] {
+            mapper.cpu_write(0x8000, register);
+            mapper.cpu_write(0x8001, bank);
+        }
+
+        // R0 and R1 ignore their low bit
+        let expected = [4, 5, 8, 9, 10, 11, 12, 13];
+
+        for (i, bank) in expected.iter().enumerate() {
+            assert!(mapper.ppu_read(i as u16 * 0x400) == *bank);
+        }
+
+        // CHR A12 inversion
+        mapper.cpu_write(0x8000, 0x80);
+
+        for (i, bank) in expected.iter().enumerate() {
+            assert!(mapper.ppu_read((i as u16 * 0x400) ^ 0x1000) == *bank);
+        }
+    }
+
+    #[test]
+    fn test_prg_ram_protect() {
+        let mut mapper = test_mapper();
+
+        mapper.cpu_write(0x6000, 0x12);
+        assert!(mapper.cpu_read(0x6000) == 0x12);
+
+        // Write protected
+        mapper.cpu_write(0xA001, 0xC0);
+        mapper.cpu_write(0x6000, 0x34);
+        assert!(mapper.cpu_read(0x6000) == 0x12);
+
+        // Disabled
+        mapper.cpu_write(0xA001, 0x00);
+        assert!(mapper.cpu_read(0x6000) == 0);
+
+        mapper.cpu_write(0xA001, 0x80);
+        assert!(mapper.cpu_read(0x6000) == 0x12);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut mapper = test_mapper();
+
+        mapper.cpu_write(0xC000, 2);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        // One scanline's worth of fetches: BG at $0000, sprites at $1000
+        let mut cycle = 0;
+        let mut run_scanline = |mapper: &mut Mmc3Mapper| {
+            for i in 0..32 {
+                mapper.ppu_fetch(0x0000, cycle + i * 8);
+            }
+            for i in 0..8 {
+                mapper.ppu_fetch(0x1FF0, cycle + 264 + i * 8);
+            }
+            cycle += 341;
+        };
+
+        // Reload, then count down 1, 0
+        run_scanline(&mut mapper);
+        assert!(mapper.irq_counter == 2);
+        run_scanline(&mut mapper);
+        assert!(mapper.irq_counter == 1);
+        assert!(!mapper.irq_pending());
+        run_scanline(&mut mapper);
+        assert!(mapper.irq_pending());
+
+        // Acknowledge
+        mapper.cpu_write(0xE000, 0);
+        assert!(!mapper.irq_pending());
+
+        // Reloads from the latch at zero
+        run_scanline(&mut mapper);
+        assert!(mapper.irq_counter == 2);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_irq_counter_clocked_by_ppuaddr() {
+        let mut mapper = test_mapper();
+
+        mapper.cpu_write(0xC000, 0);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        let ppu = Rc::new(RefCell::new(Ppu::new(Timing::default())));
+        let mut state = NesState::new(Box::new(mapper), Rc::clone(&ppu),
+                                      Rc::new(RefCell::new(Apu::new(Timing::default()))));
+
+        // Let A12 stay low for a while first
+        for _ in 0..20 {
+            ppu.borrow_mut().cycle(&mut state);
+        }
+
+        // Setting the PPU address to $1000 raises A12, reloading the counter
+        // with 0, which raises an IRQ. Writes are made after the PPU warm up.
+        state.cpu_mem_write(0x2006, 0x10, 30000);
+        assert!(!state.irq_line());
+        state.cpu_mem_write(0x2006, 0x00, 30000);
+        assert!(state.irq_line());
+    }
+}
@@ -0,0 +1,192 @@
+use super::{check_chr_ram, load_trainer, Mapper};
+use crate::ines::{InesRom, RomError, PRG_ROM_CHUNK_SIZE};
+use crate::mem::{Memory, PpuMemory};
+use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::utils::bit_is_set;
+
+const PRG_BANK_SIZE: usize = PRG_ROM_CHUNK_SIZE * 2;
+
+///
+/// AxROM (ANROM, AMROM, AOROM). Switches 32KB PRG ROM banks and selects
+/// which nametable page is shown with single-screen mirroring. CHR is 8KB
+/// of unbanked RAM.
+///
+pub struct AxromMapper {
+    name: &'static str,
+    number: u16,
+    cpu_mem: Memory,
+    ppu_mem: PpuMemory,
+    mirroring: Mirroring,
+    prg_rom_banks: Vec<[u8; PRG_BANK_SIZE]>,
+
+    /// Register writes are ANDed with the ROM byte at the written address.
+    /// Only boards marked as NES 2.0 submapper 2 (e.g. AMROM) have them.
+    bus_conflicts: bool,
+}
+
+pub fn new(submapper: u8, cpu_mem: Memory, ppu_mem: PpuMemory) -> AxromMapper {
+    AxromMapper {
+        name: "AxROM",
+        number: 7,
+        cpu_mem,
+        ppu_mem,
+        mirroring: Mirroring::OneScreen0,
+        prg_rom_banks: Vec::new(),
+        bus_conflicts: submapper == 2,
+    }
+}
+
+impl Mapper for AxromMapper {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn number(&self) -> u16 {
+        self.number
+    }
+
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        // PRG ROM must be a whole number of 32KB banks
+        let num_chunks = ines.header.num_prg_rom_chunks;
+
+        if num_chunks == 0 || num_chunks & 0x01 != 0 {
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "PRG ROM", size: ines.header.prg_rom_size,
+            });
+        }
+
+        self.init_prg_banks(ines);
+        self.cpu_mem.load(0x8000, &self.prg_rom_banks[0]);
+
+        load_trainer(ines, None, &mut self.cpu_mem);
+
+        // AxROM have chr ram, so no need to load anything into ppu mem here
+        check_chr_ram(self.name, ines)?;
+
+        self.mirroring = Mirroring::OneScreen0;
+        self.ppu_mem.set_mirroring(self.mirroring);
+
+        Ok(())
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.cpu_mem.read(addr)
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        if addr < 0x8000 {
+            self.cpu_mem.write(addr, value);
+        }
+        else {
+            let value = if self.bus_conflicts {
+                value & self.cpu_mem.read(addr)
+            } else {
+                value
+            };
+
+            let bank = (value & 0x07) as usize % self.prg_rom_banks.len();
+            self.cpu_mem.load(0x8000, &self.prg_rom_banks[bank]);
+
+            // Bit 4 selects the nametable page
+            self.mirroring = if bit_is_set(4, value) {
+                Mirroring::OneScreen1
+            } else {
+                Mirroring::OneScreen0
+            };
+
+            self.ppu_mem.set_mirroring(self.mirroring);
+        }
+    }
+
+    fn get_cpu_dma_slice(&self, addr: u16) -> &[u8] {
+        self.cpu_mem.get_slice(addr, 256)
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        self.ppu_mem.write(addr, value);
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+        self.mirroring.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        Ok(())
+    }
+}
+
+impl AxromMapper {
+    fn init_prg_banks(&mut self, ines: &InesRom) {
+        for pair in ines.prg_rom.chunks(2) {
+            let mut bank = [0; PRG_BANK_SIZE];
+            bank[..PRG_ROM_CHUNK_SIZE].copy_from_slice(&pair[0]);
+            bank[PRG_ROM_CHUNK_SIZE..].copy_from_slice(&pair[1]);
+
+            self.prg_rom_banks.push(bank);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_rom;
+
+    /// AxROM mapper with 4 x 32KB PRG ROM banks.
+    fn test_mapper() -> AxromMapper {
+        let mut mapper = new(0, Memory::new_cpu(), PpuMemory::new());
+        mapper.load_rom(&test_rom(7, 0, 16, 0)).unwrap();
+        mapper
+    }
+
+    /// 32KB PRG bank mapped at the address.
+    fn prg_bank(mapper: &mut AxromMapper, addr: u16) -> u8 {
+        mapper.cpu_read(addr) / 4
+    }
+
+    #[test]
+    fn test_prg_bank_switch() {
+        let mut mapper = test_mapper();
+
+        assert!(prg_bank(&mut mapper, 0x8000) == 0);
+
+        mapper.cpu_write(0x8000, 3);
+        assert!(prg_bank(&mut mapper, 0x8000) == 3);
+        assert!(prg_bank(&mut mapper, 0xFFFF) == 3);
+
+        // Bank numbers wrap at the number of banks
+        mapper.cpu_write(0x8000, 6);
+        assert!(prg_bank(&mut mapper, 0xC000) == 2);
+    }
+
+    #[test]
+    fn test_single_screen_mirroring() {
+        let mut mapper = test_mapper();
+
+        // All four nametables show page 0
+        mapper.ppu_write(0x2000, 0x11);
+        assert!(mapper.ppu_read(0x2400) == 0x11);
+        assert!(mapper.ppu_read(0x2C00) == 0x11);
+
+        // Switch to page 1, which is still empty
+        mapper.cpu_write(0x8000, 0x10);
+        assert!(mapper.ppu_read(0x2000) == 0);
+
+        mapper.ppu_write(0x2800, 0x22);
+        assert!(mapper.ppu_read(0x2400) == 0x22);
+
+        mapper.cpu_write(0x8000, 0x00);
+        assert!(mapper.ppu_read(0x2800) == 0x11);
+    }
+}
@@ -0,0 +1,359 @@
+use super::{load_trainer, new_prg_ram, Mapper};
+use crate::ines::{InesRom, MirroringType, RomError};
+use crate::mem::{Memory, PpuMemory};
+use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::wram::WRam;
+
+const EIGHT_KB: usize = 8192;
+const FOUR_KB: usize = 4096;
+
+/// Latch values, named after the tiles whose fetch sets them.
+const LATCH_FD: u8 = 0xFD;
+const LATCH_FE: u8 = 0xFE;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Variant {
+    /// Mapper 9: one switchable 8KB PRG ROM bank.
+    Mmc2,
+
+    /// Mapper 10: one switchable 16KB PRG ROM bank.
+    Mmc4,
+}
+
+///
+/// MMC2 (mapper 9) and MMC4 (mapper 10). Each half of the pattern tables
+/// has two CHR ROM banks, and a latch that picks between them. The latch
+/// switches when the PPU fetches tile $FD or $FE from that half.
+/// See: https://www.nesdev.org/wiki/MMC2 and https://www.nesdev.org/wiki/MMC4
+///
+pub struct Mmc2Mapper {
+    name: &'static str,
+    number: u16,
+    variant: Variant,
+    cpu_mem: Memory,
+    ppu_mem: PpuMemory,
+    mirroring: Mirroring,
+    prg_rom_banks: Vec<[u8; EIGHT_KB]>,
+    chr_rom_banks: Vec<[u8; FOUR_KB]>,
+
+    /// Selected PRG ROM bank at $8000, in units of the variant's bank size.
+    prg_bank: u8,
+
+    /// CHR ROM banks for $0000 with latch 0 set to $FD and $FE, then for
+    /// $1000 with latch 1 set to $FD and $FE.
+    chr_banks: [u8; 4],
+
+    /// Latch 0 ($0000-$0FFF) and latch 1 ($1000-$1FFF).
+    latches: [u8; 2],
+
+    wram: Option<WRam>,
+}
+
+pub fn new(cpu_mem: Memory, ppu_mem: PpuMemory) -> Mmc2Mapper {
+    Mmc2Mapper {
+        name: "MMC2",
+        number: 9,
+        variant: Variant::Mmc2,
+        cpu_mem,
+        ppu_mem,
+        mirroring: Mirroring::Vertical,
+        prg_rom_banks: Vec::new(),
+        chr_rom_banks: Vec::new(),
+        prg_bank: 0,
+        chr_banks: [0; 4],
+        latches: [LATCH_FE; 2],
+        wram: None,
+    }
+}
+
+pub fn new_mmc4(cpu_mem: Memory, ppu_mem: PpuMemory) -> Mmc2Mapper {
+    Mmc2Mapper {
+        name: "MMC4",
+        number: 10,
+        variant: Variant::Mmc4,
+        ..new(cpu_mem, ppu_mem)
+    }
+}
+
+impl Mapper for Mmc2Mapper {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn number(&self) -> u16 {
+        self.number
+    }
+
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        if ines.chr_rom.is_empty() {
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "CHR ROM", size: 0,
+            });
+        }
+
+        self.wram = new_prg_ram(ines);
+
+        load_trainer(ines, self.wram.as_mut(), &mut self.cpu_mem);
+
+        for chunk in ines.prg_rom.iter().flat_map(|bank| bank.chunks(EIGHT_KB)) {
+            self.prg_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        for chunk in ines.chr_rom.iter().flat_map(|bank| bank.chunks(FOUR_KB)) {
+            self.chr_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        self.mirroring = match ines.header.flags6.mirroring {
+            MirroringType::Horizontal => Mirroring::Horizontal,
+            MirroringType::Vertical => Mirroring::Vertical,
+        };
+
+        self.ppu_mem.set_mirroring(self.mirroring);
+
+        // Everything after the switchable bank is fixed to the end of PRG ROM
+        let num_banks = self.prg_rom_banks.len();
+        let first_fixed = match self.variant {
+            Variant::Mmc2 => 1,
+            Variant::Mmc4 => 2,
+        };
+
+        for slot in first_fixed..4 {
+            let bank = (num_banks + slot).saturating_sub(4);
+            self.cpu_mem.load(0x8000 + (slot * EIGHT_KB) as u16, &self.prg_rom_banks[bank]);
+        }
+
+        self.update_prg_bank();
+        self.update_chr_bank(0);
+        self.update_chr_bank(1);
+
+        Ok(())
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match (addr, &mut self.wram) {
+            (0x6000..=0x7FFF, Some(cart_wram)) => cart_wram.read(addr),
+            _ => self.cpu_mem.read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.write(addr, value),
+                    None => self.cpu_mem.write(addr, value),
+                }
+            },
+            0xA000..=0xAFFF => {
+                self.prg_bank = value & 0x0F;
+                self.update_prg_bank();
+            },
+            0xB000..=0xEFFF => {
+                let register = ((addr - 0xB000) >> 12) as usize;
+                self.chr_banks[register] = value & 0x1F;
+                self.update_chr_bank(register / 2);
+            },
+            0xF000..=0xFFFF => {
+                self.mirroring = match value & 0x01 {
+                    0 => Mirroring::Vertical,
+                    _ => Mirroring::Horizontal,
+                };
+
+                self.ppu_mem.set_mirroring(self.mirroring);
+            },
+            0x8000..=0x9FFF => (), // No registers
+            _ => self.cpu_mem.write(addr, value),
+        }
+    }
+
+    fn get_cpu_dma_slice(&self, addr: u16) -> &[u8] {
+        self.cpu_mem.get_slice(addr, 256)
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => (), // Cannot overwrite pattern table ROM
+            _ => self.ppu_mem.write(addr, value),
+        }
+    }
+
+    fn ppu_fetch(&mut self, addr: u16, _ppu_cycle: u64) -> u8 {
+        // The fetch that sets a latch still reads from the old bank
+        let value = self.ppu_mem.read(addr);
+
+        let latch = match (self.variant, addr) {
+            (Variant::Mmc2, 0x0FD8) => Some((0, LATCH_FD)),
+            (Variant::Mmc2, 0x0FE8) => Some((0, LATCH_FE)),
+            (Variant::Mmc4, 0x0FD8..=0x0FDF) => Some((0, LATCH_FD)),
+            (Variant::Mmc4, 0x0FE8..=0x0FEF) => Some((0, LATCH_FE)),
+            (_, 0x1FD8..=0x1FDF) => Some((1, LATCH_FD)),
+            (_, 0x1FE8..=0x1FEF) => Some((1, LATCH_FE)),
+            _ => None,
+        };
+
+        if let Some((index, latch_value)) = latch {
+            if self.latches[index] != latch_value {
+                self.latches[index] = latch_value;
+                self.update_chr_bank(index);
+            }
+        }
+
+        value
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(wram) = &self.wram {
+            wram.write_to_file();
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+        self.mirroring.save_state(writer);
+
+        writer.write_u8(self.prg_bank);
+        writer.write_bytes(&self.chr_banks);
+        writer.write_bytes(&self.latches);
+
+        if let Some(wram) = &self.wram {
+            wram.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        self.prg_bank = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.chr_banks)?;
+        reader.read_bytes_into(&mut self.latches)?;
+
+        if let Some(wram) = &mut self.wram {
+            wram.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Mmc2Mapper {
+    /// Load the selected PRG ROM bank at $8000. MMC4's 16KB banks are loaded
+    /// as two 8KB banks.
+    fn update_prg_bank(&mut self) {
+        let num_banks = self.prg_rom_banks.len();
+
+        match self.variant {
+            Variant::Mmc2 => {
+                let bank = self.prg_bank as usize % num_banks;
+                self.cpu_mem.load(0x8000, &self.prg_rom_banks[bank]);
+            },
+            Variant::Mmc4 => {
+                let bank = (self.prg_bank as usize * 2) % num_banks;
+                self.cpu_mem.load(0x8000, &self.prg_rom_banks[bank]);
+                self.cpu_mem.load(0xA000, &self.prg_rom_banks[(bank + 1) % num_banks]);
+            },
+        }
+    }
+
+    /// Load the CHR ROM bank picked by latch 0 ($0000) or latch 1 ($1000).
+    fn update_chr_bank(&mut self, latch: usize) {
+        let register = latch * 2 + if self.latches[latch] == LATCH_FD { 0 } else { 1 };
+        let bank = self.chr_banks[register] as usize % self.chr_rom_banks.len();
+
+        self.ppu_mem.load((latch * FOUR_KB) as u16, &self.chr_rom_banks[bank]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_rom;
+
+    /// Load a rom with 8 x 8KB PRG ROM banks and 8 x 4KB CHR ROM banks.
+    fn test_mapper(mut mapper: Mmc2Mapper) -> Mmc2Mapper {
+        mapper.load_rom(&test_rom(9, 0, 8, 32)).unwrap();
+        mapper
+    }
+
+    /// 4KB CHR bank mapped at the address.
+    fn chr_bank(mapper: &mut Mmc2Mapper, addr: u16) -> u8 {
+        mapper.ppu_read(addr) / 4
+    }
+
+    #[test]
+    fn test_mmc2_prg_banks() {
+        let mut mapper = test_mapper(new(Memory::new_cpu(), PpuMemory::new()));
+
+        mapper.cpu_write(0xA000, 3);
+
+        assert!(mapper.cpu_read(0x8000) == 3);
+        assert!(mapper.cpu_read(0xA000) == 5);
+        assert!(mapper.cpu_read(0xC000) == 6);
+        assert!(mapper.cpu_read(0xE000) == 7);
+    }
+
+    #[test]
+    fn test_mmc4_prg_banks() {
+        let mut mapper = test_mapper(new_mmc4(Memory::new_cpu(), PpuMemory::new()));
+
+        mapper.cpu_write(0xA000, 1);
+
+        assert!(mapper.cpu_read(0x8000) == 2);
+        assert!(mapper.cpu_read(0xA000) == 3);
+        assert!(mapper.cpu_read(0xC000) == 6);
+        assert!(mapper.cpu_read(0xE000) == 7);
+    }
+
+    #[test]
+    fn test_chr_latches() {
+        let mut mapper = test_mapper(new(Memory::new_cpu(), PpuMemory::new()));
+
+        // $FD and $FE banks for each half
+        mapper.cpu_write(0xB000, 1);
+        mapper.cpu_write(0xC000, 2);
+        mapper.cpu_write(0xD000, 3);
+        mapper.cpu_write(0xE000, 4);
+
+        // Latches start at $FE
+        assert!(chr_bank(&mut mapper, 0x0000) == 2);
+        assert!(chr_bank(&mut mapper, 0x1000) == 4);
+
+        // Fetching tile $FD switches after the fetch
+        assert!(mapper.ppu_fetch(0x0FD8, 0) / 4 == 2);
+        assert!(chr_bank(&mut mapper, 0x0000) == 1);
+        assert!(chr_bank(&mut mapper, 0x1000) == 4);
+
+        // MMC2 only latches on $0FD8 in the left pattern table, but any row
+        // in the right one
+        mapper.ppu_fetch(0x0FE9, 0);
+        assert!(chr_bank(&mut mapper, 0x0000) == 1);
+
+        mapper.ppu_fetch(0x1FDB, 0);
+        assert!(chr_bank(&mut mapper, 0x1000) == 3);
+
+        mapper.ppu_fetch(0x0FE8, 0);
+        assert!(chr_bank(&mut mapper, 0x0000) == 2);
+
+        // Plain reads (e.g. $2007) don't switch
+        mapper.ppu_read(0x1FE8);
+        assert!(chr_bank(&mut mapper, 0x1000) == 3);
+    }
+
+    #[test]
+    fn test_mmc4_chr_latches() {
+        let mut mapper = test_mapper(new_mmc4(Memory::new_cpu(), PpuMemory::new()));
+
+        mapper.cpu_write(0xB000, 1);
+        mapper.cpu_write(0xC000, 2);
+
+        mapper.ppu_fetch(0x0FDD, 0);
+        assert!(chr_bank(&mut mapper, 0x0000) == 1);
+    }
+}
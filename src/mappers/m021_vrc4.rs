@@ -0,0 +1,441 @@
+use super::vrc_irq::VrcIrq;
+use super::{load_trainer, new_prg_ram, Mapper};
+use crate::ines::{InesRom, MirroringType, RomError};
+use crate::mem::{Memory, PpuMemory};
+use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::utils::bit_is_set;
+use crate::wram::WRam;
+
+const EIGHT_KB: usize = 8192;
+const ONE_KB: usize = 1024;
+
+///
+/// Which CPU address lines a board connects to the chip's two register
+/// select pins. Each field is a mask of address bits; any bit set in the
+/// mask selects the pin. Boards without a known submapper get both of their
+/// mapper number's variants ORed together, which works for all games since
+/// none of them write to the other variant's addresses.
+///
+#[derive(Clone, Copy)]
+struct Wiring {
+    a0: u16,
+    a1: u16,
+}
+
+///
+/// Konami VRC2 and VRC4, iNES mappers 21, 22, 23 and 25. 8KB PRG ROM banks,
+/// 1KB CHR ROM banks and mirroring control. VRC4 adds a swappable PRG ROM
+/// layout, single-screen mirroring and a CPU cycle IRQ counter. The boards
+/// differ in which address lines select registers, picked here by mapper
+/// and submapper number.
+/// See: https://www.nesdev.org/wiki/VRC2_and_VRC4
+///
+pub struct Vrc4Mapper {
+    name: &'static str,
+    number: u16,
+    cpu_mem: Memory,
+    ppu_mem: PpuMemory,
+    mirroring: Mirroring,
+    prg_rom_banks: Vec<[u8; EIGHT_KB]>,
+    chr_rom_banks: Vec<[u8; ONE_KB]>,
+    wiring: Wiring,
+
+    /// VRC2 only has 2 mirroring modes and no IRQ or PRG swap mode.
+    vrc2: bool,
+
+    /// VRC2a (mapper 22) ignores the low bit of CHR bank numbers.
+    chr_shift: bool,
+
+    /// 8KB PRG ROM banks at $8000 (or $C000 in swap mode) and $A000.
+    prg_banks: [u8; 2],
+
+    /// Swap the switchable $8000 bank with the fixed second to last bank.
+    prg_swap: bool,
+
+    /// 1KB CHR ROM banks at $0000-$1C00, written a nibble at a time.
+    chr_banks: [u16; 8],
+
+    irq: VrcIrq,
+    wram: Option<WRam>,
+}
+
+pub fn new(number: u16, submapper: u8, cpu_mem: Memory, ppu_mem: PpuMemory) -> Vrc4Mapper {
+    let (a0, a1, vrc2) = match (number, submapper) {
+        (21, 1) => (0x02, 0x04, false), // VRC4a
+        (21, 2) => (0x40, 0x80, false), // VRC4c
+        (21, _) => (0x42, 0x84, false),
+        (22, _) => (0x02, 0x01, true),  // VRC2a
+        (23, 1) => (0x01, 0x02, false), // VRC4f
+        (23, 2) => (0x04, 0x08, false), // VRC4e
+        (23, 3) => (0x01, 0x02, true),  // VRC2b
+        (23, _) => (0x05, 0x0A, false),
+        (25, 1) => (0x02, 0x01, false), // VRC4b
+        (25, 2) => (0x08, 0x04, false), // VRC4d
+        (25, 3) => (0x02, 0x01, true),  // VRC2c
+        _ => (0x0A, 0x05, false),       // 25
+    };
+
+    Vrc4Mapper {
+        name: if vrc2 { "VRC2" } else { "VRC4" },
+        number,
+        cpu_mem,
+        ppu_mem,
+        mirroring: Mirroring::Vertical,
+        prg_rom_banks: Vec::new(),
+        chr_rom_banks: Vec::new(),
+        wiring: Wiring { a0, a1 },
+        vrc2,
+        chr_shift: number == 22,
+        prg_banks: [0, 1],
+        prg_swap: false,
+        chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+        irq: VrcIrq::new(),
+        wram: None,
+    }
+}
+
+impl Mapper for Vrc4Mapper {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn number(&self) -> u16 {
+        self.number
+    }
+
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        if ines.chr_rom.is_empty() {
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "CHR ROM", size: 0,
+            });
+        }
+
+        self.wram = new_prg_ram(ines);
+
+        load_trainer(ines, self.wram.as_mut(), &mut self.cpu_mem);
+
+        for chunk in ines.prg_rom.iter().flat_map(|bank| bank.chunks(EIGHT_KB)) {
+            self.prg_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        for chunk in ines.chr_rom.iter().flat_map(|bank| bank.chunks(ONE_KB)) {
+            self.chr_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        self.mirroring = match ines.header.flags6.mirroring {
+            MirroringType::Horizontal => Mirroring::Horizontal,
+            MirroringType::Vertical => Mirroring::Vertical,
+        };
+
+        self.ppu_mem.set_mirroring(self.mirroring);
+
+        self.update_prg_banks();
+
+        for bank in 0..self.chr_banks.len() {
+            self.update_chr_bank(bank);
+        }
+
+        Ok(())
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => {
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.read(addr),
+                    None => self.cpu_mem.read(addr),
+                }
+            },
+            _ => self.cpu_mem.read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.write(addr, value),
+                    None => self.cpu_mem.write(addr, value),
+                }
+            },
+            0x8000..=0xFFFF => self.write_register(self.register(addr), value),
+            _ => self.cpu_mem.write(addr, value),
+        }
+    }
+
+    fn get_cpu_dma_slice(&self, addr: u16) -> &[u8] {
+        self.cpu_mem.get_slice(addr, 256)
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => (), // Cannot overwrite pattern table ROM
+            _ => self.ppu_mem.write(addr, value),
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        if !self.vrc2 {
+            self.irq.cpu_cycle();
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(wram) = &self.wram {
+            wram.write_to_file();
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+        self.mirroring.save_state(writer);
+
+        writer.write_bytes(&self.prg_banks);
+        writer.write_bool(self.prg_swap);
+
+        for bank in self.chr_banks {
+            writer.write_u16(bank);
+        }
+
+        self.irq.save_state(writer);
+
+        if let Some(wram) = &self.wram {
+            wram.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        reader.read_bytes_into(&mut self.prg_banks)?;
+        self.prg_swap = reader.read_bool()?;
+
+        for bank in self.chr_banks.iter_mut() {
+            *bank = reader.read_u16()?;
+        }
+
+        self.irq.load_state(reader)?;
+
+        if let Some(wram) = &mut self.wram {
+            wram.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Vrc4Mapper {
+    /// Translate a CPU address to the register it selects on this board,
+    /// as $x000-$x003.
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = (addr & self.wiring.a0 != 0) as u16;
+        let a1 = (addr & self.wiring.a1 != 0) as u16;
+
+        (addr & 0xF000) | (a1 << 1) | a0
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0x8000..=0x8003 => {
+                self.prg_banks[0] = value & 0x1F;
+                self.update_prg_banks();
+            },
+            0x9000..=0x9003 if self.vrc2 => {
+                self.set_mirroring(value & 0x01);
+            },
+            0x9000 => {
+                self.set_mirroring(value & 0x03);
+            },
+            0x9002 => {
+                // Bit 0 is the PRG RAM enable, which is left always on
+                self.prg_swap = bit_is_set(1, value);
+                self.update_prg_banks();
+            },
+            0xA000..=0xA003 => {
+                self.prg_banks[1] = value & 0x1F;
+                self.update_prg_banks();
+            },
+            0xB000..=0xEFFF => {
+                // Each bank has a low and high nibble register, two banks
+                // per $1000
+                let bank = (((register >> 12) - 0xB) * 2 + ((register >> 1) & 0x01)) as usize;
+
+                if register & 0x01 == 0 {
+                    self.chr_banks[bank] = (self.chr_banks[bank] & 0x1F0) | (value & 0x0F) as u16;
+                } else {
+                    let high_mask = if self.vrc2 { 0x0F } else { 0x1F };
+                    self.chr_banks[bank] = (self.chr_banks[bank] & 0x0F) | (((value & high_mask) as u16) << 4);
+                }
+
+                self.update_chr_bank(bank);
+            },
+            0xF000 if !self.vrc2 => self.irq.write_latch_low(value),
+            0xF001 if !self.vrc2 => self.irq.write_latch_high(value),
+            0xF002 if !self.vrc2 => self.irq.write_control(value),
+            0xF003 if !self.vrc2 => self.irq.acknowledge(),
+            _ => (),
+        }
+    }
+
+    fn set_mirroring(&mut self, mode: u8) {
+        self.mirroring = match mode {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::OneScreen0,
+            _ => Mirroring::OneScreen1,
+        };
+
+        self.ppu_mem.set_mirroring(self.mirroring);
+    }
+
+    /// Load the switchable PRG ROM banks. The second to last bank is fixed
+    /// at $C000 (or $8000 in swap mode), and the last bank at $E000.
+    fn update_prg_banks(&mut self) {
+        let num_banks = self.prg_rom_banks.len();
+
+        let bank0 = self.prg_banks[0] as usize % num_banks;
+        let bank1 = self.prg_banks[1] as usize % num_banks;
+        let second_last = num_banks.saturating_sub(2);
+
+        let (bank_8000, bank_c000) = if self.prg_swap {
+            (second_last, bank0)
+        } else {
+            (bank0, second_last)
+        };
+
+        self.cpu_mem.load(0x8000, &self.prg_rom_banks[bank_8000]);
+        self.cpu_mem.load(0xA000, &self.prg_rom_banks[bank1]);
+        self.cpu_mem.load(0xC000, &self.prg_rom_banks[bank_c000]);
+        self.cpu_mem.load(0xE000, &self.prg_rom_banks[num_banks - 1]);
+    }
+
+    fn update_chr_bank(&mut self, bank: usize) {
+        let mut number = self.chr_banks[bank] as usize;
+
+        if self.chr_shift {
+            number >>= 1;
+        }
+
+        let number = number % self.chr_rom_banks.len();
+        self.ppu_mem.load((bank * ONE_KB) as u16, &self.chr_rom_banks[number]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_rom;
+
+    /// VRC2/VRC4 mapper with 8 x 8KB PRG ROM banks and 32 x 1KB CHR ROM
+    /// banks.
+    fn test_mapper(number: u16, submapper: u8) -> Vrc4Mapper {
+        let mut mapper = new(number, submapper, Memory::new_cpu(), PpuMemory::new());
+        mapper.load_rom(&test_rom(number, submapper, 8, 32)).unwrap();
+        mapper
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = test_mapper(21, 1);
+
+        assert!(mapper.cpu_read(0xC000) == 6);
+        assert!(mapper.cpu_read(0xE000) == 7);
+
+        mapper.cpu_write(0x8000, 3);
+        mapper.cpu_write(0xA000, 4);
+        assert!(mapper.cpu_read(0x8000) == 3);
+        assert!(mapper.cpu_read(0xA000) == 4);
+
+        // Swap mode ($9002 on VRC4a is A2)
+        mapper.cpu_write(0x9004, 0x02);
+        assert!(mapper.cpu_read(0x8000) == 6);
+        assert!(mapper.cpu_read(0xC000) == 3);
+        assert!(mapper.cpu_read(0xE000) == 7);
+    }
+
+    #[test]
+    fn test_register_wiring() {
+        // VRC4d: A3 and A2 select the low and high nibble of bank 1
+        let mut mapper = test_mapper(25, 2);
+        mapper.cpu_write(0xB004, 0x05);
+        mapper.cpu_write(0xB00C, 0x01);
+        assert!(mapper.ppu_read(0x0400) == 0x15);
+
+        // VRC4e: A2 and A3
+        let mut mapper = test_mapper(23, 2);
+        mapper.cpu_write(0xE008, 0x07);
+        assert!(mapper.ppu_read(0x1C00) == 0x07);
+
+        // No submapper: both the VRC4f and VRC4e addresses work
+        let mut mapper = test_mapper(23, 0);
+        mapper.cpu_write(0xC002, 0x09);
+        assert!(mapper.ppu_read(0x0C00) == 0x09);
+        mapper.cpu_write(0xC008, 0x0A);
+        assert!(mapper.ppu_read(0x0C00) == 0x0A);
+    }
+
+    #[test]
+    fn test_vrc2a_chr_shift() {
+        let mut mapper = test_mapper(22, 0);
+        assert!(mapper.name() == "VRC2");
+
+        // $B002 is the high nibble of bank 0 on VRC2a (A1, A0)
+        mapper.cpu_write(0xB000, 0x06);
+        mapper.cpu_write(0xB002, 0x01);
+        assert!(mapper.ppu_read(0x0000) == 0x0B);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mapper = test_mapper(21, 1);
+
+        mapper.cpu_write(0x9000, 0x02);
+        mapper.ppu_write(0x2000, 0x11);
+        assert!(mapper.ppu_read(0x2C00) == 0x11);
+
+        mapper.cpu_write(0x9000, 0x01);
+        mapper.ppu_write(0x2800, 0x22);
+        assert!(mapper.ppu_read(0x2400) == 0x11);
+        assert!(mapper.ppu_read(0x2C00) == 0x22);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = test_mapper(23, 1);
+
+        // Latch 0xFE in cycle mode
+        mapper.cpu_write(0xF000, 0x0E);
+        mapper.cpu_write(0xF001, 0x0F);
+        mapper.cpu_write(0xF002, 0x06);
+
+        mapper.cpu_cycle();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_cycle();
+        assert!(mapper.irq_pending());
+
+        mapper.cpu_write(0xF003, 0);
+        assert!(!mapper.irq_pending());
+
+        // VRC2 has no IRQ
+        let mut mapper = test_mapper(23, 3);
+        mapper.cpu_write(0xF002, 0x06);
+
+        for _ in 0..512 {
+            mapper.cpu_cycle();
+        }
+
+        assert!(!mapper.irq_pending());
+    }
+}
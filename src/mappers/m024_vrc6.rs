@@ -0,0 +1,358 @@
+use super::vrc6_audio::{self, Vrc6Audio};
+use super::vrc_irq::VrcIrq;
+use super::{load_trainer, new_prg_ram, Mapper};
+use crate::ines::{InesRom, MirroringType, RomError};
+use crate::mem::{Memory, PpuMemory};
+use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::wram::WRam;
+
+const EIGHT_KB: usize = 8192;
+const ONE_KB: usize = 1024;
+
+///
+/// Konami VRC6, iNES mappers 24 (VRC6a) and 26 (VRC6b, with A0 and A1
+/// swapped). A 16KB and an 8KB PRG ROM bank, 1KB CHR ROM banks, the VRC IRQ
+/// counter and two pulse plus one sawtooth expansion audio channels.
+/// Only the 1KB CHR banking mode, which all games use with CIRAM
+/// nametables, is supported.
+/// See: https://www.nesdev.org/wiki/VRC6
+///
+pub struct Vrc6Mapper {
+    name: &'static str,
+    number: u16,
+    cpu_mem: Memory,
+    ppu_mem: PpuMemory,
+    mirroring: Mirroring,
+    prg_rom_banks: Vec<[u8; EIGHT_KB]>,
+    chr_rom_banks: Vec<[u8; ONE_KB]>,
+
+    /// 16KB PRG ROM bank at $8000.
+    prg_bank_16k: u8,
+
+    /// 8KB PRG ROM bank at $C000.
+    prg_bank_8k: u8,
+
+    /// 1KB CHR ROM banks at $0000-$1C00.
+    chr_banks: [u8; 8],
+
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+    wram: Option<WRam>,
+}
+
+pub fn new(number: u16, cpu_mem: Memory, ppu_mem: PpuMemory) -> Vrc6Mapper {
+    Vrc6Mapper {
+        name: "VRC6",
+        number,
+        cpu_mem,
+        ppu_mem,
+        mirroring: Mirroring::Vertical,
+        prg_rom_banks: Vec::new(),
+        chr_rom_banks: Vec::new(),
+        prg_bank_16k: 0,
+        prg_bank_8k: 0,
+        chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+        irq: VrcIrq::new(),
+        audio: Vrc6Audio::new(),
+        wram: None,
+    }
+}
+
+impl Mapper for Vrc6Mapper {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn number(&self) -> u16 {
+        self.number
+    }
+
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError> {
+        if ines.chr_rom.is_empty() {
+            return Err(RomError::UnsupportedSize {
+                mapper: self.name, section: "CHR ROM", size: 0,
+            });
+        }
+
+        self.wram = new_prg_ram(ines);
+
+        load_trainer(ines, self.wram.as_mut(), &mut self.cpu_mem);
+
+        for chunk in ines.prg_rom.iter().flat_map(|bank| bank.chunks(EIGHT_KB)) {
+            self.prg_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        for chunk in ines.chr_rom.iter().flat_map(|bank| bank.chunks(ONE_KB)) {
+            self.chr_rom_banks.push(chunk.try_into().unwrap());
+        }
+
+        self.mirroring = match ines.header.flags6.mirroring {
+            MirroringType::Horizontal => Mirroring::Horizontal,
+            MirroringType::Vertical => Mirroring::Vertical,
+        };
+
+        self.ppu_mem.set_mirroring(self.mirroring);
+
+        self.update_prg_banks();
+
+        for bank in 0..self.chr_banks.len() {
+            self.update_chr_bank(bank);
+        }
+
+        Ok(())
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => {
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.read(addr),
+                    None => self.cpu_mem.read(addr),
+                }
+            },
+            _ => self.cpu_mem.read(addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                match &mut self.wram {
+                    Some(cart_wram) => cart_wram.write(addr, value),
+                    None => self.cpu_mem.write(addr, value),
+                }
+            },
+            0x8000..=0xFFFF => self.write_register(self.register(addr), value),
+            _ => self.cpu_mem.write(addr, value),
+        }
+    }
+
+    fn get_cpu_dma_slice(&self, addr: u16) -> &[u8] {
+        self.cpu_mem.get_slice(addr, 256)
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_mem.read(addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => (), // Cannot overwrite pattern table ROM
+            _ => self.ppu_mem.write(addr, value),
+        }
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.irq.cpu_cycle();
+        self.audio.cycle();
+    }
+
+    fn audio_sample(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn audio_peak(&self) -> f32 {
+        vrc6_audio::MAX_OUTPUT
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(wram) = &self.wram {
+            wram.write_to_file();
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu_mem.save_state(writer);
+        self.ppu_mem.save_state(writer);
+        self.mirroring.save_state(writer);
+
+        writer.write_u8(self.prg_bank_16k);
+        writer.write_u8(self.prg_bank_8k);
+        writer.write_bytes(&self.chr_banks);
+
+        self.irq.save_state(writer);
+        self.audio.save_state(writer);
+
+        if let Some(wram) = &self.wram {
+            wram.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_mem.load_state(reader)?;
+        self.ppu_mem.load_state(reader)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        self.prg_bank_16k = reader.read_u8()?;
+        self.prg_bank_8k = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.chr_banks)?;
+
+        self.irq.load_state(reader)?;
+        self.audio.load_state(reader)?;
+
+        if let Some(wram) = &mut self.wram {
+            wram.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Vrc6Mapper {
+    /// Translate a CPU address to the register it selects, as $x000-$x003.
+    /// VRC6b boards swap A0 and A1.
+    fn register(&self, addr: u16) -> u16 {
+        let low = addr & 0x03;
+
+        let low = if self.number == 26 {
+            ((low & 0x01) << 1) | (low >> 1)
+        } else {
+            low
+        };
+
+        (addr & 0xF000) | low
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0x8000..=0x8003 => {
+                self.prg_bank_16k = value & 0x0F;
+                self.update_prg_banks();
+            },
+            0x9000..=0xB002 => self.audio.write_register(register, value),
+            0xB003 => {
+                // Bits 0-1 select the CHR banking mode and bit 4 ROM
+                // nametables, neither of which games use. Bit 7 is the PRG
+                // RAM enable, which is left always on.
+                if value & 0x13 != 0 {
+                    warn!("VRC6: unsupported PPU banking mode {:02X}", value);
+                }
+
+                self.mirroring = match (value >> 2) & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreen0,
+                    _ => Mirroring::OneScreen1,
+                };
+
+                self.ppu_mem.set_mirroring(self.mirroring);
+            },
+            0xC000..=0xC003 => {
+                self.prg_bank_8k = value & 0x1F;
+                self.update_prg_banks();
+            },
+            0xD000..=0xE003 => {
+                let bank = (((register >> 12) - 0xD) * 4 + (register & 0x03)) as usize;
+                self.chr_banks[bank] = value;
+                self.update_chr_bank(bank);
+            },
+            0xF000 => self.irq.write_latch(value),
+            0xF001 => self.irq.write_control(value),
+            0xF002 => self.irq.acknowledge(),
+            _ => (),
+        }
+    }
+
+    /// Load the switchable PRG ROM banks. The last 8KB bank is fixed at
+    /// $E000.
+    fn update_prg_banks(&mut self) {
+        let num_banks = self.prg_rom_banks.len();
+
+        let bank_8000 = (self.prg_bank_16k as usize * 2) % num_banks;
+        let bank_c000 = self.prg_bank_8k as usize % num_banks;
+
+        self.cpu_mem.load(0x8000, &self.prg_rom_banks[bank_8000]);
+        self.cpu_mem.load(0xA000, &self.prg_rom_banks[bank_8000 + 1]);
+        self.cpu_mem.load(0xC000, &self.prg_rom_banks[bank_c000]);
+        self.cpu_mem.load(0xE000, &self.prg_rom_banks[num_banks - 1]);
+    }
+
+    fn update_chr_bank(&mut self, bank: usize) {
+        let number = self.chr_banks[bank] as usize % self.chr_rom_banks.len();
+        self.ppu_mem.load((bank * ONE_KB) as u16, &self.chr_rom_banks[number]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappers::test_rom;
+
+    /// VRC6 mapper with 8 x 8KB PRG ROM banks and 16 x 1KB CHR ROM banks.
+    fn test_mapper(number: u16) -> Vrc6Mapper {
+        let mut mapper = new(number, Memory::new_cpu(), PpuMemory::new());
+        mapper.load_rom(&test_rom(number, 0, 8, 16)).unwrap();
+        mapper
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = test_mapper(24);
+
+        mapper.cpu_write(0x8000, 2);
+        mapper.cpu_write(0xC000, 5);
+        assert!(mapper.cpu_read(0x8000) == 4);
+        assert!(mapper.cpu_read(0xA000) == 5);
+        assert!(mapper.cpu_read(0xC000) == 5);
+        assert!(mapper.cpu_read(0xE000) == 7);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut mapper = test_mapper(24);
+
+        mapper.cpu_write(0xD001, 9);
+        mapper.cpu_write(0xE003, 12);
+        assert!(mapper.ppu_read(0x0400) == 9);
+        assert!(mapper.ppu_read(0x1C00) == 12);
+
+        // VRC6b swaps A0 and A1
+        let mut mapper = test_mapper(26);
+        mapper.cpu_write(0xD001, 9);
+        assert!(mapper.ppu_read(0x0800) == 9);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mapper = test_mapper(24);
+
+        mapper.cpu_write(0xB003, 0x24);
+        mapper.ppu_write(0x2000, 0x11);
+        assert!(mapper.ppu_read(0x2400) == 0x11);
+        assert!(mapper.ppu_read(0x2800) == 0);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = test_mapper(24);
+
+        // Latch 0xFE in cycle mode
+        mapper.cpu_write(0xF000, 0xFE);
+        mapper.cpu_write(0xF001, 0x06);
+
+        mapper.cpu_cycle();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_cycle();
+        assert!(mapper.irq_pending());
+
+        mapper.cpu_write(0xF002, 0);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_audio_sample() {
+        let mut mapper = test_mapper(26);
+        assert!(mapper.audio_sample() == 0.0);
+
+        // Pulse 1 in constant mode at volume 15 ($9002 is $9001 on VRC6b)
+        mapper.cpu_write(0x9000, 0x8F);
+        mapper.cpu_write(0x9001, 0x80);
+        mapper.cpu_cycle();
+        assert!(mapper.audio_sample() > 0.0);
+    }
+}
@@ -1,9 +1,22 @@
 pub mod m000_nrom;
 pub mod m001_mmc1;
 pub mod m002_unrom;
+pub mod m003_cnrom;
+pub mod m004_mmc3;
+pub mod m007_axrom;
+pub mod m009_mmc2;
+pub mod m021_vrc4;
+pub mod m024_vrc6;
+pub mod vrc6_audio;
+pub mod vrc_irq;
 
-use crate::ines::InesRom;
+use crate::ines::{InesRom, RomError, CHR_ROM_CHUNK_SIZE};
 use crate::mem::{Memory, PpuMemory};
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::wram::WRam;
+
+/// CPU address that a rom's trainer is loaded to, in PRG RAM.
+pub const TRAINER_ADDR: u16 = 0x7000;
 
 ///
 /// Trait for implementing a mapper.
@@ -16,8 +29,9 @@ pub trait Mapper {
     /// Get this mapper's number/id.
     fn number(&self) -> u16;
 
-    /// Load a rom from an ines file object into memory.
-    fn load_rom(&mut self, ines: &InesRom);
+    /// Load a rom from an ines file object into memory. Fails if the mapper
+    /// does not support the rom's layout.
+    fn load_rom(&mut self, ines: &InesRom) -> Result<(), RomError>;
 
     /// Read from CPU memory.
     fn cpu_read(&mut self, addr: u16) -> u8;
@@ -34,6 +48,57 @@ pub trait Mapper {
     /// Write to PPU memory.
     fn ppu_write(&mut self, addr: u16, value: u8);
 
+    /// Read from PPU memory for a pattern table fetch made while rendering.
+    /// Mappers can react to what the PPU is fetching here (e.g. MMC2's CHR
+    /// latches). ppu_cycle is the PPU's total cycle count, for mappers that
+    /// watch the timing of PPU address lines (e.g. MMC3's A12 scanline
+    /// counter).
+    fn ppu_fetch(&mut self, addr: u16, _ppu_cycle: u64) -> u8 {
+        self.ppu_read(addr)
+    }
+
+    /// Called when the CPU puts an address on the PPU's address bus through
+    /// $2006 or $2007, on the given PPU cycle. Mappers watching PPU address
+    /// lines see these changes as well as the fetches made while rendering.
+    fn ppu_bus_address(&mut self, _addr: u16, _ppu_cycle: u64) {
+        // Default is to do nothing
+    }
+
+    /// Called once per CPU cycle, for mappers with timers clocked by the CPU
+    /// (e.g. VRC4's IRQ counter).
+    fn cpu_cycle(&mut self) {
+        // Default is to do nothing
+    }
+
+    /// Expansion audio output for the current CPU cycle, read once per CPU
+    /// cycle (after cpu_cycle) and mixed into the APU's output.
+    fn audio_sample(&self) -> f32 {
+        0.0
+    }
+
+    /// Largest value audio_sample can return. The mix is scaled down by this
+    /// much so the APU and expansion audio together stay within full scale.
+    fn audio_peak(&self) -> f32 {
+        0.0
+    }
+
+    /// Write mapper memory, banking and registers to a save state.
+    fn save_state(&self, writer: &mut StateWriter);
+
+    /// Restore mapper memory, banking and registers from a save state.
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+
+    /// True while the mapper is asserting the CPU's IRQ line.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Reset mapper registers as if the NES reset button was pressed. Most
+    /// mappers do not see the reset line, so the default does nothing.
+    fn reset(&mut self) {
+        // Default is to do nothing
+    }
+
     /// Perform any shutdown tasks (write wram file, etc).
     fn shutdown(&mut self) {
         // Default is to do nothing
@@ -45,15 +110,156 @@ pub trait Mapper {
     }
 }
 
+/// PRG RAM at $6000-$7FFF as given by the rom header: battery backed RAM if
+/// the rom has a battery, otherwise volatile work RAM of the header's PRG RAM
+/// size. None if the header gives neither.
+pub fn new_prg_ram(ines: &InesRom) -> Option<WRam> {
+    let header = &ines.header;
+
+    if header.flags6._has_battery_backed_prg_ram {
+        Some(WRam::new(&ines.rom_name, header.prg_nvram_size))
+    } else if header.prg_ram_size > 0 {
+        Some(WRam::new_volatile(header.prg_ram_size))
+    } else {
+        None
+    }
+}
+
+/// Load the rom's trainer, if it has one, at $7000: into PRG RAM if the
+/// mapper has any, otherwise into plain CPU memory.
+pub fn load_trainer(ines: &InesRom, wram: Option<&mut WRam>, cpu_mem: &mut Memory) {
+    if let Some(trainer) = &ines.trainer {
+        match wram {
+            Some(cart_wram) => cart_wram.load(TRAINER_ADDR, trainer),
+            None => cpu_mem.load(TRAINER_ADDR, trainer),
+        }
+    }
+}
+
+/// Check that the CHR RAM given by the rom header fits in the 8KB pattern
+/// tables, for mappers that don't bank CHR RAM. Battery backed CHR RAM is
+/// treated as volatile.
+pub fn check_chr_ram(mapper: &'static str, ines: &InesRom) -> Result<(), RomError> {
+    let size = ines.header.chr_ram_size + ines.header.chr_nvram_size;
+
+    if size > CHR_ROM_CHUNK_SIZE {
+        return Err(RomError::UnsupportedSize { mapper, section: "CHR RAM", size });
+    }
+
+    if ines.header.chr_nvram_size > 0 {
+        warn!("{mapper}: battery backed CHR RAM will not be saved");
+    }
+
+    Ok(())
+}
+
 /// Factory function to create a new mapper.
-/// The passed in mapper number determines the type of mapper returned.
-pub fn get_mapper(number: u16, cpu_mem: Memory, ppu_mem: PpuMemory) -> Box<dyn Mapper> {
-    match number {
+/// The passed in mapper number determines the type of mapper returned, and
+/// the NES 2.0 submapper number (0 if unknown) the board variant.
+pub fn get_mapper(number: u16, submapper: u8, cpu_mem: Memory, ppu_mem: PpuMemory) -> Result<Box<dyn Mapper>, RomError> {
+    let mapper: Box<dyn Mapper> = match number {
         //0 => Box::new(nrom000::NromMapper::new()),
         0 => Box::new(m000_nrom::new(cpu_mem, ppu_mem)),
         1 => Box::new(m001_mmc1::new(cpu_mem, ppu_mem)),
         2 => Box::new(m002_unrom::new(cpu_mem, ppu_mem)),
+        3 => Box::new(m003_cnrom::new(submapper, cpu_mem, ppu_mem)),
+        4 => Box::new(m004_mmc3::new(cpu_mem, ppu_mem)),
+        7 => Box::new(m007_axrom::new(submapper, cpu_mem, ppu_mem)),
+        9 => Box::new(m009_mmc2::new(cpu_mem, ppu_mem)),
+        10 => Box::new(m009_mmc2::new_mmc4(cpu_mem, ppu_mem)),
+        21 | 22 | 23 | 25 => Box::new(m021_vrc4::new(number, submapper, cpu_mem, ppu_mem)),
+        24 | 26 => Box::new(m024_vrc6::new(number, cpu_mem, ppu_mem)),
         71 => Box::new(m002_unrom::new(cpu_mem, ppu_mem)), // Same as mapper 002
-        _ => panic!("Unsupported mapper: {}", number),
+        _ => return Err(RomError::UnsupportedMapper(number)),
+    };
+
+    Ok(mapper)
+}
+
+///
+/// Build an NES 2.0 rom for mapper tests, with 8KB of PRG RAM and the given
+/// number of 8KB PRG ROM banks and 1KB CHR ROM banks. Each bank is filled
+/// with its bank number.
+///
+#[cfg(test)]
+pub(crate) fn test_rom(mapper: u16, submapper: u8, prg_8k_banks: u8, chr_1k_banks: u8) -> InesRom {
+    let mut buffer = vec![
+        0x4E, 0x45, 0x53, 0x1A,
+        prg_8k_banks / 2,
+        chr_1k_banks / 8,
+        (mapper as u8 & 0x0F) << 4,
+        (mapper as u8 & 0xF0) | 0x08,
+        (submapper << 4) | (mapper >> 8) as u8,
+        0,
+        0x07,
+        0, 0, 0, 0, 0,
+    ];
+
+    for bank in 0..prg_8k_banks {
+        buffer.extend_from_slice(&[bank; 8 * 1024]);
+    }
+
+    for bank in 0..chr_1k_banks {
+        buffer.extend_from_slice(&[bank; 1024]);
+    }
+
+    InesRom::from_buffer(format!("mapper{mapper}.nes"), &buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ines::TRAINER_SIZE;
+
+    #[test]
+    fn test_trainer_loaded() {
+        // Trainer, 16KB PRG ROM, 8KB CHR ROM
+        let mut buffer = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        buffer.extend((0..TRAINER_SIZE).map(|i| i as u8));
+        buffer.resize(16 + TRAINER_SIZE + 16384 + 8192, 0);
+
+        for number in [0, 1, 2] {
+            buffer[6] = (number << 4) | 0x04;
+            let rom = InesRom::from_buffer("trainer.nes".to_string(), &buffer).unwrap();
+
+            let mut mapper = get_mapper(number as u16, 0, Memory::new_cpu(), PpuMemory::new()).unwrap();
+            mapper.load_rom(&rom).unwrap();
+
+            assert!(mapper.cpu_read(TRAINER_ADDR) == 0x00);
+            assert!(mapper.cpu_read(TRAINER_ADDR + 0x1FF) == 0xFF);
+            assert!(mapper.cpu_read(TRAINER_ADDR + 0x200) == 0x00);
+        }
+    }
+
+    #[test]
+    fn test_prg_ram_size() {
+        // NES 2.0 MMC1 rom with 2KB of volatile PRG RAM, mirrored over
+        // $6000-$7FFF
+        let mut buffer = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x10, 0x08, 0, 0, 0x05, 0, 0, 0, 0, 0];
+        buffer.resize(16 + 32768 + 8192, 0);
+
+        let rom = InesRom::from_buffer("prg_ram.nes".to_string(), &buffer).unwrap();
+        assert!(rom.header.prg_ram_size == 2048);
+
+        let mut mapper = get_mapper(1, 0, Memory::new_cpu(), PpuMemory::new()).unwrap();
+        mapper.load_rom(&rom).unwrap();
+
+        mapper.cpu_write(0x6000, 0x42);
+        assert!(mapper.cpu_read(0x6800) == 0x42);
+        assert!(mapper.cpu_read(0x7800) == 0x42);
+    }
+
+    #[test]
+    fn test_chr_ram_too_large() {
+        // NES 2.0 UNROM rom with 32KB of CHR RAM
+        let mut buffer = vec![0x4E, 0x45, 0x53, 0x1A, 2, 0, 0x20, 0x08, 0, 0, 0, 0x09, 0, 0, 0, 0];
+        buffer.resize(16 + 32768, 0);
+
+        let rom = InesRom::from_buffer("chr_ram.nes".to_string(), &buffer).unwrap();
+
+        let mut mapper = get_mapper(2, 0, Memory::new_cpu(), PpuMemory::new()).unwrap();
+        let result = mapper.load_rom(&rom);
+
+        assert!(matches!(result, Err(RomError::UnsupportedSize { section: "CHR RAM", size: 32768, .. })));
     }
 }
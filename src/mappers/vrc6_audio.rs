@@ -0,0 +1,346 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::utils::bit_is_set;
+
+/// Output level of one step of channel volume, relative to the APU mix. A
+/// full volume VRC6 pulse is about as loud as a full volume APU pulse (0.149
+/// in the APU's pulse table), so one of its 15 steps is about 0.01.
+/// See: https://www.nesdev.org/wiki/VRC6_audio
+const OUTPUT_STEP: f32 = 0.01;
+
+/// Largest output of the three channels together: two pulses at volume 15
+/// and the sawtooth at 31.
+pub const MAX_OUTPUT: f32 = 61.0 * OUTPUT_STEP;
+
+///
+/// Timer shared by the VRC6 channels. Counts down every CPU cycle and clocks
+/// its channel when it reloads.
+///
+#[derive(Default)]
+struct Timer {
+    enabled: bool,
+
+    /// 12-bit reload value.
+    period: u16,
+    counter: u16,
+}
+
+impl Timer {
+    /// $x001: PPPP PPPP (period low bits)
+    fn write_period_low(&mut self, value: u8) {
+        self.period = (self.period & 0x0F00) | value as u16;
+    }
+
+    /// $x002: E... PPPP
+    /// Enabled (E), period high bits (P).
+    fn write_period_high(&mut self, value: u8) {
+        self.period = (self.period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+        self.enabled = bit_is_set(7, value);
+    }
+
+    /// Run the timer for one CPU cycle, with the period shifted right by
+    /// the frequency scaling in $9003. Returns true when the channel should
+    /// be clocked.
+    fn cycle(&mut self, shift: u8) -> bool {
+        if self.counter == 0 {
+            self.counter = self.period >> shift;
+            true
+        } else {
+            self.counter -= 1;
+            false
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.period);
+        writer.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.period = reader.read_u16()?;
+        self.counter = reader.read_u16()?;
+
+        Ok(())
+    }
+}
+
+///
+/// VRC6 pulse channel. A 16-step sequence with a selectable duty cycle and
+/// a 4-bit volume, with no envelope, sweep or length counter.
+///
+#[derive(Default)]
+struct Pulse {
+    /// Output volume on every step, ignoring the duty cycle.
+    constant: bool,
+
+    /// The channel outputs its volume on steps 0 to duty.
+    duty: u8,
+    volume: u8,
+
+    /// Current position in the 16-step sequence, counting down.
+    step: u8,
+
+    timer: Timer,
+}
+
+impl Pulse {
+    /// $9000/$A000: MDDD VVVV
+    /// Constant mode (M), duty cycle (D), volume (V).
+    fn write_control(&mut self, value: u8) {
+        self.constant = bit_is_set(7, value);
+        self.duty = (value >> 4) & 0x07;
+        self.volume = value & 0x0F;
+    }
+
+    fn write_period_high(&mut self, value: u8) {
+        self.timer.write_period_high(value);
+
+        // Disabling the channel resets the sequence
+        if !self.timer.enabled {
+            self.step = 15;
+        }
+    }
+
+    fn cycle(&mut self, shift: u8) {
+        if self.timer.enabled && self.timer.cycle(shift) {
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.timer.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.constant);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.step);
+        self.timer.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.constant = reader.read_bool()?;
+        self.duty = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        self.step = reader.read_u8()?;
+        self.timer.load_state(reader)
+    }
+}
+
+///
+/// VRC6 sawtooth channel. An 8-bit accumulator has the rate added to it on
+/// every other clock and is reset on the 14th clock. The top 5 bits are
+/// output.
+///
+#[derive(Default)]
+struct Sawtooth {
+    /// 6-bit value added to the accumulator.
+    rate: u8,
+    accumulator: u8,
+
+    /// Clocks since the accumulator was reset (0-13).
+    step: u8,
+
+    timer: Timer,
+}
+
+impl Sawtooth {
+    /// $B000: ..AA AAAA
+    /// Accumulator rate (A).
+    fn write_rate(&mut self, value: u8) {
+        self.rate = value & 0x3F;
+    }
+
+    fn write_period_high(&mut self, value: u8) {
+        self.timer.write_period_high(value);
+
+        if !self.timer.enabled {
+            self.accumulator = 0;
+            self.step = 0;
+        }
+    }
+
+    fn cycle(&mut self, shift: u8) {
+        if !self.timer.enabled || !self.timer.cycle(shift) {
+            return;
+        }
+
+        self.step += 1;
+
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.rate);
+        writer.write_u8(self.accumulator);
+        writer.write_u8(self.step);
+        self.timer.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.rate = reader.read_u8()?;
+        self.accumulator = reader.read_u8()?;
+        self.step = reader.read_u8()?;
+        self.timer.load_state(reader)
+    }
+}
+
+///
+/// VRC6 expansion audio: two pulse channels and a sawtooth channel, clocked
+/// by the CPU.
+/// See: https://www.nesdev.org/wiki/VRC6_audio
+///
+#[derive(Default)]
+pub struct Vrc6Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    sawtooth: Sawtooth,
+
+    /// Stop all channel timers ($9003 bit 0).
+    halt: bool,
+
+    /// Right shift applied to all channel periods ($9003 bits 1-2).
+    frequency_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write an audio register. register is $9000-$9003, $A000-$A002 or
+    /// $B000-$B002, with the board's address lines already translated.
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0x9000 => self.pulse1.write_control(value),
+            0x9001 => self.pulse1.timer.write_period_low(value),
+            0x9002 => self.pulse1.write_period_high(value),
+            0x9003 => {
+                self.halt = bit_is_set(0, value);
+
+                // 256x takes priority over 16x
+                self.frequency_shift = if bit_is_set(2, value) {
+                    8
+                } else if bit_is_set(1, value) {
+                    4
+                } else {
+                    0
+                };
+            },
+            0xA000 => self.pulse2.write_control(value),
+            0xA001 => self.pulse2.timer.write_period_low(value),
+            0xA002 => self.pulse2.write_period_high(value),
+            0xB000 => self.sawtooth.write_rate(value),
+            0xB001 => self.sawtooth.timer.write_period_low(value),
+            0xB002 => self.sawtooth.write_period_high(value),
+            _ => (),
+        }
+    }
+
+    /// Run the channels for one CPU cycle.
+    pub fn cycle(&mut self) {
+        if self.halt {
+            return;
+        }
+
+        self.pulse1.cycle(self.frequency_shift);
+        self.pulse2.cycle(self.frequency_shift);
+        self.sawtooth.cycle(self.frequency_shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        let sum = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        sum as f32 * OUTPUT_STEP
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.pulse1.save_state(writer);
+        self.pulse2.save_state(writer);
+        self.sawtooth.save_state(writer);
+        writer.write_bool(self.halt);
+        writer.write_u8(self.frequency_shift);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.pulse1.load_state(reader)?;
+        self.pulse2.load_state(reader)?;
+        self.sawtooth.load_state(reader)?;
+        self.halt = reader.read_bool()?;
+        self.frequency_shift = reader.read_u8()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut audio = Vrc6Audio::new();
+
+        // Volume 10, duty 3 (4/16), period 0 so every cycle steps
+        audio.write_register(0x9000, 0x3A);
+        audio.write_register(0x9001, 0x00);
+        audio.write_register(0x9002, 0x80);
+
+        let mut high_steps = 0;
+
+        for _ in 0..16 {
+            audio.cycle();
+
+            if audio.output() > 0.0 {
+                assert!(audio.output() == 10.0 * OUTPUT_STEP);
+                high_steps += 1;
+            }
+        }
+
+        assert!(high_steps == 4);
+
+        // Disabling silences the channel
+        audio.write_register(0x9002, 0x00);
+        assert!(audio.output() == 0.0);
+    }
+
+    #[test]
+    fn test_sawtooth() {
+        let mut audio = Vrc6Audio::new();
+
+        audio.write_register(0xB000, 42);
+        audio.write_register(0xB002, 0x80);
+
+        let mut outputs = Vec::new();
+
+        for _ in 0..14 {
+            audio.cycle();
+            outputs.push(audio.sawtooth.output());
+        }
+
+        // 6 additions of 42, then reset on the 14th clock
+        assert!(outputs[11] == (42 * 6) >> 3);
+        assert!(outputs[12] == (42 * 6) >> 3);
+        assert!(outputs[13] == 0);
+
+        // Halt stops the channels
+        audio.write_register(0x9003, 0x01);
+        audio.cycle();
+        audio.cycle();
+        assert!(audio.sawtooth.output() == 0);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::utils::bit_is_set;
+
+/// CPU cycles per scanline, times 3 so the prescaler can count in whole
+/// PPU cycles.
+const PRESCALER_RELOAD: i16 = 341;
+
+///
+/// IRQ counter shared by Konami's VRC4, VRC6 and VRC7. An 8-bit counter
+/// counts up from a latched value and raises an IRQ when it overflows. It is
+/// clocked either every CPU cycle, or once per scanline by a prescaler that
+/// divides CPU cycles by 113.667.
+/// See: https://www.nesdev.org/wiki/VRC_IRQ
+///
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+
+    /// Value enabled is set to when the IRQ is acknowledged.
+    enable_after_ack: bool,
+    enabled: bool,
+
+    /// Clock the counter every CPU cycle instead of every scanline.
+    cycle_mode: bool,
+
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        Self {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_RELOAD,
+            enable_after_ack: false,
+            enabled: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    /// Write the low 4 bits of the latch (VRC4 splits it over 2 registers).
+    pub fn write_latch_low(&mut self, value: u8) {
+        self.latch = (self.latch & 0xF0) | (value & 0x0F);
+    }
+
+    /// Write the high 4 bits of the latch.
+    pub fn write_latch_high(&mut self, value: u8) {
+        self.latch = (self.latch & 0x0F) | (value << 4);
+    }
+
+    /// Write the control register. Acknowledges any pending IRQ, and
+    /// reloads the counter if the IRQ is being enabled.
+    pub fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = bit_is_set(0, value);
+        self.enabled = bit_is_set(1, value);
+        self.cycle_mode = bit_is_set(2, value);
+
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_RELOAD;
+        }
+
+        self.pending = false;
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Run the counter for one CPU cycle.
+    pub fn cpu_cycle(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_RELOAD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.latch);
+        writer.write_u8(self.counter);
+        writer.write_u16(self.prescaler as u16);
+        writer.write_bool(self.enable_after_ack);
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.cycle_mode);
+        writer.write_bool(self.pending);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.latch = reader.read_u8()?;
+        self.counter = reader.read_u8()?;
+        self.prescaler = reader.read_u16()? as i16;
+        self.enable_after_ack = reader.read_bool()?;
+        self.enabled = reader.read_bool()?;
+        self.cycle_mode = reader.read_bool()?;
+        self.pending = reader.read_bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_mode() {
+        let mut irq = VrcIrq::new();
+
+        irq.write_latch_low(0x0D);
+        irq.write_latch_high(0x0F);
+        irq.write_control(0x06);
+
+        // 0xFD -> 0xFE -> 0xFF -> overflow
+        irq.cpu_cycle();
+        irq.cpu_cycle();
+        assert!(!irq.pending());
+        irq.cpu_cycle();
+        assert!(irq.pending());
+
+        // Acknowledging disables the IRQ unless enable after ack is set
+        irq.acknowledge();
+        assert!(!irq.pending());
+
+        for _ in 0..10 {
+            irq.cpu_cycle();
+        }
+
+        assert!(!irq.pending());
+    }
+
+    #[test]
+    fn test_scanline_mode() {
+        let mut irq = VrcIrq::new();
+
+        irq.write_latch_low(0x0E);
+        irq.write_latch_high(0x0F);
+        irq.write_control(0x03);
+
+        // Two scanlines (0xFE -> 0xFF -> overflow) take 227.33 CPU cycles
+        for _ in 0..227 {
+            irq.cpu_cycle();
+        }
+
+        assert!(!irq.pending());
+        irq.cpu_cycle();
+        assert!(irq.pending());
+
+        // Enable after ack keeps the IRQ running
+        irq.acknowledge();
+        assert!(!irq.pending());
+
+        for _ in 0..114 * 256 {
+            irq.cpu_cycle();
+        }
+
+        assert!(irq.pending());
+    }
+}
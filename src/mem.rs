@@ -1,4 +1,5 @@
 use crate::ppu::constants::*;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
 //const PAGE_SIZE: u16 = 256;
 //const MEMORY_SIZE: usize = 1024 * 64;
@@ -81,6 +82,14 @@ impl Memory {
 
         &self.mem[start .. end]
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.mem);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes_into(&mut self.mem)
+    }
 }
 
 
@@ -114,6 +123,22 @@ impl PpuMemory {
         self.mirroring = mode;
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.mem);
+        writer.write_bytes(&self.vram_bank0);
+        writer.write_bytes(&self.vram_bank1);
+        self.mirroring.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes_into(&mut self.mem)?;
+        reader.read_bytes_into(&mut self.vram_bank0)?;
+        reader.read_bytes_into(&mut self.vram_bank1)?;
+        self.mirroring = Mirroring::load_state(reader)?;
+
+        Ok(())
+    }
+
     /// Utility function for mappers that ensures ppu address is properly wrapped
     /// at 0x4000 and mirrored.
     fn get_effective_address(&self, addr: u16) -> u16 {
@@ -0,0 +1,507 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::apu::Apu;
+use crate::audio::Resampler;
+use crate::cpu::Cpu;
+use crate::ines::{InesRom, Region, RomError};
+use crate::mappers;
+use crate::mem::{Memory, PpuMemory};
+use crate::palette::PALETTE;
+use crate::ppu::{Ppu, PpuCycleResult};
+use crate::savestate::{self, SaveStateError};
+use crate::state::NesState;
+use crate::timing::Timing;
+
+pub const MASTER_CLOCK_HZ: u64 = 21_441_960;
+pub const CLOCK_DIVISOR: u64 = 12;
+pub const CPU_FREQ: u64 = MASTER_CLOCK_HZ / CLOCK_DIVISOR;
+pub const CPU_CYCLES_PER_FRAME: u64 = CPU_FREQ / 60;
+pub const NS_PER_CYCLE: u64 = (1.0 / CPU_FREQ as f64 * 1e9) as u64;
+
+/// Width of the frame output by the PPU, in pixels.
+pub const FRAME_WIDTH: usize = 256;
+
+/// Height of the frame output by the PPU, in pixels (including overscan).
+pub const FRAME_HEIGHT: usize = 240;
+
+/// Audio sample rate used until set_audio_sample_rate is called.
+const DEFAULT_AUDIO_SAMPLE_RATE: u32 = 48_000;
+
+/// Controller button masks, for use with Nes::set_input.
+pub const A_BUTTON_MASK: u8 = 1;
+pub const B_BUTTON_MASK: u8 = 2;
+pub const SELECT_BUTTON_MASK: u8 = 4;
+pub const START_BUTTON_MASK: u8 = 8;
+pub const UP_BUTTON_MASK: u8 = 16;
+pub const DOWN_BUTTON_MASK: u8 = 32;
+pub const LEFT_BUTTON_MASK: u8 = 64;
+pub const RIGHT_BUTTON_MASK: u8 = 128;
+
+/// Controller port, for use with Nes::set_input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    One,
+    Two,
+}
+
+/// CPU, memory/state and the PPU and APU it shares, as created by power on.
+type Components = (Cpu, NesState, Rc<RefCell<Ppu>>, Rc<RefCell<Apu>>);
+
+///
+/// A complete NES with a cartridge inserted. This is the interface used by
+/// frontends (and tools/tests) to run the emulator. It does no video, audio
+/// or input handling of its own: the frontend reads the frame buffer and
+/// audio samples after each frame and supplies controller state.
+///
+pub struct Nes {
+    cpu: Cpu,
+    state: NesState,
+    ppu: Rc<RefCell<Ppu>>,
+    apu: Rc<RefCell<Apu>>,
+
+    /// Clock rates and frame layout of the rom's region.
+    timing: Timing,
+
+    /// PPU cycles owed from previous steps, in 1/denominator PPU cycles,
+    /// when the CPU/PPU clock ratio is not a whole number (PAL).
+    ppu_cycle_remainder: u64,
+
+    /// Converts APU output to the frontend's audio sample rate.
+    resampler: Resampler,
+
+    /// The inserted rom. Kept to reload the cartridge on power cycle.
+    rom: InesRom,
+
+    /// Most recently rendered frame, as RGB24 pixels.
+    frame_buffer: Vec<u8>,
+
+    /// CPU cycle the next step runs to.
+    cycle: u64,
+
+    /// Number of CPU cycles to run per step.
+    cycle_batch: u64,
+
+    /// Number of frames completed.
+    frame_count: u64,
+}
+
+impl Nes {
+    /// Create a NES with the ines rom at the given path inserted.
+    pub fn load_rom(path: &Path) -> Result<Self, RomError> {
+        Self::from_rom(InesRom::from_path(path)?)
+    }
+
+    /// Create a NES with the given rom inserted. Fails if the rom's mapper
+    /// is not supported.
+    pub fn from_rom(rom: InesRom) -> Result<Self, RomError> {
+        let timing = Timing::for_region(rom.header.region);
+        let (cpu, state, ppu, apu) = Self::power_on(&rom, timing, false)?;
+
+        Ok(Self {
+            cycle: cpu.cycle_count(),
+            cpu,
+            state,
+            ppu,
+            apu,
+            timing,
+            ppu_cycle_remainder: 0,
+            resampler: Resampler::new(timing.cpu_freq as f64, DEFAULT_AUDIO_SAMPLE_RATE as f64),
+            rom,
+            frame_buffer: vec![0; FRAME_WIDTH * FRAME_HEIGHT * 3],
+            cycle_batch: 1,
+            frame_count: 0,
+        })
+    }
+
+    /// Build the machine in its power on state, with RAM cleared and the
+    /// rom loaded by a freshly initialized mapper.
+    fn power_on(rom: &InesRom, timing: Timing, trace_cpu: bool) -> Result<Components, RomError> {
+        // Init mapper and load rom
+        let mut mapper = mappers::get_mapper(
+            rom.get_mapper_number(), rom.get_submapper_number(), Memory::new_cpu(), PpuMemory::new())?;
+
+        mapper.print_info();
+        mapper.load_rom(rom)?;
+
+        // Init state object
+        let ppu = Rc::new(RefCell::new(Ppu::new(timing)));
+        let apu = Rc::new(RefCell::new(Apu::new(timing)));
+        let mut state = NesState::new(mapper, Rc::clone(&ppu), Rc::clone(&apu));
+
+        let cpu = Cpu::new(&mut state, trace_cpu);
+
+        info!("reset vector: {:04X}", state.cpu_mem_read_word(0xFFFC));
+
+        Ok((cpu, state, ppu, apu))
+    }
+
+    /// File name of the loaded rom.
+    pub fn rom_name(&self) -> &str {
+        &self.rom.rom_name
+    }
+
+    /// CPU/PPU timing the loaded rom expects.
+    pub fn region(&self) -> Region {
+        self.rom.header.region
+    }
+
+    /// Clock rates and frame layout the NES is running with.
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// Enable trace logging of CPU execution.
+    pub fn set_trace_cpu(&mut self, trace_cpu: bool) {
+        self.cpu.set_trace_cpu(trace_cpu);
+    }
+
+    /// Sets the program counter to the given value (for debug/testing).
+    pub fn set_program_counter(&mut self, addr: u16) {
+        self.cpu.set_program_counter(addr);
+    }
+
+    /// Set the number of CPU cycles run per step. Not recommended to change
+    /// from the default of 1.
+    pub fn set_cycle_batch(&mut self, cycle_batch: u64) {
+        self.cycle_batch = cycle_batch.max(1);
+    }
+
+    /// Set the sample rate of the samples returned by take_audio_samples.
+    /// Any samples not yet taken are discarded.
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(self.timing.cpu_freq as f64, sample_rate as f64);
+    }
+
+    /// Set the buttons held on the controller in the given port.
+    /// See the *_BUTTON_MASK constants.
+    pub fn set_input(&mut self, port: Port, buttons: u8) {
+        match port {
+            Port::One => self.state.set_controller1_state(buttons),
+            Port::Two => self.state.set_controller2_state(buttons),
+        }
+    }
+
+    ///
+    /// Run the CPU for the next batch of cycles, along with the PPU and APU
+    /// cycles that happen in the same time. Returns true if a frame was
+    /// completed during the step.
+    ///
+    pub fn step(&mut self) -> bool {
+        self.cycle += self.cycle_batch;
+
+        let cpu_cycles_used = self.cpu.cycle_to(&mut self.state, self.cycle);
+
+        let mut apu = self.apu.borrow_mut();
+        let mapper_audio_peak = self.state.mapper_audio_peak();
+
+        for _ in 0..cpu_cycles_used {
+            apu.cycle(&mut self.state);
+            self.state.mapper_cpu_cycle();
+
+            let sample = mix_audio(apu.output(), self.state.mapper_audio_sample(), mapper_audio_peak);
+            self.resampler.push(sample);
+        }
+
+        drop(apu);
+
+        let mut ppu = self.ppu.borrow_mut();
+        let mut frame_completed = false;
+
+        let (ppu_cycles, cpu_cycles) = self.timing.ppu_cycles_per_cpu_cycle;
+        let owed = cpu_cycles_used * ppu_cycles + self.ppu_cycle_remainder;
+        self.ppu_cycle_remainder = owed % cpu_cycles;
+
+        for _ in 0..owed / cpu_cycles {
+            match ppu.cycle(&mut self.state) {
+                PpuCycleResult::Idle => (),
+                PpuCycleResult::Pixel { scanline, x, color } => {
+                    set_pixel(&mut self.frame_buffer, x, scanline, color);
+                },
+                PpuCycleResult::HBlank => (),
+                PpuCycleResult::PostRenderLine => (),
+                PpuCycleResult::VBlankLine { trigger_nmi, scanline } => {
+                    if trigger_nmi {
+                        self.cpu.set_nmi_flag();
+                        trace!("VBlank: nmi triggered at scanline {scanline}");
+                    }
+                },
+                PpuCycleResult::PreRenderLine { scanline_cycle } => {
+                    // Prerender line scanline cycle 2 marks the end of a frame
+                    if scanline_cycle == 2 {
+                        self.frame_count += 1;
+                        frame_completed = true;
+                    }
+                },
+            }
+        }
+
+        frame_completed
+    }
+
+    ///
+    /// Press the reset button. The CPU jumps through the reset vector, the
+    /// APU is silenced and the PPU and mapper are reset. RAM is left alone.
+    /// See: https://www.nesdev.org/wiki/CPU_power_up_state
+    ///
+    pub fn reset(&mut self) {
+        self.state.reset(self.cpu.cycle_count());
+        self.cpu.reset(&mut self.state);
+        self.cycle = self.cpu.cycle_count();
+    }
+
+    ///
+    /// Turn the power off and back on. Everything is reinitialized, except
+    /// battery backed WRAM which is written out and loaded back in by the
+    /// new mapper. Fails if the new mapper can't load the rom.
+    ///
+    pub fn power_cycle(&mut self) -> Result<(), RomError> {
+        self.state.shutdown();
+
+        let (cpu, state, ppu, apu) = Self::power_on(&self.rom, self.timing, self.cpu.trace_cpu())?;
+
+        self.cycle = cpu.cycle_count();
+        self.cpu = cpu;
+        self.state = state;
+        self.ppu = ppu;
+        self.apu = apu;
+        self.ppu_cycle_remainder = 0;
+        self.frame_buffer.fill(0);
+
+        Ok(())
+    }
+
+    /// Run until the current frame is completed.
+    pub fn run_frame(&mut self) {
+        while !self.step() {}
+    }
+
+    ///
+    /// Run until the PPU moves on to the next scanline. Returns true if a
+    /// frame was completed along the way. Steps that include a DMA stall can
+    /// carry the PPU over several scanlines.
+    ///
+    pub fn run_scanline(&mut self) -> bool {
+        let scanline = self.scanline();
+        let mut frame_completed = false;
+
+        while self.scanline() == scanline {
+            frame_completed |= self.step();
+        }
+
+        frame_completed
+    }
+
+    /// Scanline the PPU is currently rendering. The last scanline of the
+    /// frame (261 on NTSC) is the prerender line.
+    pub fn scanline(&self) -> u16 {
+        self.ppu.borrow().scanline()
+    }
+
+    /// Most recently rendered frame, as FRAME_WIDTH x FRAME_HEIGHT RGB24
+    /// pixels.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame_buffer
+    }
+
+    /// Number of frames completed.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Copy of the 2KB of internal CPU RAM ($0000-$07FF).
+    pub fn ram(&self) -> Vec<u8> {
+        self.state.cpu_ram()
+    }
+
+    /// Current CPU cycle count.
+    pub fn cpu_cycle_count(&self) -> u64 {
+        self.cpu.cycle_count()
+    }
+
+    /// Take all audio samples (mono, at the audio sample rate) produced since
+    /// the last call.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.resampler.take_samples()
+    }
+
+    /// Dynamic rate control. See Resampler::adjust_rate.
+    pub fn adjust_audio_rate(&mut self, buffer_fill: f64) {
+        self.resampler.adjust_rate(buffer_fill);
+    }
+
+    /// Create a save state of the whole machine.
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::save(&self.cpu, &self.state)
+    }
+
+    /// Restore the whole machine from a save state. If the state cannot be
+    /// restored the machine is left as it was before the call.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        savestate::load(data, &mut self.cpu, &mut self.state)?;
+        self.cycle = self.cpu.cycle_count();
+
+        Ok(())
+    }
+
+    /// Save the machine state to the given slot's file.
+    pub fn save_state_to_slot(&self, slot: u8) -> Result<PathBuf, SaveStateError> {
+        savestate::save_to_slot(&self.rom.rom_name, slot, &self.cpu, &self.state)
+    }
+
+    /// Restore the machine state from the given slot's file.
+    pub fn load_state_from_slot(&mut self, slot: u8) -> Result<PathBuf, SaveStateError> {
+        let path = savestate::load_from_slot(&self.rom.rom_name, slot, &mut self.cpu, &mut self.state)?;
+        self.cycle = self.cpu.cycle_count();
+
+        Ok(path)
+    }
+
+    /// Perform any shutdown related tasks/bookkeeping (such as saving
+    /// battery backed RAM).
+    pub fn shutdown(&mut self) {
+        self.state.shutdown();
+    }
+}
+
+/// Mix the APU's output (0.0-1.0) with the mapper's expansion audio. The sum
+/// is scaled down by the expansion audio's peak level so it stays within
+/// full scale.
+fn mix_audio(apu_sample: f32, mapper_sample: f32, mapper_peak: f32) -> f32 {
+    (apu_sample + mapper_sample) / (1.0 + mapper_peak)
+}
+
+fn set_pixel(frame_buffer: &mut [u8], x: u16, y: u16, color: u8) {
+    let index = (y as usize * FRAME_WIDTH + x as usize) * 3;
+
+    // Mod value by 64 because some games rely on the palette value wrapping
+    // after 64
+    let rgb = PALETTE[(color % 64) as usize];
+    frame_buffer[index..index + 3].copy_from_slice(&rgb);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_frame() {
+        let mut nes = Nes::load_rom(Path::new("./nestest/nestest.nes")).unwrap();
+
+        for _ in 0..10 {
+            nes.run_frame();
+        }
+
+        assert!(nes.frame_count() == 10);
+        assert!(nes.frame_buffer().len() == FRAME_WIDTH * FRAME_HEIGHT * 3);
+
+        // nestest draws its menu, so the frame isn't a single color
+        let first_pixel = &nes.frame_buffer()[0..3];
+        assert!(nes.frame_buffer().chunks(3).any(|pixel| pixel != first_pixel));
+
+        // About 800 samples per frame at 48kHz
+        let samples = nes.take_audio_samples();
+        assert!(samples.len() > 7000 && samples.len() < 8100);
+    }
+
+    #[test]
+    fn test_run_scanline() {
+        let mut nes = Nes::load_rom(Path::new("./nestest/nestest.nes")).unwrap();
+        nes.run_frame();
+
+        // Frames end early in the prerender line
+        assert!(nes.scanline() == 261);
+
+        assert!(!nes.run_scanline());
+        assert!(nes.scanline() == 0);
+
+        // Running scanlines eventually completes the next frame, leaving the
+        // PPU back on the prerender line
+        let mut scanline = nes.scanline();
+
+        while !nes.run_scanline() {
+            assert!(nes.scanline() != scanline);
+            scanline = nes.scanline();
+        }
+
+        assert!(nes.scanline() == 261);
+        assert!(nes.frame_count() == 2);
+    }
+
+    #[test]
+    fn test_reset_keeps_ram() {
+        let mut nes = Nes::load_rom(Path::new("./nestest/nestest.nes")).unwrap();
+
+        for _ in 0..10 {
+            nes.run_frame();
+        }
+
+        let ram = nes.ram();
+        let cycle = nes.cpu_cycle_count();
+
+        nes.reset();
+        assert!(nes.ram() == ram);
+        assert!(nes.cpu_cycle_count() == cycle + 7);
+
+        nes.run_frame();
+        assert!(nes.frame_count() == 11);
+    }
+
+    #[test]
+    fn test_power_cycle_clears_ram() {
+        let mut nes = Nes::load_rom(Path::new("./nestest/nestest.nes")).unwrap();
+
+        for _ in 0..10 {
+            nes.run_frame();
+        }
+
+        assert!(nes.ram().iter().any(|&value| value != 0));
+
+        nes.power_cycle().unwrap();
+        assert!(nes.ram().iter().all(|&value| value == 0));
+        assert!(nes.cpu_cycle_count() == 7);
+    }
+
+    #[test]
+    fn test_mix_audio_in_range() {
+        let mut mapper = mappers::get_mapper(24, 0, Memory::new_cpu(), PpuMemory::new()).unwrap();
+        mapper.load_rom(&mappers::test_rom(24, 0, 16, 8)).unwrap();
+
+        // Both pulses at volume 15 with a 100% duty cycle, and the sawtooth
+        // accumulating up to its peak of 31
+        mapper.cpu_write(0x9000, 0xFF);
+        mapper.cpu_write(0x9002, 0x80);
+        mapper.cpu_write(0xA000, 0xFF);
+        mapper.cpu_write(0xA002, 0x80);
+        mapper.cpu_write(0xB000, 42);
+        mapper.cpu_write(0xB002, 0x80);
+
+        let mut loudest: f32 = 0.0;
+
+        for _ in 0..64 {
+            mapper.cpu_cycle();
+            loudest = loudest.max(mapper.audio_sample());
+        }
+        assert!(loudest == mapper.audio_peak());
+
+        // A full scale APU output mixed with the loudest VRC6 output
+        for apu_sample in [0.0, 0.5, 1.0] {
+            let sample = mix_audio(apu_sample, loudest, mapper.audio_peak());
+            assert!((-1.0..=1.0).contains(&sample));
+        }
+
+        // Without expansion audio the APU output is left alone
+        assert!(mix_audio(0.5, 0.0, 0.0) == 0.5);
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        // Mapper 15, 16KB PRG ROM, 8KB CHR ROM
+        let mut buffer = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0xF0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        buffer.resize(16 + 16384 + 8192, 0);
+
+        let rom = InesRom::from_buffer("test.nes".to_string(), &buffer).unwrap();
+        assert!(matches!(Nes::from_rom(rom), Err(RomError::UnsupportedMapper(15))));
+    }
+}
@@ -0,0 +1,560 @@
+use std::fmt;
+
+use crate::ines::MAX_ROM_SIZE;
+
+/// Magic bytes at the start of each patch format.
+const IPS_MAGIC: &[u8] = b"PATCH";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// IPS records end with a record at this offset ("EOF").
+const IPS_EOF: usize = 0x454F46;
+
+/// Size of the source, target and patch CRC32 trailer on UPS and BPS patches.
+const CHECKSUM_TRAILER_SIZE: usize = 12;
+
+/// Patch file extensions looked for beside a rom, in order of preference.
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+///
+/// Errors that can occur while applying a patch.
+///
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    /// Patch does not start with a known magic number.
+    UnknownFormat,
+
+    /// Patch ended before all of its data was read.
+    UnexpectedEnd,
+
+    /// Patch refers to data outside of the rom or its own output.
+    OutOfBounds,
+
+    /// Patched rom would be larger than MAX_ROM_SIZE.
+    TargetTooLarge(usize),
+
+    /// Rom is not the size the patch was made for.
+    SourceSizeMismatch { expected: usize, actual: usize },
+
+    /// Rom is not the one the patch was made for.
+    SourceChecksumMismatch { expected: u32, actual: u32 },
+
+    /// Patched rom does not have the checksum the patch expects.
+    TargetChecksumMismatch { expected: u32, actual: u32 },
+
+    /// Patch file is corrupt.
+    PatchChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::UnexpectedEnd => write!(f, "patch data is truncated"),
+            PatchError::OutOfBounds => write!(f, "patch refers to data outside of the rom"),
+            PatchError::TargetTooLarge(size) => {
+                write!(f, "patched rom would be {size} bytes, more than the {MAX_ROM_SIZE} byte limit")
+            },
+            PatchError::SourceSizeMismatch { expected, actual } => {
+                write!(f, "patch is for a {expected} byte rom, but the rom is {actual} bytes")
+            },
+            PatchError::SourceChecksumMismatch { expected, actual } => {
+                write!(f, "patch is for a rom with CRC32 {expected:08X}, but the rom's CRC32 is {actual:08X}")
+            },
+            PatchError::TargetChecksumMismatch { expected, actual } => {
+                write!(f, "patched rom should have CRC32 {expected:08X}, but has {actual:08X}")
+            },
+            PatchError::PatchChecksumMismatch { expected, actual } => {
+                write!(f, "patch is corrupt: CRC32 should be {expected:08X}, but is {actual:08X}")
+            },
+        }
+    }
+}
+
+///
+/// Apply an IPS, UPS or BPS patch (detected by magic bytes) to a rom file,
+/// returning the patched file. UPS and BPS checksums are verified.
+///
+pub fn apply(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(patch, rom)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(patch, rom)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(patch, rom)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+///
+/// Reads the fields of a patch in order.
+///
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, PatchError> {
+        let value = *self.data.get(self.pos).ok_or(PatchError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.pos.checked_add(len).ok_or(PatchError::UnexpectedEnd)?;
+        let bytes = self.data.get(self.pos..end).ok_or(PatchError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Big endian number of the given size in bytes (IPS).
+    fn read_be(&mut self, len: usize) -> Result<usize, PatchError> {
+        let bytes = self.read_bytes(len)?;
+        Ok(bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    /// Variable length number (UPS and BPS). Each byte holds 7 bits, least
+    /// significant first, with bit 7 set on the last byte.
+    fn read_varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+
+        loop {
+            let byte = self.read_u8()?;
+            let bits = ((byte & 0x7F) as usize).checked_mul(shift).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(bits).ok_or(PatchError::OutOfBounds)?;
+
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+
+            shift = shift.checked_mul(128).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+}
+
+///
+/// IPS: a list of records that each write bytes (or a run of one byte) at a
+/// 24-bit offset, with an optional size to truncate to after the end marker.
+/// See: https://zerosoft.zophar.net/ips.php
+///
+fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut output = rom.to_vec();
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        let offset = reader.read_be(3)?;
+
+        if offset == IPS_EOF {
+            break;
+        }
+
+        let size = reader.read_be(2)?;
+
+        let (size, data) = if size == 0 {
+            // Run length encoded record
+            let run_size = reader.read_be(2)?;
+            let value = reader.read_u8()?;
+            (run_size, vec![value; run_size])
+        } else {
+            (size, reader.read_bytes(size)?.to_vec())
+        };
+
+        if output.len() < offset + size {
+            output.resize(offset + size, 0);
+        }
+
+        output[offset..offset + size].copy_from_slice(&data);
+    }
+
+    // Truncation extension
+    if let Ok(size) = reader.read_be(3) {
+        output.truncate(size);
+    }
+
+    Ok(output)
+}
+
+/// Check the CRC32 trailer of a UPS or BPS patch against the patch itself
+/// and the rom. Returns the expected target CRC32.
+fn check_source_and_patch(patch: &[u8], rom: &[u8]) -> Result<u32, PatchError> {
+    if patch.len() < CHECKSUM_TRAILER_SIZE {
+        return Err(PatchError::UnexpectedEnd);
+    }
+
+    let trailer = &patch[patch.len() - CHECKSUM_TRAILER_SIZE..];
+    let read_crc = |i: usize| u32::from_le_bytes(trailer[i * 4..i * 4 + 4].try_into().unwrap());
+
+    let expected = read_crc(2);
+    let actual = crc32fast::hash(&patch[..patch.len() - 4]);
+
+    if expected != actual {
+        return Err(PatchError::PatchChecksumMismatch { expected, actual });
+    }
+
+    let expected = read_crc(0);
+    let actual = crc32fast::hash(rom);
+
+    if expected != actual {
+        return Err(PatchError::SourceChecksumMismatch { expected, actual });
+    }
+
+    Ok(read_crc(1))
+}
+
+fn check_source_size(expected: usize, rom: &[u8]) -> Result<(), PatchError> {
+    if expected != rom.len() {
+        Err(PatchError::SourceSizeMismatch { expected, actual: rom.len() })
+    } else {
+        Ok(())
+    }
+}
+
+/// The target size is read from the patch, so is checked before anything is
+/// allocated for it.
+fn check_target_size(size: usize) -> Result<(), PatchError> {
+    if size > MAX_ROM_SIZE {
+        Err(PatchError::TargetTooLarge(size))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_target(expected: u32, output: &[u8]) -> Result<(), PatchError> {
+    let actual = crc32fast::hash(output);
+
+    if expected != actual {
+        Err(PatchError::TargetChecksumMismatch { expected, actual })
+    } else {
+        Ok(())
+    }
+}
+
+///
+/// UPS: a list of hunks, each skipping ahead some number of bytes and then
+/// XORing bytes into the rom until a zero byte.
+/// See: https://www.romhacking.net/documents/392/
+///
+fn apply_ups(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_source_and_patch(patch, rom)?;
+
+    let hunks_end = patch.len() - CHECKSUM_TRAILER_SIZE;
+    let mut reader = PatchReader::new(&patch[..hunks_end], UPS_MAGIC.len());
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    check_source_size(source_size, rom)?;
+    check_target_size(target_size)?;
+
+    let mut output = rom.to_vec();
+    output.resize(target_size, 0);
+
+    let mut pos: usize = 0;
+
+    while reader.pos < hunks_end {
+        pos = pos.checked_add(reader.read_varint()?).ok_or(PatchError::OutOfBounds)?;
+
+        loop {
+            let value = reader.read_u8()?;
+
+            // The terminating zero also advances the position
+            if value != 0 {
+                *output.get_mut(pos).ok_or(PatchError::OutOfBounds)? ^= value;
+            }
+
+            pos = pos.checked_add(1).ok_or(PatchError::OutOfBounds)?;
+
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(target_crc, &output)?;
+
+    Ok(output)
+}
+
+///
+/// BPS: a list of actions that build the patched rom by copying from the
+/// rom, the patch, or earlier output.
+/// See: https://www.romhacking.net/documents/746/
+///
+fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = check_source_and_patch(patch, rom)?;
+
+    let actions_end = patch.len() - CHECKSUM_TRAILER_SIZE;
+    let mut reader = PatchReader::new(&patch[..actions_end], BPS_MAGIC.len());
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    check_source_size(source_size, rom)?;
+    check_target_size(target_size)?;
+
+    let metadata_size = reader.read_varint()?;
+    reader.read_bytes(metadata_size)?;
+
+    let mut output = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    while reader.pos < actions_end {
+        let action = reader.read_varint()?;
+        let length = (action >> 2) + 1;
+
+        // Stops actions from growing the output past the target size
+        if length > target_size - output.len() {
+            return Err(PatchError::OutOfBounds);
+        }
+
+        match action & 0x03 {
+            // SourceRead: copy from the same position in the rom
+            0 => {
+                let pos = output.len();
+                let end = pos.checked_add(length).ok_or(PatchError::OutOfBounds)?;
+                let data = rom.get(pos..end).ok_or(PatchError::OutOfBounds)?;
+                output.extend_from_slice(data);
+            },
+            // TargetRead: copy from the patch
+            1 => {
+                output.extend_from_slice(reader.read_bytes(length)?);
+            },
+            // SourceCopy: copy from anywhere in the rom
+            2 => {
+                source_offset = apply_relative_offset(source_offset, reader.read_varint()?)?;
+
+                let end = source_offset.checked_add(length).ok_or(PatchError::OutOfBounds)?;
+                let data = rom.get(source_offset..end).ok_or(PatchError::OutOfBounds)?;
+                output.extend_from_slice(data);
+                source_offset = end;
+            },
+            // TargetCopy: copy from earlier output, one byte at a time since
+            // the ranges may overlap
+            _ => {
+                target_offset = apply_relative_offset(target_offset, reader.read_varint()?)?;
+
+                for _ in 0..length {
+                    let value = *output.get(target_offset).ok_or(PatchError::OutOfBounds)?;
+                    output.push(value);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(PatchError::OutOfBounds);
+    }
+
+    check_target(target_crc, &output)?;
+
+    Ok(output)
+}
+
+/// BPS copy offsets are relative, with the sign in bit 0.
+fn apply_relative_offset(offset: usize, data: usize) -> Result<usize, PatchError> {
+    let delta = data >> 1;
+
+    let offset = if data & 1 != 0 {
+        offset.checked_sub(delta)
+    } else {
+        offset.checked_add(delta)
+    };
+
+    offset.ok_or(PatchError::OutOfBounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                out.push(byte | 0x80);
+                break;
+            }
+
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    /// Append the source, target and patch CRC32 trailer.
+    fn finish_patch(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let patch_crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, 16511, 16512, 1 << 20] {
+            let mut data = Vec::new();
+            write_varint(&mut data, value);
+
+            assert!(PatchReader::new(&data, 0).read_varint().unwrap() == value);
+        }
+    }
+
+    #[test]
+    fn test_varint_overflow() {
+        let data = [0x7F; 16];
+        assert!(PatchReader::new(&data, 0).read_varint() == Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = vec![0u8; 8];
+
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]);
+        // Run of 3 x 0xCC at offset 8, extending the rom
+        patch.extend_from_slice(&[0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+
+        let output = apply(&patch, &rom).unwrap();
+        assert!(output == [0, 0, 0xAA, 0xBB, 0, 0, 0, 0, 0xCC, 0xCC, 0xCC]);
+
+        // Truncation extension
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        let output = apply(&patch, &rom).unwrap();
+        assert!(output == [0, 0, 0xAA, 0xBB]);
+
+        assert!(apply(&patch[..patch.len() - 6], &rom) == Err(PatchError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = b"Hello world!".to_vec();
+        let target = b"Hello WORLD!!".to_vec();
+
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target.len());
+
+        // Skip "Hello ", XOR in "WORLD"
+        write_varint(&mut patch, 6);
+        patch.extend(source[6..11].iter().zip(&target[6..11]).map(|(s, t)| s ^ t));
+        patch.push(0);
+
+        // Skip "!" (the terminator already skipped the space), add "!"
+        write_varint(&mut patch, 0);
+        patch.push(b'!');
+        patch.push(0);
+
+        let patch = finish_patch(patch, &source, &target);
+
+        assert!(apply(&patch, &source).unwrap() == target);
+
+        let mut wrong_source = source.clone();
+        wrong_source[0] = b'J';
+        assert!(matches!(apply(&patch, &wrong_source), Err(PatchError::SourceChecksumMismatch { .. })));
+
+        let mut corrupt_patch = patch.clone();
+        corrupt_patch[8] ^= 0xFF;
+        assert!(matches!(apply(&corrupt_patch, &source), Err(PatchError::PatchChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"abcdefgh".to_vec();
+        let target = b"abcdXYefXYefXY".to_vec();
+
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, target.len());
+        write_varint(&mut patch, 0);
+
+        // SourceRead "abcd"
+        write_varint(&mut patch, (4 - 1) << 2);
+        // TargetRead "XY"
+        write_varint(&mut patch, (2 - 1) << 2 | 1);
+        patch.extend_from_slice(b"XY");
+        // SourceCopy "ef" from offset 4
+        write_varint(&mut patch, (2 - 1) << 2 | 2);
+        write_varint(&mut patch, 4 << 1);
+        // TargetCopy "XYefXY" from offset 4 (overlapping its own output)
+        write_varint(&mut patch, (6 - 1) << 2 | 3);
+        write_varint(&mut patch, 4 << 1);
+
+        let patch = finish_patch(patch, &source, &target);
+
+        assert!(apply(&patch, &source).unwrap() == target);
+
+        assert!(matches!(apply(&patch, b"abcdefg"), Err(PatchError::SourceChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn test_huge_target_size() {
+        let source = b"abcdefgh".to_vec();
+
+        for magic in [UPS_MAGIC, BPS_MAGIC] {
+            let mut patch = magic.to_vec();
+            write_varint(&mut patch, source.len());
+            write_varint(&mut patch, 1 << 40);
+            write_varint(&mut patch, 0);
+
+            let patch = finish_patch(patch, &source, &source);
+
+            assert!(apply(&patch, &source) == Err(PatchError::TargetTooLarge(1 << 40)));
+        }
+    }
+
+    #[test]
+    fn test_huge_offsets() {
+        let source = b"abcdefgh".to_vec();
+
+        // UPS skip that overflows the position
+        let mut patch = b"UPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 0);
+        patch.extend_from_slice(&[0x01, 0]);
+        write_varint(&mut patch, usize::MAX - 1);
+        patch.extend_from_slice(&[0x01, 0]);
+
+        let patch = finish_patch(patch, &source, &source);
+        assert!(apply(&patch, &source) == Err(PatchError::OutOfBounds));
+
+        // BPS SourceCopy from far past the end of the rom
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 0);
+        write_varint(&mut patch, 2);
+        write_varint(&mut patch, usize::MAX & !1);
+
+        let bps_source_copy = finish_patch(patch, &source, &source);
+        assert!(apply(&bps_source_copy, &source) == Err(PatchError::OutOfBounds));
+
+        // BPS TargetCopy longer than the target
+        let mut patch = b"BPS1".to_vec();
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, source.len());
+        write_varint(&mut patch, 0);
+        write_varint(&mut patch, 0);
+        write_varint(&mut patch, (usize::MAX >> 2) << 2 | 3);
+        write_varint(&mut patch, 0);
+
+        let bps_target_copy = finish_patch(patch, &source, &source);
+        assert!(apply(&bps_target_copy, &source) == Err(PatchError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        assert!(apply(b"NOTAPATCH", &[0; 8]) == Err(PatchError::UnknownFormat));
+    }
+}
@@ -2,6 +2,8 @@ use crate::state::NesState;
 use crate::utils::{self, bit_is_set, clear_bit, set_bit, set_bits_from_mask_u16};
 use crate::mem::Memory;
 use crate::mappers::Mapper;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::timing::Timing;
 
 pub mod constants;
 use constants::*;
@@ -285,6 +287,35 @@ enum PpuBgFetchState {
 }
 
 impl PpuBgFetchState {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(match self {
+            PpuBgFetchState::Idle              => 0,
+            PpuBgFetchState::NametableAddr     => 1,
+            PpuBgFetchState::NametableRead     => 2,
+            PpuBgFetchState::AttrtableAddr     => 3,
+            PpuBgFetchState::AttrtableRead     => 4,
+            PpuBgFetchState::BackgroundLSBAddr => 5,
+            PpuBgFetchState::BackgroundLSBRead => 6,
+            PpuBgFetchState::BackgroundMSBAddr => 7,
+            PpuBgFetchState::BackgroundMSBRead => 8,
+        });
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        match reader.read_u8()? {
+            0 => Ok(PpuBgFetchState::Idle),
+            1 => Ok(PpuBgFetchState::NametableAddr),
+            2 => Ok(PpuBgFetchState::NametableRead),
+            3 => Ok(PpuBgFetchState::AttrtableAddr),
+            4 => Ok(PpuBgFetchState::AttrtableRead),
+            5 => Ok(PpuBgFetchState::BackgroundLSBAddr),
+            6 => Ok(PpuBgFetchState::BackgroundLSBRead),
+            7 => Ok(PpuBgFetchState::BackgroundMSBAddr),
+            8 => Ok(PpuBgFetchState::BackgroundMSBRead),
+            _ => Err(SaveStateError::InvalidData("ppu background fetch state")),
+        }
+    }
+
     fn next(&mut self) {
         match *self {
             PpuBgFetchState::Idle              => *self = PpuBgFetchState::Idle,
@@ -372,6 +403,42 @@ struct PpuBgRenderState {
     attribute_lsb_shift_register: ShiftRegister16Bit,
 }
 
+impl PpuBgRenderState {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.fetch_state.save_state(writer);
+        writer.write_u16(self.tile_addr);
+        writer.write_u8(self.tile_value);
+        writer.write_u16(self.attribute_addr);
+        writer.write_u8(self.attribute_data);
+        writer.write_u16(self.bg_lsb_addr);
+        writer.write_u8(self.bg_lsb);
+        writer.write_u16(self.bg_msb_addr);
+        writer.write_u8(self.bg_msb);
+        writer.write_u16(self.pattern_tile_msb_register.contents);
+        writer.write_u16(self.pattern_tile_lsb_register.contents);
+        writer.write_u16(self.attribute_msb_shift_register.contents);
+        writer.write_u16(self.attribute_lsb_shift_register.contents);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.fetch_state = PpuBgFetchState::load_state(reader)?;
+        self.tile_addr = reader.read_u16()?;
+        self.tile_value = reader.read_u8()?;
+        self.attribute_addr = reader.read_u16()?;
+        self.attribute_data = reader.read_u8()?;
+        self.bg_lsb_addr = reader.read_u16()?;
+        self.bg_lsb = reader.read_u8()?;
+        self.bg_msb_addr = reader.read_u16()?;
+        self.bg_msb = reader.read_u8()?;
+        self.pattern_tile_msb_register.contents = reader.read_u16()?;
+        self.pattern_tile_lsb_register.contents = reader.read_u16()?;
+        self.attribute_msb_shift_register.contents = reader.read_u16()?;
+        self.attribute_lsb_shift_register.contents = reader.read_u16()?;
+
+        Ok(())
+    }
+}
+
 struct BackgroundPixel {
     palette_value: u8,
     color_index: u8,
@@ -411,41 +478,113 @@ pub struct Ppu {
     /// Hold various buffers and variables used during sprite evaluation
     /// and rendering.
     sprite_render_state: PpuSpriteEvalState,
+
+    /// Frame layout of the console's region.
+    timing: Timing,
 }
 
 impl Ppu {
-    pub fn new() -> Self {
+    pub fn new(timing: Timing) -> Self {
         Self {
             total_cycle_count: 0,
             reg: PpuRegisters::default(),
             oam: Memory::new(OAM_SIZE),
             ppudata_read_buffer: 0,
             frame: 0,
-            scanline: 261, // Start on prerender scanline
+            scanline: timing.prerender_scanline(), // Start on prerender scanline
             scanline_cycle: 0,
             bg_render_state: PpuBgRenderState::default(),
             sprite_render_state: PpuSpriteEvalState::default(),
+            timing,
         }
     }
 
+    /// Scanline currently being rendered (the prerender line is the last,
+    /// 261 on NTSC and 311 on PAL).
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    ///
+    /// Reset PPU as if NES reset button was pressed. PPUCTRL, PPUMASK, the
+    /// scroll position, the $2005/$2006 write toggle and the read buffer are
+    /// cleared. OAM, palettes and the VRAM address are left alone.
+    /// See: https://www.nesdev.org/wiki/PPU_power_up_state
+    ///
+    pub fn reset(&mut self) {
+        self.reg.ppu_ctrl.update(0);
+        self.reg.ppu_mask.update(0);
+        self.reg.t = 0;
+        self.reg.x = 0;
+        self.reg.w = Toggle::FirstWrite;
+        self.ppudata_read_buffer = 0;
+        self.frame = 0;
+    }
+
+    /// Write registers, OAM and rendering state to a save state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u64(self.total_cycle_count);
+
+        writer.write_u8(self.reg.ppu_ctrl.value);
+        writer.write_u8(self.reg.ppu_mask.value);
+        writer.write_u8(self.reg.ppu_status);
+        writer.write_u8(self.reg.oam_addr);
+        writer.write_u16(self.reg.v);
+        writer.write_u16(self.reg.t);
+        writer.write_u8(self.reg.x);
+        writer.write_bool(matches!(self.reg.w, Toggle::SecondWrite));
+
+        self.oam.save_state(writer);
+        writer.write_u8(self.ppudata_read_buffer);
+        writer.write_u64(self.frame);
+        writer.write_u16(self.scanline);
+        writer.write_u16(self.scanline_cycle);
+
+        self.bg_render_state.save_state(writer);
+        self.sprite_render_state.save_state(writer);
+    }
+
+    /// Restore registers, OAM and rendering state from a save state.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.total_cycle_count = reader.read_u64()?;
+
+        self.reg.ppu_ctrl.update(reader.read_u8()?);
+        self.reg.ppu_mask.update(reader.read_u8()?);
+        self.reg.ppu_status = reader.read_u8()?;
+        self.reg.oam_addr = reader.read_u8()?;
+        self.reg.v = reader.read_u16()?;
+        self.reg.t = reader.read_u16()?;
+        self.reg.x = reader.read_u8()?;
+        self.reg.w = if reader.read_bool()? { Toggle::SecondWrite } else { Toggle::FirstWrite };
+
+        self.oam.load_state(reader)?;
+        self.ppudata_read_buffer = reader.read_u8()?;
+        self.frame = reader.read_u64()?;
+        self.scanline = reader.read_u16()?;
+        self.scanline_cycle = reader.read_u16()?;
+
+        self.bg_render_state.load_state(reader)?;
+        self.sprite_render_state.load_state(reader)
+    }
+
     fn set_next_cycle(&mut self) {
         self.scanline_cycle += 1;
 
-        // 262 scanlines x 341 pixels
+        // 262 (NTSC) or 312 (PAL) scanlines x 341 pixels
 
         // Reset at cycle index 341
         if self.scanline_cycle > 340 {
             self.scanline_cycle = 0;
             self.scanline += 1;
 
-            // Reset at scanline index 262
-            if self.scanline > 261 {
+            // Reset after the prerender scanline
+            if self.scanline > self.timing.prerender_scanline() {
                 self.scanline = 0;
                 self.frame += 1;
 
                 // On odd numbered frames skip the idle tick on scanline 0, cycle 0.
-                // But only when rendering is enabled.
-                if self.frame % 2 == 1 {
+                // But only when rendering is enabled, and only on NTSC.
+                if self.timing.skip_odd_frame_cycle && self.frame % 2 == 1 {
                     if self.rendering_enabled() {
                         self.scanline_cycle = 1;
                     }
@@ -535,39 +674,51 @@ impl Ppu {
                         // We need to load the 8 sprites from secondary oam into our sprite
                         // buffers for the next scanline. We'll do all of the work for each
                         // sprite buffer load in one cycle. This is not cycle by cycle accurate.
-                        if self.scanline_cycle % 8 == 0 {
+                        if self.scanline_cycle.is_multiple_of(8) {
 
                             // Our first load will happen on cycle 264. 264 / 8 = 33. So, we can
                             // divide by 8 and subtract 33 to get the secondary oam index to process.
                             let n = (self.scanline_cycle / 8 - 33) as usize;
 
-                            let y = self.sprite_render_state.secondary_oam[n][0];
-                            let tile = self.sprite_render_state.secondary_oam[n][1];
-                            let attributes = self.sprite_render_state.secondary_oam[n][2];
-                            let x = self.sprite_render_state.secondary_oam[n][3];
-
+                            let entry = self.sprite_render_state.secondary_oam[n];
                             let is_sprite_0 = self.sprite_render_state.maybe_sprite_0_hit && (n == 0);
 
-                            // Copy sprite size
-                            let sprite_size = match self.reg.ppu_ctrl.sprite_size {
-                                SpriteSize::Sprite8x8 => SpriteSize::Sprite8x8,
-                                SpriteSize::Sprite8x16 => SpriteSize::Sprite8x16,
-                            };
-
-                            self.sprite_render_state.sprite_buffers[n].load(
-                                y, tile, attributes, x, is_sprite_0,
-                                self.reg.ppu_ctrl.sprite_pt_addr_8x8,
-                                sprite_size, self.scanline, state);
+                            self.load_sprite_buffer(n, entry, is_sprite_0, state);
                         }
                     },
 
                     _ => ()
                 }
             }
+            // Prerender scanline. Sprite fetches still happen, but from an empty
+            // secondary OAM, so no sprites are drawn on scanline 0.
+            s if s == self.timing.prerender_scanline()
+                && (257..=320).contains(&self.scanline_cycle) && self.scanline_cycle.is_multiple_of(8) => {
+                let n = (self.scanline_cycle / 8 - 33) as usize;
+                self.load_sprite_buffer(n, [0xFF; 4], false, state);
+            }
             _ => ()
         };
     }
 
+    /// Load sprite buffer n for the next scanline from a secondary OAM entry,
+    /// fetching its pattern data. The PPU makes no fetches while rendering is
+    /// disabled, leaving the buffer empty in case rendering is re-enabled
+    /// before the next scanline.
+    fn load_sprite_buffer(&mut self, n: usize, entry: [u8; 4], is_sprite_0: bool, state: &mut NesState) {
+        if !self.rendering_enabled() {
+            self.sprite_render_state.sprite_buffers[n].clear();
+            return;
+        }
+
+        let [y, tile, attributes, x] = entry;
+
+        self.sprite_render_state.sprite_buffers[n].load(
+            y, tile, attributes, x, is_sprite_0,
+            self.reg.ppu_ctrl.sprite_pt_addr_8x8,
+            self.reg.ppu_ctrl.sprite_size, self.scanline, self.total_cycle_count, state);
+    }
+
     pub fn cycle(&mut self, state: &mut NesState) -> PpuCycleResult {
         self.total_cycle_count += 1;
 
@@ -654,8 +805,7 @@ impl Ppu {
 
                 cycle_result
             },
-            240 => PpuCycleResult::PostRenderLine,
-            241 => {
+            s if s == self.timing.vblank_scanline => {
                 if self.scanline_cycle == 1 {
                     self.set_vblank_flag();
                     let do_nmi = self.reg.ppu_ctrl.generate_nmi;
@@ -666,8 +816,7 @@ impl Ppu {
                     PpuCycleResult::VBlankLine { trigger_nmi: false, scanline: self.scanline }
                 }
             }
-            242..=260 => PpuCycleResult::VBlankLine { trigger_nmi: false, scanline: self.scanline },
-            261 => {
+            s if s == self.timing.prerender_scanline() => {
                 match self.scanline_cycle {
                     0 => (),
                     1 => {
@@ -715,7 +864,10 @@ impl Ppu {
 
                 PpuCycleResult::PreRenderLine{ scanline_cycle: self.scanline_cycle }
             },
-            _ => panic!("Invalid scanline: {}", self.scanline)
+            s if s > self.timing.vblank_scanline => PpuCycleResult::VBlankLine { trigger_nmi: false, scanline: self.scanline },
+
+            // Scanline 240, and Dendy's extra lines before vblank
+            _ => PpuCycleResult::PostRenderLine,
         };
 
         self.set_next_cycle();
@@ -760,7 +912,7 @@ impl Ppu {
                               ((self.bg_render_state.tile_value as u16) << 4) | fine_y as u16;
             }
             PpuBgFetchState::BackgroundLSBRead => {
-                self.bg_render_state.bg_lsb = state.ppu_mem_read(self.bg_render_state.bg_lsb_addr);
+                self.bg_render_state.bg_lsb = self.read_pattern(state, self.bg_render_state.bg_lsb_addr);
             }
             PpuBgFetchState::BackgroundMSBAddr => {
                 let fine_y = 0x8 | self.get_fine_y_scroll(); // Or with 0x8 for msb bit plane
@@ -769,11 +921,22 @@ impl Ppu {
                               ((self.bg_render_state.tile_value as u16) << 4) | fine_y as u16;
             }
             PpuBgFetchState::BackgroundMSBRead => {
-                self.bg_render_state.bg_msb = state.ppu_mem_read(self.bg_render_state.bg_msb_addr);
+                self.bg_render_state.bg_msb = self.read_pattern(state, self.bg_render_state.bg_msb_addr);
             }
         }
     }
 
+    /// Read background pattern table data. Only passed on to the mapper as a
+    /// rendering fetch while rendering is enabled, since the PPU makes no
+    /// fetches otherwise.
+    fn read_pattern(&self, state: &mut NesState, addr: u16) -> u8 {
+        if self.rendering_enabled() {
+            state.ppu_mem_fetch(addr, self.total_cycle_count)
+        } else {
+            state.ppu_mem_read(addr)
+        }
+    }
+
     fn shift_bg_shift_registers(&mut self) {
         self.bg_render_state.pattern_tile_lsb_register.shift();
         self.bg_render_state.pattern_tile_msb_register.shift();
@@ -1124,7 +1287,7 @@ impl Ppu {
         }
     }
 
-    pub fn write_2006_ppuaddr(&mut self, value: u8) {
+    pub fn write_2006_ppuaddr(&mut self, value: u8, mapper: &mut Box<dyn Mapper>) {
         match self.reg.w {
 
             Toggle::FirstWrite => {
@@ -1149,6 +1312,9 @@ impl Ppu {
                 self.reg.t = self.reg.t & 0x3FFF; // mirror down beyond 0x3FFF
                 self.reg.v = self.reg.t;
 
+                // The new address is put on the PPU's address bus
+                mapper.ppu_bus_address(self.reg.v, self.total_cycle_count);
+
                 self.reg.w.toggle();
 
                 //println!("t: {:04X}, v: {:04X}", self.reg.t, self.reg.v);
@@ -1167,6 +1333,7 @@ impl Ppu {
     }
 
     pub fn write_2007_ppudata(&mut self, value: u8, mapper: &mut Box<dyn Mapper>) {
+        mapper.ppu_bus_address(self.reg.v, self.total_cycle_count);
         mapper.ppu_write(self.reg.v, value);
         self.reg.v += self.reg.ppu_ctrl.vram_increment;
         //println!("t: {:04X}, v: {:04X}", self.reg.t, self.reg.v);
@@ -1174,6 +1341,8 @@ impl Ppu {
 
     //pub fn read_2007_ppudata(&mut self, state: &mut NesState) -> u8 {
     pub fn read_2007_ppudata(&mut self, mapper: &mut Box<dyn Mapper>) -> u8 {
+        mapper.ppu_bus_address(self.reg.v, self.total_cycle_count);
+
         if self.reg.v > 0x3EFF {
             let value = mapper.ppu_read(self.reg.v);
             self.reg.v += self.reg.ppu_ctrl.vram_increment;
@@ -1,3 +1,4 @@
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
 /// Size of Object Attribute Memory.
 pub const OAM_SIZE: usize = 256;
@@ -26,3 +27,24 @@ pub enum Mirroring {
     OneScreen0,
     OneScreen1,
 }
+
+impl Mirroring {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(match self {
+            Mirroring::Vertical => 0,
+            Mirroring::Horizontal => 1,
+            Mirroring::OneScreen0 => 2,
+            Mirroring::OneScreen1 => 3,
+        });
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> Result<Self, SaveStateError> {
+        match reader.read_u8()? {
+            0 => Ok(Mirroring::Vertical),
+            1 => Ok(Mirroring::Horizontal),
+            2 => Ok(Mirroring::OneScreen0),
+            3 => Ok(Mirroring::OneScreen1),
+            _ => Err(SaveStateError::InvalidData("mirroring")),
+        }
+    }
+}
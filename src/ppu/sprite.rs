@@ -1,7 +1,8 @@
 use crate::state::NesState;
 use crate::utils;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[derive(Default)]
 pub enum SpriteSize {
     #[default] Sprite8x8,
@@ -63,6 +64,12 @@ pub struct SpriteBuffer {
 }
 
 impl SpriteBuffer {
+    /// Empty the buffer so nothing is drawn or hit tested from it.
+    pub(super) fn clear(&mut self) {
+        self.is_renderable = false;
+        self.is_sprite_0 = false;
+    }
+
     /// Load data from OAM into the sprite buffer.
     pub(super) fn load(&mut self, 
                        y: u8,
@@ -73,6 +80,7 @@ impl SpriteBuffer {
                        pattern_table_addr_8x8: u16,
                        sprite_size: SpriteSize,
                        scanline: u16,
+                       ppu_cycle: u64,
                        state: &mut NesState)
     {
         self.x_position = x;
@@ -92,10 +100,27 @@ impl SpriteBuffer {
 
         self.is_renderable = y < 240;
 
-        // Don't fetch if not on a visible scanline. This avoids overflow issues when calculating
-        // the tile address when doing scanline - y
+        // The PPU still fetches tile $FF for empty slots, which mappers that
+        // watch pattern table fetches (e.g. MMC3) rely on. Only calculate the
+        // real address on a visible scanline. This avoids overflow issues when
+        // calculating the tile address when doing scanline - y
+        let tile_addr = if self.is_renderable {
+            self.get_tile_addr(sprite_size, scanline, y, pattern_table_addr_8x8, tile)
+        } else {
+            match sprite_size {
+                SpriteSize::Sprite8x8 => pattern_table_addr_8x8 | 0x0FF0,
+                SpriteSize::Sprite8x16 => 0x1FE0,
+            }
+        };
+
+        let pattern_tile_lsb = state.ppu_mem_fetch(tile_addr, ppu_cycle);
+
+        // Or tile_addr with 0x8 to set bit 3 for msb bitplane
+        let pattern_tile_msb = state.ppu_mem_fetch(tile_addr | 0x8, ppu_cycle);
+
         if self.is_renderable {
-            self.set_pattern_lsb_msb(sprite_size, scanline, y, pattern_table_addr_8x8, tile, state);
+            self.pattern_tile_lsb = pattern_tile_lsb;
+            self.pattern_tile_msb = pattern_tile_msb;
         }
     }
 
@@ -113,13 +138,13 @@ impl SpriteBuffer {
         msb << 1 | lsb
     }
 
-    fn set_pattern_lsb_msb(&mut self,
-                           sprite_size: SpriteSize,
-                           scanline: u16,
-                           y: u8,
-                           pattern_table_addr_8x8: u16,
-                           tile: u8,
-                           state: &mut NesState)
+    /// Address of the lsb bit plane of the sprite's row on the scanline.
+    fn get_tile_addr(&self,
+                     sprite_size: SpriteSize,
+                     scanline: u16,
+                     y: u8,
+                     pattern_table_addr_8x8: u16,
+                     tile: u8) -> u16
     {
         match sprite_size {
             SpriteSize::Sprite8x8 => {
@@ -129,13 +154,7 @@ impl SpriteBuffer {
                     scanline - (y as u16)
                 };
     
-                let tile_addr = pattern_table_addr_8x8 | ((tile as u16) << 4) | intra_tile_y;
-
-                self.pattern_tile_lsb = state.ppu_mem_read(tile_addr);
-    
-                // Or tile_addr with 0x8 to set bit 3 for msb bitplane
-                self.pattern_tile_msb = state.ppu_mem_read(tile_addr | 0x8);
-    
+                pattern_table_addr_8x8 | ((tile as u16) << 4) | intra_tile_y
             },
             SpriteSize::Sprite8x16 => {
                 let mut intra_tile_y = if self.flip_vertically {
@@ -162,14 +181,9 @@ impl SpriteBuffer {
 
                 // tile_index * 16 selects first or second set of 16 bytes for first or second
                 // 8x8 tile, then add in intra_tile_y.
-                let tile_addr = pattern_table_base + tile_offset + (tile_index * 16) + intra_tile_y;
-
-                self.pattern_tile_lsb = state.ppu_mem_read(tile_addr);
-    
-                // Or tile_addr with 0x8 to set bit 3 for msb bitplane
-                self.pattern_tile_msb = state.ppu_mem_read(tile_addr | 0x8);
+                pattern_table_base + tile_offset + (tile_index * 16) + intra_tile_y
             }
-        };
+        }
     }
 
     pub fn x_position(&self) -> u8 {
@@ -194,6 +208,38 @@ impl SpriteBuffer {
     pub fn is_renderable(&self) -> bool {
         self.is_renderable
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.is_renderable);
+        writer.write_u8(self.x_position);
+        writer.write_u8(self.y_position);
+        writer.write_u8(self.palette);
+        writer.write_bool(matches!(self.priority, SpriteBgPriority::BehindBackground));
+        writer.write_bool(self.is_sprite_0);
+        writer.write_bool(self.flip_horizontally);
+        writer.write_bool(self.flip_vertically);
+        writer.write_u8(self.pattern_tile_lsb);
+        writer.write_u8(self.pattern_tile_msb);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.is_renderable = reader.read_bool()?;
+        self.x_position = reader.read_u8()?;
+        self.y_position = reader.read_u8()?;
+        self.palette = reader.read_u8()?;
+        self.priority = if reader.read_bool()? {
+            SpriteBgPriority::BehindBackground
+        } else {
+            SpriteBgPriority::InFrontOfBackground
+        };
+        self.is_sprite_0 = reader.read_bool()?;
+        self.flip_horizontally = reader.read_bool()?;
+        self.flip_vertically = reader.read_bool()?;
+        self.pattern_tile_lsb = reader.read_u8()?;
+        self.pattern_tile_msb = reader.read_u8()?;
+
+        Ok(())
+    }
 }
 
 
@@ -249,6 +295,45 @@ impl Default for PpuSpriteEvalState {
 
 impl PpuSpriteEvalState {
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.oam_addr);
+
+        for entry in self.secondary_oam.iter() {
+            writer.write_bytes(entry);
+        }
+
+        writer.write_u8(self.secondary_oam_index as u8);
+        writer.write_bool(self.maybe_sprite_0_hit);
+        writer.write_bool(self.sprite_overflow);
+
+        for sprite_buffer in self.sprite_buffers.iter() {
+            sprite_buffer.save_state(writer);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.oam_addr = reader.read_u16()?;
+
+        for entry in self.secondary_oam.iter_mut() {
+            reader.read_bytes_into(entry)?;
+        }
+
+        self.secondary_oam_index = reader.read_u8()? as usize;
+
+        if self.secondary_oam_index > self.secondary_oam.len() {
+            return Err(SaveStateError::InvalidData("secondary oam index"));
+        }
+
+        self.maybe_sprite_0_hit = reader.read_bool()?;
+        self.sprite_overflow = reader.read_bool()?;
+
+        for sprite_buffer in self.sprite_buffers.iter_mut() {
+            sprite_buffer.load_state(reader)?;
+        }
+
+        Ok(())
+    }
+
     pub fn reset(&mut self) {
         //self.num_sprites_on_scanline = 0;
         self.oam_addr = 0;
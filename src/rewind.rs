@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+
+///
+/// Ring buffer of save states used to play the game backwards.
+///
+/// The newest state is kept in full. Each older state is stored as the
+/// difference (XOR) against the next newer state, run length encoded. Since
+/// most of the machine's memory doesn't change between captures, the deltas
+/// are mostly zeros and compress to a small fraction of a full state. When
+/// the buffer exceeds its size limit the oldest states are discarded.
+///
+pub struct RewindBuffer {
+    /// Most recently captured state.
+    newest: Option<Vec<u8>>,
+
+    /// Encoded deltas, oldest first. Applying the last delta to the newest
+    /// state yields the state captured before it.
+    deltas: VecDeque<Vec<u8>>,
+
+    /// Total size of all encoded deltas.
+    deltas_size: usize,
+
+    /// Maximum number of bytes used by the newest state plus all deltas.
+    max_size: usize,
+
+    /// Capture a state every this many frames.
+    capture_interval: u32,
+
+    /// Frames since the last capture.
+    frames_since_capture: u32,
+}
+
+impl RewindBuffer {
+    pub fn new(max_size: usize, capture_interval: u32) -> Self {
+        Self {
+            newest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            max_size,
+            capture_interval: capture_interval.max(1),
+            frames_since_capture: 0,
+        }
+    }
+
+    /// Called once per frame. Returns true if a state should be captured
+    /// and pushed this frame.
+    pub fn frame_completed(&mut self) -> bool {
+        self.frames_since_capture += 1;
+
+        if self.frames_since_capture >= self.capture_interval {
+            self.frames_since_capture = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add a newly captured state.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            if previous.len() == state.len() {
+                let delta = encode_delta(&state, &previous);
+                self.deltas_size += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // Can't diff states of different sizes, so start over
+                self.clear_deltas();
+            }
+        }
+
+        self.newest = Some(state);
+
+        // Keep within size limit by discarding the oldest states
+        while self.size() > self.max_size {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    ///
+    /// Remove and return the most recently captured state. The oldest state
+    /// is never removed, so repeated calls eventually keep returning it
+    /// rather than running out.
+    ///
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.deltas_size -= delta.len();
+                self.newest = Some(apply_delta(&newest, &delta));
+            },
+            None => self.newest = Some(newest.clone()),
+        }
+
+        self.frames_since_capture = 0;
+
+        Some(newest)
+    }
+
+    /// Number of states held.
+    #[allow(dead_code)]
+    pub fn num_states(&self) -> usize {
+        match self.newest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    /// Bytes used by held states.
+    pub fn size(&self) -> usize {
+        self.newest.as_ref().map_or(0, |newest| newest.len()) + self.deltas_size
+    }
+
+    fn clear_deltas(&mut self) {
+        self.deltas.clear();
+        self.deltas_size = 0;
+    }
+}
+
+///
+/// XOR two equal length buffers and run length encode the result as a
+/// sequence of (zero run length, literal length, literal bytes) records.
+/// Lengths are LEB128 encoded.
+///
+fn encode_delta(current: &[u8], previous: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+
+    while i < current.len() {
+        let zeros_start = i;
+
+        while i < current.len() && current[i] == previous[i] {
+            i += 1;
+        }
+
+        let literal_start = i;
+
+        // End the literal at the first run of zeros long enough to be worth
+        // a new record.
+        while i < current.len() {
+            if current[i..].iter().zip(&previous[i..]).take(4).all(|(c, p)| c == p) {
+                break;
+            }
+
+            i += 1;
+        }
+
+        write_length(&mut encoded, literal_start - zeros_start);
+        write_length(&mut encoded, i - literal_start);
+
+        let literal = current[literal_start..i].iter().zip(&previous[literal_start..i]);
+        encoded.extend(literal.map(|(c, p)| c ^ p));
+    }
+
+    encoded
+}
+
+/// Reverse encode_delta, recovering the previous buffer from the current one.
+fn apply_delta(current: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut previous = current.to_vec();
+    let mut position = 0;
+    let mut offset = 0;
+
+    while offset < delta.len() {
+        position += read_length(delta, &mut offset);
+        let literal_len = read_length(delta, &mut offset);
+
+        for value in &delta[offset..offset + literal_len] {
+            previous[position] ^= value;
+            position += 1;
+        }
+
+        offset += literal_len;
+    }
+
+    previous
+}
+
+fn write_length(buffer: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let byte = (length & 0x7F) as u8;
+        length >>= 7;
+
+        if length == 0 {
+            buffer.push(byte);
+            return;
+        }
+
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn read_length(buffer: &[u8], offset: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = buffer[*offset];
+        *offset += 1;
+
+        length |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake state with a few bytes changed per frame.
+    fn state_for_frame(frame: u8) -> Vec<u8> {
+        let mut state = vec![0xAA; 65536];
+
+        state[0] = frame;
+        state[1000] = frame.wrapping_mul(3);
+        state[65535] = !frame;
+
+        for byte in state[2000..2300].iter_mut() {
+            *byte = frame;
+        }
+
+        state
+    }
+
+    #[test]
+    fn test_pop_returns_states_newest_first() {
+        let mut rewind = RewindBuffer::new(1024 * 1024, 1);
+
+        for frame in 0..10 {
+            rewind.push(state_for_frame(frame));
+        }
+
+        // Deltas should be much smaller than full states
+        assert!(rewind.size() < 65536 + 10 * 1024);
+
+        for frame in (0..10).rev() {
+            assert!(rewind.pop().unwrap() == state_for_frame(frame));
+        }
+
+        // Oldest state is kept
+        assert!(rewind.pop().unwrap() == state_for_frame(0));
+        assert!(rewind.num_states() == 1);
+    }
+
+    #[test]
+    fn test_size_limit_discards_oldest() {
+        let mut rewind = RewindBuffer::new(65536 + 2000, 1);
+
+        for frame in 0..100 {
+            rewind.push(state_for_frame(frame));
+            assert!(rewind.size() <= 65536 + 2000);
+        }
+
+        let num_states = rewind.num_states();
+        assert!(num_states > 1 && num_states < 100);
+
+        while rewind.num_states() > 1 {
+            rewind.pop();
+        }
+
+        assert!(rewind.pop().unwrap() == state_for_frame((100 - num_states) as u8));
+    }
+}
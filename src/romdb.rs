@@ -0,0 +1,197 @@
+use std::sync::OnceLock;
+
+use crate::ines::{InesHeader, MirroringType};
+
+/// Database source, compiled into the binary.
+const ROM_DATABASE: &str = include_str!("romdb.txt");
+
+///
+/// Correct header values for a known dump, keyed by hashes of its PRG ROM
+/// followed by its CHR ROM.
+///
+#[derive(Debug)]
+pub struct RomDbEntry {
+    pub name: String,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+    pub mapper_number: u16,
+    pub submapper_number: u8,
+    pub mirroring: MirroringType,
+    pub four_screen_vram: bool,
+    pub has_battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+}
+
+impl RomDbEntry {
+    /// Parse a database line. Returns None if the line is malformed.
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+
+        let crc32 = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let sha1 = parse_sha1(fields.next()?)?;
+        let mapper_number = fields.next()?.parse().ok()?;
+        let submapper_number = fields.next()?.parse().ok()?;
+
+        let (mirroring, four_screen_vram) = match fields.next()? {
+            "H" => (MirroringType::Horizontal, false),
+            "V" => (MirroringType::Vertical, false),
+            "4" => (MirroringType::Horizontal, true),
+            _ => return None,
+        };
+
+        let has_battery = match fields.next()? {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+
+        Some(Self {
+            crc32,
+            sha1,
+            mapper_number,
+            submapper_number,
+            mirroring,
+            four_screen_vram,
+            has_battery,
+            prg_ram_size: fields.next()?.parse().ok()?,
+            prg_nvram_size: fields.next()?.parse().ok()?,
+            chr_ram_size: fields.next()?.parse().ok()?,
+            name: fields.collect::<Vec<_>>().join(" "),
+        })
+    }
+
+    /// Overwrite the header fields this entry knows the correct values for.
+    /// The mapper nybbles in the flags are kept in step with the mapper
+    /// number.
+    pub fn apply(&self, header: &mut InesHeader) {
+        header.mapper_number = self.mapper_number;
+        header.flags6.mapper_lower_nybble = (self.mapper_number & 0x0F) as u8;
+        header.flags7.mapper_upper_nybble = (self.mapper_number & 0xF0) as u8;
+        header.submapper_number = self.submapper_number;
+        header.flags6.mirroring = self.mirroring.clone();
+        header.flags6._four_screen_vram = self.four_screen_vram;
+        header.flags6._has_battery_backed_prg_ram = self.has_battery;
+        header.prg_ram_size = self.prg_ram_size;
+        header.prg_nvram_size = self.prg_nvram_size;
+        header.chr_ram_size = self.chr_ram_size;
+    }
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+
+    let mut sha1 = [0; 20];
+
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(sha1)
+}
+
+/// Database entries, parsed on first use.
+fn entries() -> &'static [RomDbEntry] {
+    static ENTRIES: OnceLock<Vec<RomDbEntry>> = OnceLock::new();
+
+    ENTRIES.get_or_init(|| {
+        ROM_DATABASE.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let entry = RomDbEntry::parse(line);
+
+                if entry.is_none() {
+                    warn!("romdb: ignoring malformed entry: {line}");
+                }
+
+                entry
+            })
+            .collect()
+    })
+}
+
+///
+/// Find the database entry for a rom with the given PRG and CHR ROM. The
+/// CRC32 is used to find candidates, which must also match on SHA-1.
+///
+pub fn lookup(prg_rom: &[u8], chr_rom: &[u8]) -> Option<&'static RomDbEntry> {
+    let mut crc_hasher = crc32fast::Hasher::new();
+    crc_hasher.update(prg_rom);
+    crc_hasher.update(chr_rom);
+    let crc32 = crc_hasher.finalize();
+
+    // Only compute the SHA-1 if there are candidates
+    if !entries().iter().any(|entry| entry.crc32 == crc32) {
+        return None;
+    }
+
+    let mut sha1_hasher = sha1_smol::Sha1::new();
+    sha1_hasher.update(prg_rom);
+    sha1_hasher.update(chr_rom);
+    let sha1 = sha1_hasher.digest().bytes();
+
+    entries().iter().find(|entry| entry.crc32 == crc32 && entry.sha1 == sha1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ines::InesRom;
+
+    #[test]
+    fn test_database_parses() {
+        let num_lines = ROM_DATABASE.lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .count();
+
+        assert!(entries().len() == num_lines);
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let entry = RomDbEntry::parse(
+            "0123abcd 00112233445566778899aabbccddeeff00112233 4 1 4 1 0 8192 8192 Some Game (USA)").unwrap();
+
+        assert!(entry.crc32 == 0x0123ABCD);
+        assert!(entry.sha1[1] == 0x11 && entry.sha1[19] == 0x33);
+        assert!(entry.mapper_number == 4);
+        assert!(entry.submapper_number == 1);
+        assert!(entry.four_screen_vram);
+        assert!(entry.has_battery);
+        assert!(entry.prg_nvram_size == 8192);
+        assert!(entry.name == "Some Game (USA)");
+
+        assert!(RomDbEntry::parse("0123abcd 0011 4 1 H 1 0 8192 8192 Short SHA-1").is_none());
+        assert!(RomDbEntry::parse("0123abcd 00112233445566778899aabbccddeeff00112233 4 1 X 1 0 0 0 Bad").is_none());
+    }
+
+    #[test]
+    fn test_apply_updates_mapper_nybbles() {
+        let data = std::fs::read("./nestest/nestest.nes").unwrap();
+        let mut header = InesRom::from_buffer("nestest.nes".to_string(), &data).unwrap().header;
+
+        let entry = RomDbEntry::parse(
+            "0123abcd 00112233445566778899aabbccddeeff00112233 71 0 V 0 0 0 0 Some Game (USA)").unwrap();
+        entry.apply(&mut header);
+
+        assert!(header.mapper_number == 71);
+        assert!(header.flags6.mapper_lower_nybble == 7);
+        assert!(header.flags7.mapper_upper_nybble == 0x40);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let data = std::fs::read("./nestest/nestest.nes").unwrap();
+        let prg_rom = &data[16..16 + 16384];
+        let chr_rom = &data[16 + 16384..];
+
+        assert!(lookup(prg_rom, chr_rom).unwrap().name == "nestest");
+
+        // Same size but different contents
+        assert!(lookup(chr_rom, prg_rom).is_none());
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::cpu::Cpu;
+use crate::state::NesState;
+use crate::utils;
+
+/// Identifies a retrobrite save state file.
+const MAGIC: [u8; 4] = *b"RBSS";
+
+/// Save state format version. Must be incremented whenever the layout of
+/// any component's state changes.
+const VERSION: u16 = 2;
+
+/// Number of save state slots available from the UI.
+pub const NUM_SLOTS: u8 = 10;
+
+///
+/// Errors that can occur while restoring a save state.
+///
+#[derive(Debug)]
+pub enum SaveStateError {
+    /// Data does not start with the save state magic number.
+    InvalidHeader,
+
+    /// Save state was written by an incompatible version of the format.
+    UnsupportedVersion(u16),
+
+    /// Save state was created with a different mapper than the one running.
+    MapperMismatch { expected: u16, found: u16 },
+
+    /// Data ended before all state was read.
+    UnexpectedEnd,
+
+    /// A field held a value that is not valid for its type.
+    InvalidData(&'static str),
+
+    /// Save state file could not be read or written.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::InvalidHeader => write!(f, "not a save state file"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {version} (expected {VERSION})")
+            },
+            SaveStateError::MapperMismatch { expected, found } => {
+                write!(f, "save state is for mapper {found}, but mapper {expected} is running")
+            },
+            SaveStateError::UnexpectedEnd => write!(f, "save state data is truncated"),
+            SaveStateError::InvalidData(field) => write!(f, "invalid value for {field}"),
+            SaveStateError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for SaveStateError {
+    fn from(e: std::io::Error) -> Self {
+        SaveStateError::Io(e)
+    }
+}
+
+///
+/// Serializes component state into a little endian binary buffer.
+/// Components write their fields in a fixed order that their load_state
+/// function must read back in the same order.
+///
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write a length prefixed byte slice.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+///
+/// Reads state written by StateWriter.
+///
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self.position + count;
+
+        if end > self.data.len() {
+            return Err(SaveStateError::UnexpectedEnd);
+        }
+
+        let slice = &self.data[self.position..end];
+        self.position = end;
+
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidData("bool")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read a length prefixed byte slice.
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let mut len = [0; 4];
+        len.copy_from_slice(self.take(4)?);
+
+        self.take(u32::from_le_bytes(len) as usize)
+    }
+
+    /// Read a length prefixed byte slice into dest. The stored length must
+    /// match the length of dest.
+    pub fn read_bytes_into(&mut self, dest: &mut [u8]) -> Result<(), SaveStateError> {
+        let bytes = self.read_bytes()?;
+
+        if bytes.len() != dest.len() {
+            return Err(SaveStateError::InvalidData("memory size"));
+        }
+
+        dest.copy_from_slice(bytes);
+
+        Ok(())
+    }
+}
+
+/// Create a save state of the whole machine.
+pub fn save(cpu: &Cpu, state: &NesState) -> Vec<u8> {
+    let mut writer = StateWriter::new();
+
+    for byte in MAGIC {
+        writer.write_u8(byte);
+    }
+
+    writer.write_u16(VERSION);
+    writer.write_u16(state.mapper_number());
+
+    cpu.save_state(&mut writer);
+    state.save_state(&mut writer);
+
+    writer.into_vec()
+}
+
+fn load_machine(reader: &mut StateReader, cpu: &mut Cpu, state: &mut NesState) -> Result<(), SaveStateError> {
+    cpu.load_state(reader)?;
+    state.load_state(reader)
+}
+
+///
+/// Restore the whole machine from a save state. If the state cannot be
+/// restored the machine is left as it was before the call.
+///
+pub fn load(data: &[u8], cpu: &mut Cpu, state: &mut NesState) -> Result<(), SaveStateError> {
+    let mut reader = StateReader::new(data);
+
+    for byte in MAGIC {
+        if reader.read_u8().map_err(|_| SaveStateError::InvalidHeader)? != byte {
+            return Err(SaveStateError::InvalidHeader);
+        }
+    }
+
+    let version = reader.read_u16()?;
+
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    let mapper_number = reader.read_u16()?;
+
+    if mapper_number != state.mapper_number() {
+        return Err(SaveStateError::MapperMismatch {
+            expected: state.mapper_number(),
+            found: mapper_number,
+        });
+    }
+
+    // Keep the current state around in case the save state turns out to be
+    // truncated or corrupt partway through.
+    let mut backup = StateWriter::new();
+    cpu.save_state(&mut backup);
+    state.save_state(&mut backup);
+
+    let result = load_machine(&mut reader, cpu, state);
+
+    if result.is_err() {
+        let backup = backup.into_vec();
+
+        load_machine(&mut StateReader::new(&backup), cpu, state)
+            .expect("failed to restore state after loading save state");
+    }
+
+    result
+}
+
+/// Path of the save state file for the given rom and slot.
+pub fn slot_path(rom_name: &str, slot: u8) -> PathBuf {
+    let mut path = utils::get_data_dir_path();
+    path.push(format!("{rom_name}.ss{slot}"));
+
+    path
+}
+
+/// Save the machine state to the given slot's file.
+pub fn save_to_slot(rom_name: &str, slot: u8, cpu: &Cpu, state: &NesState) -> Result<PathBuf, SaveStateError> {
+    let path = slot_path(rom_name, slot);
+    fs::write(&path, save(cpu, state))?;
+
+    Ok(path)
+}
+
+/// Restore the machine state from the given slot's file.
+pub fn load_from_slot(rom_name: &str, slot: u8, cpu: &mut Cpu, state: &mut NesState) -> Result<PathBuf, SaveStateError> {
+    let path = slot_path(rom_name, slot);
+    let data = fs::read(&path)?;
+
+    load(&data, cpu, state)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::apu::Apu;
+    use crate::mappers::get_mapper;
+    use crate::mem::{Memory, PpuMemory};
+    use crate::ppu::Ppu;
+    use crate::timing::Timing;
+
+    fn get_machine() -> (Cpu, NesState) {
+        let mut state = NesState::new(get_mapper(0, 0, Memory::new_cpu(), PpuMemory::new()).unwrap(),
+                                      Rc::new(RefCell::new(Ppu::new(Timing::default()))),
+                                      Rc::new(RefCell::new(Apu::new(Timing::default()))));
+        let cpu = Cpu::new(&mut state, false);
+
+        (cpu, state)
+    }
+
+    #[test]
+    fn test_reader_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0xAB);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(&[1, 2, 3]);
+
+        let data = writer.into_vec();
+        let mut reader = StateReader::new(&data);
+
+        assert!(reader.read_u8().unwrap() == 0xAB);
+        assert!(reader.read_bool().unwrap());
+        assert!(reader.read_u16().unwrap() == 0x1234);
+        assert!(reader.read_u64().unwrap() == 0x0123_4567_89AB_CDEF);
+
+        let mut bytes = [0; 3];
+        reader.read_bytes_into(&mut bytes).unwrap();
+        assert!(bytes == [1, 2, 3]);
+
+        assert!(matches!(reader.read_u8(), Err(SaveStateError::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn test_machine_round_trip() {
+        let (mut cpu, mut state) = get_machine();
+        cpu.cycle_to(&mut state, 1000);
+
+        let saved = save(&cpu, &state);
+
+        cpu.cycle_to(&mut state, 2000);
+        state.cpu_mem_write(0x0010, 0x55, 2000);
+        assert!(save(&cpu, &state) != saved);
+
+        load(&saved, &mut cpu, &mut state).unwrap();
+        assert!(save(&cpu, &state) == saved);
+    }
+
+    #[test]
+    fn test_truncated_state_leaves_machine_unchanged() {
+        let (mut cpu, mut state) = get_machine();
+        let saved = save(&cpu, &state);
+
+        cpu.cycle_to(&mut state, 1000);
+        let current = save(&cpu, &state);
+
+        let result = load(&saved[..saved.len() - 1], &mut cpu, &mut state);
+        assert!(matches!(result, Err(SaveStateError::UnexpectedEnd)));
+        assert!(save(&cpu, &state) == current);
+
+        let result = load(b"not a save state", &mut cpu, &mut state);
+        assert!(matches!(result, Err(SaveStateError::InvalidHeader)));
+    }
+}
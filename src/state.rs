@@ -1,8 +1,14 @@
 use std::{cell::RefCell, rc::Rc};
 use crate::ppu::Ppu;
+use crate::apu::Apu;
 use crate::mappers::Mapper;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use crate::utils;
 
+/// Number of CPU cycles after power on or reset during which the PPU ignores
+/// writes to PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR.
+const PPU_WARMUP_CYCLES: u64 = 29658;
+
 ///
 /// Manages reads/writes to cpu and ppu memory, properly delegating calls to
 /// ppu/apu functions when memory mapped registers are used.
@@ -10,6 +16,7 @@ use crate::utils;
 pub struct NesState {
     mapper: Box<dyn Mapper>,
     ppu_ref: Rc<RefCell<Ppu>>,
+    apu_ref: Rc<RefCell<Apu>>,
 
     /// Controller shift register strobe state. If true we will accept controller
     /// state updates from the UI. If false the program is in the process of reading
@@ -37,13 +44,21 @@ pub struct NesState {
     /// any shift register reads after the first eight until the strobe is
     /// reset.
     controller2_read_count: u16,
+
+    /// CPU cycles stolen by OAM and DMC DMA that the CPU has not yet
+    /// accounted for.
+    dma_stall_cycles: u64,
+
+    /// CPU cycle after which the PPU accepts writes to its registers.
+    ppu_ready_cycle: u64,
 }
 
 impl NesState {
-    pub fn new(mapper: Box<dyn Mapper>, ppu_ref: Rc<RefCell<Ppu>>) -> Self {
+    pub fn new(mapper: Box<dyn Mapper>, ppu_ref: Rc<RefCell<Ppu>>, apu_ref: Rc<RefCell<Apu>>) -> Self {
         Self {
             mapper,
             ppu_ref,
+            apu_ref,
             reload_controller_state: true,
             controller1_state: 0,
             controller1_register: 0,
@@ -51,13 +66,79 @@ impl NesState {
             controller2_state: 0,
             controller2_register: 0,
             controller2_read_count: 0,
+            dma_stall_cycles: 0,
+            ppu_ready_cycle: PPU_WARMUP_CYCLES,
         }
     }
 
+    ///
+    /// Reset the mapper, PPU and APU as if the NES reset button was pressed
+    /// on the given CPU cycle. The PPU ignores register writes again for a
+    /// while after.
+    ///
+    pub fn reset(&mut self, cycle_count: u64) {
+        self.mapper.reset();
+        self.ppu_ref.borrow_mut().reset();
+        self.apu_ref.borrow_mut().reset(cycle_count);
+
+        self.dma_stall_cycles = 0;
+        self.ppu_ready_cycle = cycle_count + PPU_WARMUP_CYCLES;
+    }
+
     pub fn shutdown(&mut self) {
         self.mapper.shutdown();
     }
 
+    pub fn mapper_number(&self) -> u16 {
+        self.mapper.number()
+    }
+
+    /// Clock the mapper for one CPU cycle.
+    pub fn mapper_cpu_cycle(&mut self) {
+        self.mapper.cpu_cycle();
+    }
+
+    /// Mapper expansion audio output for the current CPU cycle.
+    pub fn mapper_audio_sample(&self) -> f32 {
+        self.mapper.audio_sample()
+    }
+
+    /// Largest expansion audio sample the mapper can output.
+    pub fn mapper_audio_peak(&self) -> f32 {
+        self.mapper.audio_peak()
+    }
+
+    /// Write controller, DMA, mapper, PPU and APU state to a save state.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.reload_controller_state);
+        writer.write_u8(self.controller1_register);
+        writer.write_u16(self.controller1_read_count);
+        writer.write_u8(self.controller2_register);
+        writer.write_u16(self.controller2_read_count);
+        writer.write_u64(self.dma_stall_cycles);
+        writer.write_u64(self.ppu_ready_cycle);
+
+        self.mapper.save_state(writer);
+        self.ppu_ref.borrow().save_state(writer);
+        self.apu_ref.borrow().save_state(writer);
+    }
+
+    /// Restore controller, DMA, mapper, PPU and APU state from a save state.
+    /// Controller button state is left alone since it comes from the UI.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.reload_controller_state = reader.read_bool()?;
+        self.controller1_register = reader.read_u8()?;
+        self.controller1_read_count = reader.read_u16()?;
+        self.controller2_register = reader.read_u8()?;
+        self.controller2_read_count = reader.read_u16()?;
+        self.dma_stall_cycles = reader.read_u64()?;
+        self.ppu_ready_cycle = reader.read_u64()?;
+
+        self.mapper.load_state(reader)?;
+        self.ppu_ref.borrow_mut().load_state(reader)?;
+        self.apu_ref.borrow_mut().load_state(reader)
+    }
+
     fn get_cpu_effective_address(&self, addr: u16) -> u16 {
         match addr {
             // 0000-07FF is RAM with 0800-1FFF mirroring it
@@ -83,6 +164,7 @@ impl NesState {
             0x2007 => {
                 self.ppu_ref.borrow_mut().read_2007_ppudata(&mut self.mapper)
             },
+            0x4015 => self.apu_ref.borrow_mut().read_4015_status(),
             0x4016 => self.read_controller1(),
             0x4017 => self.read_controller2(),
             _ => self.mapper.cpu_read(addr)
@@ -106,8 +188,9 @@ impl NesState {
     pub fn cpu_mem_write(&mut self, addr: u16, value: u8, cycle_count: u64) {
         let addr = self.get_cpu_effective_address(addr);
 
-        // PPU ignores certain writes until approx 29658 cpu cycles
-        let ppu_ready = cycle_count > 29658;
+        // PPU ignores certain writes for approx 29658 cpu cycles after
+        // power on or reset
+        let ppu_ready = cycle_count > self.ppu_ready_cycle;
 
         // Handle PPU register address writes if necessary.
         match addr {
@@ -130,7 +213,7 @@ impl NesState {
             }
             0x2006 => {
                 if ppu_ready {
-                    self.ppu_ref.borrow_mut().write_2006_ppuaddr(value);
+                    self.ppu_ref.borrow_mut().write_2006_ppuaddr(value, &mut self.mapper);
                 }
             }
             0x2007 => self.ppu_ref.borrow_mut().write_2007_ppudata(value, &mut self.mapper),
@@ -140,12 +223,46 @@ impl NesState {
 
                 let mut ppu = self.ppu_ref.borrow_mut();
                 ppu.oam_dma(dma_slice);
+
+                // Takes 513 cycles, plus one more to align if written on an odd cycle
+                self.dma_stall_cycles += 513 + (cycle_count & 1);
             },
+            0x4000..=0x4013 => self.apu_ref.borrow_mut().write_register(addr, value),
+            0x4015 => self.apu_ref.borrow_mut().write_4015_status(value),
             0x4016 => self.handle_controller_strobe(value),
+            0x4017 => self.apu_ref.borrow_mut().write_4017_frame_counter(value, cycle_count),
             _ => self.mapper.cpu_write(addr, value),
         }
     }
 
+    /// State of the CPU's (level triggered, active low) IRQ line. Asserted if
+    /// any IRQ source is asserting it.
+    pub fn irq_line(&self) -> bool {
+        self.apu_ref.borrow().irq_pending() || self.mapper.irq_pending()
+    }
+
+    /// Read a DMC sample byte. The CPU is stalled while the DMC fetches.
+    /// This always stalls for 4 cycles, which is an approximation: the
+    /// hardware stall is 3 cycles if the CPU was writing, and 1 or 2 if the
+    /// fetch lands during OAM DMA.
+    /// See: https://www.nesdev.org/wiki/APU_DMC#Memory_reader
+    pub fn dmc_mem_read(&mut self, addr: u16) -> u8 {
+        self.dma_stall_cycles += 4;
+        self.mapper.cpu_read(addr)
+    }
+
+    /// Copy of the 2KB of internal CPU RAM.
+    pub fn cpu_ram(&self) -> Vec<u8> {
+        (0..8u16)
+            .flat_map(|page| self.mapper.get_cpu_dma_slice(page << 8).iter().copied())
+            .collect()
+    }
+
+    /// Return and reset the number of cycles stolen from the CPU by DMA.
+    pub fn take_dma_stall_cycles(&mut self) -> u64 {
+        std::mem::take(&mut self.dma_stall_cycles)
+    }
+
     /// Load a sequence of bytes into cpu memory, starting at addr.
     //pub fn cpu_mem_load(&mut self, addr: u16, data: &[u8]) {
     //    let addr = self.get_cpu_effective_address(addr);
@@ -163,6 +280,12 @@ impl NesState {
         self.mapper.ppu_read(addr)
     }
 
+    /// Read PPU memory for a pattern table fetch made while rendering on the
+    /// given PPU cycle.
+    pub fn ppu_mem_fetch(&mut self, addr: u16, ppu_cycle: u64) -> u8 {
+        self.mapper.ppu_fetch(addr, ppu_cycle)
+    }
+
     //pub fn ppu_mem_write(&mut self, addr: u16, value: u8) {
     //    //let addr = self.get_ppu_effective_address(addr);
     //    self.mapper.ppu_write(addr, value)
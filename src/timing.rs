@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use crate::ines::Region;
+use crate::nes::CPU_FREQ;
+
+/// PPU cycles per scanline, for all regions.
+pub const PPU_CYCLES_PER_SCANLINE: u64 = 341;
+
+///
+/// Console clock rates and frame layout. NTSC consoles and PAL consoles
+/// (and the Dendy famiclone) differ in their master clock, CPU/PPU clock
+/// ratio, number of scanlines and APU tables.
+/// See: https://www.nesdev.org/wiki/Cycle_reference_chart
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub region: Region,
+
+    /// CPU clock rate in Hz.
+    pub cpu_freq: u64,
+
+    /// PPU cycles run per CPU cycle, as (numerator, denominator).
+    pub ppu_cycles_per_cpu_cycle: (u64, u64),
+
+    /// Scanlines per frame, counting post-render, vblank and prerender.
+    pub scanlines_per_frame: u16,
+
+    /// Scanline the vblank flag is set (and NMI triggered) on.
+    pub vblank_scanline: u16,
+
+    /// The PPU skips the first idle cycle of odd frames while rendering.
+    pub skip_odd_frame_cycle: bool,
+
+    /// The APU uses PAL noise and DMC periods and frame counter timing.
+    pub pal_apu: bool,
+}
+
+impl Timing {
+    /// Timing of the console the region's roms are made for. Multi-region
+    /// roms run with NTSC timing.
+    pub fn for_region(region: Region) -> Self {
+        match region {
+            Region::Ntsc | Region::Multi => Self {
+                region,
+                cpu_freq: CPU_FREQ,
+                ppu_cycles_per_cpu_cycle: (3, 1),
+                scanlines_per_frame: 262,
+                vblank_scanline: 241,
+                skip_odd_frame_cycle: true,
+                pal_apu: false,
+            },
+            Region::Pal => Self {
+                region,
+                cpu_freq: 26_601_712 / 16,
+                ppu_cycles_per_cpu_cycle: (16, 5),
+                scanlines_per_frame: 312,
+                vblank_scanline: 241,
+                skip_odd_frame_cycle: false,
+                pal_apu: true,
+            },
+
+            // Dendy runs PAL's frame layout with NTSC's clock ratio, and
+            // delays vblank so NTSC games get their usual amount of time
+            // after it.
+            Region::Dendy => Self {
+                region,
+                cpu_freq: 26_601_712 / 15,
+                ppu_cycles_per_cpu_cycle: (3, 1),
+                scanlines_per_frame: 312,
+                vblank_scanline: 291,
+                skip_odd_frame_cycle: false,
+                pal_apu: false,
+            },
+        }
+    }
+
+    /// Last scanline of the frame.
+    pub fn prerender_scanline(&self) -> u16 {
+        self.scanlines_per_frame - 1
+    }
+
+    /// Real time taken by one frame.
+    pub fn frame_duration(&self) -> Duration {
+        let (ppu_cycles, cpu_cycles) = self.ppu_cycles_per_cpu_cycle;
+        let ppu_cycles_per_frame = PPU_CYCLES_PER_SCANLINE * self.scanlines_per_frame as u64;
+
+        let nanos = ppu_cycles_per_frame as u128 * cpu_cycles as u128 * 1_000_000_000
+            / (ppu_cycles as u128 * self.cpu_freq as u128);
+
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self::for_region(Region::Ntsc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_rates() {
+        let fps = |region| 1.0 / Timing::for_region(region).frame_duration().as_secs_f64();
+
+        assert!((fps(Region::Ntsc) - 60.0).abs() < 0.2);
+        assert!((fps(Region::Pal) - 50.0).abs() < 0.1);
+        assert!((fps(Region::Dendy) - 50.0).abs() < 0.1);
+    }
+}
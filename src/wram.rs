@@ -1,25 +1,34 @@
 use std::path::PathBuf;
 use std::fs;
 
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
 use crate::utils;
 
 const DEFAULT_WRAM_SIZE: usize = 0x2000;
 
 /// WRAM usually mapped to CPU memory at 0x6000-0x7FFF. This
 /// is battery backed RAM for cartridges that feature persistent
-/// save files.
+/// save files, or plain work RAM for cartridges without a battery.
 pub struct WRam {
     mem: Vec<u8>,
     base_addr: u16,
     rom_name: String,
+
+    /// Contents are kept in a wram file between runs.
+    battery: bool,
 }
 
 impl WRam {
-    pub fn new(rom_name: &str) -> Self {
+    /// Create WRAM of the given size in bytes (0 for the default 8KB),
+    /// loading its contents from the rom's wram file if there is one.
+    pub fn new(rom_name: &str, size: usize) -> Self {
+        let size = if size == 0 { DEFAULT_WRAM_SIZE } else { size };
+
         let mut wram = Self { 
-            mem: vec![0; DEFAULT_WRAM_SIZE],
+            mem: vec![0; size],
             base_addr: 0x6000,
             rom_name: rom_name.to_string(),
+            battery: true,
          };
 
          wram.load_from_file();
@@ -27,12 +36,24 @@ impl WRam {
          wram
     }
 
+    /// Create volatile work RAM of the given size in bytes, which is never
+    /// written to a file.
+    pub fn new_volatile(size: usize) -> Self {
+        Self {
+            mem: vec![0; size],
+            base_addr: 0x6000,
+            rom_name: String::new(),
+            battery: false,
+        }
+    }
+
     fn load_from_file(&mut self) {
         let filepath = self.get_wram_filepath();
 
         if filepath.exists() {
             match fs::read(&filepath) {
-                Ok(data) => {
+                Ok(mut data) => {
+                    data.resize(self.mem.len(), 0);
                     self.mem = data;
                 },
                 Err(e) => {
@@ -43,6 +64,10 @@ impl WRam {
     }
 
     pub fn write_to_file(&self) {
+        if !self.battery {
+            return;
+        }
+
         let filepath = self.get_wram_filepath();
         match fs::write(&filepath, &self.mem) {
             Ok(_) => println!("wrote {}", filepath.to_string_lossy()),
@@ -53,7 +78,8 @@ impl WRam {
     fn get_index(&self, addr: u16) -> usize {
         let index = addr - self.base_addr;
 
-        index as usize
+        // WRAM smaller than its address space is mirrored
+        index as usize % self.mem.len()
     }
 
     /// Read an 8-bit value from memory.
@@ -67,19 +93,20 @@ impl WRam {
         self.mem[index] = value;
     }
 
-    /*
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.mem);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes_into(&mut self.mem)
+    }
+
     /// Load a sequence of bytes into memory, starting at addr.
     pub fn load(&mut self, addr: u16, data: &[u8]) {
-        if addr as usize + data.len() > self.mem.len() {
-            panic!("load data would exceed memory capacity");
-        }
-
         for (offset, value) in data.iter().enumerate() {
-            let location = addr as usize + offset;
-            self.mem[location] = *value;
+            self.write(addr + offset as u16, *value);
         }
     }
-    */
 
     /*
     fn open(&mut self, rom_name: String) {
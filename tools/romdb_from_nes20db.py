@@ -0,0 +1,118 @@
+#!/usr/bin/env python3
+"""
+Regenerate src/romdb.txt from the NES 2.0 XML database (nes20db.xml).
+
+nes20db.xml is maintained by NewRisingSun and published on the nesdev
+forums. It has header values for most known good NES dumps, keyed by hashes
+of the rom data without the iNES header.
+
+Usage: romdb_from_nes20db.py nes20db.xml src/romdb.txt
+
+Lines of the existing database up to the generated marker are kept, so hand
+maintained entries survive regenerating. Everything after the marker is
+replaced.
+"""
+
+import os
+import sys
+import xml.etree.ElementTree as ET
+
+GENERATED_MARKER = "# --- Generated from nes20db.xml, do not edit below this line ---"
+
+MIRRORING = {"H": "H", "V": "V", "4": "4"}
+
+
+def size(game, tag):
+    element = game.find(tag)
+    return int(element.get("size")) if element is not None else 0
+
+
+def game_entry(game, name):
+    """Database line for a game, or None if it can't be described."""
+    rom = game.find("rom")
+    pcb = game.find("pcb")
+    console = game.find("console")
+
+    if rom is None or pcb is None:
+        return None
+
+    # Lookups hash only PRG and CHR ROM
+    if game.find("trainer") is not None or game.find("miscrom") is not None:
+        return None
+
+    # Vs. System and PlayChoice-10 roms aren't emulated
+    if console is not None and console.get("type", "0") != "0":
+        return None
+
+    mirroring = MIRRORING.get(pcb.get("mirroring"))
+
+    if mirroring is None:
+        return None
+
+    return "{} {} {} {} {} {} {} {} {} {}".format(
+        rom.get("crc32").lower(),
+        rom.get("sha1").lower(),
+        int(pcb.get("mapper")),
+        int(pcb.get("submapper", "0")),
+        mirroring,
+        int(pcb.get("battery", "0")),
+        size(game, "prgram"),
+        size(game, "prgnvram"),
+        size(game, "chrram"),
+        name,
+    )
+
+
+def parse_games(xml_path):
+    """(crc32 sha1 key, line) for each game. Each game is preceded by a
+    comment holding the path of its rom file, which names the entry."""
+    parser = ET.XMLParser(target=ET.TreeBuilder(insert_comments=True))
+    root = ET.parse(xml_path, parser).getroot()
+
+    name = None
+    skipped = 0
+
+    for element in root:
+        if element.tag is ET.Comment:
+            path = element.text.strip().replace("\\", "/")
+            name = os.path.splitext(os.path.basename(path))[0]
+        elif element.tag == "game":
+            line = game_entry(element, name or "unknown")
+
+            if line is None:
+                skipped += 1
+            else:
+                yield " ".join(line.split()[:2]), line
+
+            name = None
+
+    print(f"skipped {skipped} games", file=sys.stderr)
+
+
+def main():
+    if len(sys.argv) != 3:
+        sys.exit(f"usage: {sys.argv[0]} nes20db.xml romdb.txt")
+
+    xml_path, db_path = sys.argv[1:]
+
+    with open(db_path) as f:
+        kept = f.read().split(GENERATED_MARKER)[0].rstrip("\n").splitlines()
+
+    kept_keys = {
+        " ".join(line.split()[:2])
+        for line in kept
+        if line.strip() and not line.startswith("#")
+    }
+
+    generated = sorted({
+        line for key, line in parse_games(xml_path) if key not in kept_keys
+    }, key=lambda line: line.split(None, 9)[9].lower())
+
+    with open(db_path, "w") as f:
+        f.write("\n".join(kept + ["", GENERATED_MARKER] + generated) + "\n")
+
+    print(f"wrote {len(generated)} generated entries", file=sys.stderr)
+
+
+if __name__ == "__main__":
+    main()